@@ -0,0 +1,68 @@
+//! Connects to a robot's telemetry stream and prints each message on one line.
+//!
+//! ```sh
+//! rsbot replay trace.jsonl maps/example.json --telemetry 127.0.0.1:7878 &
+//! cargo run --example telemetry_client -- 127.0.0.1:7878
+//! ```
+
+extern crate serde_json;
+
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
+use std::process;
+
+use serde_json::Value;
+
+fn main() {
+    let address = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:7878".to_string());
+    let stream = match TcpStream::connect(&address[..]) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("telemetry_client: couldn't connect to {}: {}", address, err);
+            process::exit(1);
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                eprintln!("telemetry_client: {}", err);
+                process::exit(1);
+            }
+        };
+        match serde_json::from_str::<Value>(&line) {
+            Ok(message) => println!("{}", summary(&message)),
+            Err(err) => eprintln!("telemetry_client: bad message: {}", err),
+        }
+    }
+    println!("Disconnected");
+}
+
+fn summary(message: &Value) -> String {
+    let command = match message.get("command") {
+        Some(&Value::Object(ref command)) => {
+            format!("#{} {} node {}",
+                    command.get("index").map_or("-".to_string(), Value::to_string),
+                    command.get("command").and_then(Value::as_str).unwrap_or(""),
+                    command.get("node").map_or("-".to_string(), Value::to_string))
+        }
+        _ => "idle".to_string(),
+    };
+    let direction = message.get("direction")
+        .and_then(Value::as_str)
+        .unwrap_or("stopped");
+    let distances = message.get("distances")
+        .and_then(Value::as_array)
+        .map_or(vec![], |distances| {
+            distances.iter()
+                .map(|d| {
+                    format!("{}: {:6.1}",
+                            d.get("direction").and_then(Value::as_str).unwrap_or("?"),
+                            d.get("distance").and_then(Value::as_f64).unwrap_or(0.0))
+                })
+                .collect()
+        });
+    let motors = message.get("motors").map_or("-".to_string(), Value::to_string);
+    format!("{} | {} | {} | {}", command, direction, distances.join("  "), motors)
+}
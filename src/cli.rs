@@ -0,0 +1,287 @@
+use clap::{App, AppSettings, Arg, SubCommand};
+
+use logger;
+
+pub fn app() -> App<'static, 'static> {
+    App::new("rsbot")
+        .version(crate_version!())
+        .about("A robotic control interface for the Raspberry Pi")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("robot")
+            .long("robot")
+            .value_name("FILE")
+            .default_value("robot.toml")
+            .global(true)
+            .help("Robot profile with the pin wiring and tuning, in TOML or JSON"))
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .value_name("LEVEL")
+            .default_value("info")
+            .global(true)
+            .validator(|s| s.parse::<logger::Filter>().map(|_| ()))
+            .help("Logs at this level and above, e.g. `debug`, or per module like \
+                   `info,distance=trace`"))
+        .arg(Arg::with_name("log-format")
+            .long("log-format")
+            .value_name("FORMAT")
+            .default_value("text")
+            .possible_values(&["text", "json"])
+            .global(true)
+            .help("Writes logs to stderr as text, or as one JSON object per line"))
+        .subcommand(SubCommand::with_name("run")
+            .about("Runs the robot along the path through a map")
+            .arg(map_arg())
+            .arg(Arg::with_name("state")
+                .long("state")
+                .value_name("FILE")
+                .default_value("rsbot-state.json")
+                .help("Saves the node, heading and command the run is at to FILE after each \
+                       command"))
+            .arg(Arg::with_name("resume")
+                .long("resume")
+                .help("Carries on from the node saved in the state file, instead of the \
+                       start"))
+            .arg(trace_arg())
+            .arg(telemetry_arg())
+            .arg(grid_arg())
+            .arg(step_arg())
+            .arg(control_arg())
+            .arg(keep_open_arg()))
+        .subcommand(SubCommand::with_name("plan")
+            .about("Prints the path and commands for a map without touching any pins")
+            .arg(map_arg()))
+        .subcommand(SubCommand::with_name("validate")
+            .about("Checks that a map can be loaded and pathed")
+            .arg(map_arg()))
+        .subcommand(SubCommand::with_name("script")
+            .about("Runs the commands in a script file")
+            .arg(Arg::with_name("SCRIPT")
+                .help("Path to the script file")
+                .required(true))
+            .arg(trace_arg())
+            .arg(telemetry_arg())
+            .arg(grid_arg())
+            .arg(step_arg())
+            .arg(control_arg())
+            .arg(keep_open_arg()))
+        .subcommand(SubCommand::with_name("replay")
+            .about("Plays a recorded run's sensor readings back through a map or script, \
+                    without touching any pins")
+            .after_help("Readings are played back at the pace they were recorded, so the \
+                         replay takes as long as the run did. Give the same map or script \
+                         the run used, and the robot profile's tuning, to reproduce its \
+                         decisions.")
+            .arg(Arg::with_name("TRACE")
+                .help("Trace recorded with `--trace`")
+                .required(true))
+            .arg(Arg::with_name("MAP")
+                .help("Path to the map file")
+                .required_unless("script"))
+            .arg(Arg::with_name("script")
+                .long("script")
+                .value_name("FILE")
+                .conflicts_with("MAP")
+                .help("Runs a script file instead of a map"))
+            .arg(trace_arg())
+            .arg(telemetry_arg())
+            .arg(grid_arg())
+            .arg(step_arg())
+            .arg(control_arg())
+            .arg(keep_open_arg()))
+        .subcommand(SubCommand::with_name("remote")
+            .about("Lets an operator drive the robot over TCP")
+            .after_help("Requests and replies are one JSON object per line, such as \
+                         {\"Move\":{\"direction\":\"Forward\",\"ms\":null}}, \
+                         {\"Move\":{\"direction\":\"Left\",\"ms\":300}}, \
+                         {\"Travel\":{\"direction\":\"Forward\"}}, \"Stop\", \
+                         {\"Speed\":{\"travel\":0.6,\"turn\":null}}, \
+                         {\"Distance\":{\"direction\":\"Left\"}}, \"Ping\" and \"Quit\". \
+                         Moves without a time keep going until the next request, so an \
+                         operator holding a key down should send one every so often. \
+                         Travel and timed moves are answered when they finish, and \"Stop\" \
+                         interrupts them. \"Quit\" stops the robot and ends the session, \
+                         saving anything `--trace` or `--grid` is recording.")
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDRESS")
+                .default_value("0.0.0.0:7879")
+                .help("Address to accept an operator on"))
+            .arg(Arg::with_name("heartbeat")
+                .long("heartbeat")
+                .value_name("MS")
+                .default_value("500")
+                .help("Stops the motors if the operator sends nothing for this long"))
+            .arg(trace_arg())
+            .arg(telemetry_arg())
+            .arg(grid_arg()))
+        .subcommand(SubCommand::with_name("teleop")
+            .about("Drives the robot with the arrow keys, showing live sensor readings")
+            .after_help("Each arrow key press moves in that direction for --step, and \
+                         space stops. Quit with q or Esc.")
+            .arg(Arg::with_name("step")
+                .long("step")
+                .value_name("MS")
+                .default_value("250")
+                .help("Milliseconds to move for on each key press"))
+            .arg(trace_arg())
+            .arg(telemetry_arg())
+            .arg(grid_arg()))
+        .subcommand(SubCommand::with_name("sensors")
+            .about("Prints live readings from all four distance sensors")
+            .arg(Arg::with_name("interval")
+                .long("interval")
+                .value_name("MS")
+                .default_value("200")
+                .help("Milliseconds between readings")))
+        .subcommand(SubCommand::with_name("motors")
+            .about("Motor utilities")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("test")
+                .about("Drives each wheel forward on its own, then drives in each direction, \
+                        to check the motor wiring")
+                .arg(Arg::with_name("duration")
+                    .long("duration")
+                    .value_name("MS")
+                    .default_value("500")
+                    .help("Milliseconds to drive each wheel and direction for"))))
+        .subcommand(SubCommand::with_name("calibrate")
+            .about("Measures the sensors in a corridor and saves thresholds to the robot profile")
+            .after_help("Place the robot in the middle of a straight corridor of the width used \
+                         in the maze before running this. The robot rotates in place while \
+                         measuring, so the turn time should cover at least a half turn. Saving \
+                         rewrites the robot profile, which drops any comments in it.")
+            .arg(Arg::with_name("samples")
+                .long("samples")
+                .value_name("N")
+                .default_value("20")
+                .help("Readings taken from each sensor standing still to measure noise"))
+            .arg(Arg::with_name("turn")
+                .long("turn")
+                .value_name("MS")
+                .default_value("4000")
+                .help("Milliseconds to rotate in place for"))
+            .arg(Arg::with_name("steps")
+                .long("steps")
+                .value_name("N")
+                .default_value("40")
+                .help("Number of pauses to take readings at while rotating"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Prints the thresholds without saving them")))
+        .subcommand(SubCommand::with_name("scan")
+            .about("Sweeps the scanning sensor and prints a reading at each angle")
+            .after_help("Angles are in degrees, with 0 straight ahead and positive angles to \
+                         the left. Without a scanning sensor, the fixed sensors facing between \
+                         the two angles are read instead.")
+            .arg(Arg::with_name("from")
+                .long("from")
+                .value_name("DEGREES")
+                .default_value("-90")
+                .allow_hyphen_values(true)
+                .help("Angle to start at"))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .value_name("DEGREES")
+                .default_value("90")
+                .allow_hyphen_values(true)
+                .help("Angle to finish at"))
+            .arg(Arg::with_name("step")
+                .long("step")
+                .value_name("DEGREES")
+                .default_value("15")
+                .validator(positive_degrees)
+                .help("Degrees between readings")))
+        .subcommand(SubCommand::with_name("localize")
+            .about("Works out which node of a map the robot was put down at")
+            .after_help("The robot should be put down at a junction, facing any way. It \
+                         compares the openings around it with the map's nodes, and moves \
+                         to another junction to narrow them down if it has to. Candidates \
+                         are printed for where it ends up, with headings relative to the \
+                         way it would face at the start node.")
+            .arg(map_arg())
+            .arg(Arg::with_name("confidence")
+                .long("confidence")
+                .value_name("P")
+                .default_value("0.9")
+                .help("Stops moving once a candidate is at least this likely"))
+            .arg(Arg::with_name("max-moves")
+                .long("max-moves")
+                .value_name("N")
+                .default_value("4")
+                .help("Gives up narrowing down after N moves"))
+            .arg(Arg::with_name("state")
+                .long("state")
+                .value_name("FILE")
+                .help("Saves the most likely node and heading to FILE for `run --resume`")))
+        .subcommand(SubCommand::with_name("explore")
+            .about("Explores an unknown maze and prints the map it finds")
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("Writes the map to FILE instead of stdout"))
+            .arg(Arg::with_name("max-nodes")
+                .long("max-nodes")
+                .value_name("N")
+                .default_value("64")
+                .help("Stops exploring after N nodes")))
+}
+
+fn trace_arg() -> Arg<'static, 'static> {
+    Arg::with_name("trace")
+        .long("trace")
+        .value_name("FILE")
+        .help("Records the sensor readings, motor moves and commands to a trace file")
+}
+
+fn telemetry_arg() -> Arg<'static, 'static> {
+    Arg::with_name("telemetry")
+        .long("telemetry")
+        .value_name("ADDRESS")
+        .help("Streams telemetry as JSON lines to TCP clients, e.g. `0.0.0.0:7878`")
+}
+
+fn grid_arg() -> Arg<'static, 'static> {
+    Arg::with_name("grid")
+        .long("grid")
+        .value_name("FILE")
+        .help("Maps the course as an occupancy grid, saved as a PGM image, or as a PNG \
+               image or JSON if FILE ends in `.png` or `.json`")
+}
+
+fn step_arg() -> Arg<'static, 'static> {
+    Arg::with_name("step")
+        .long("step")
+        .help("Asks before running each command, with the motors stopped in between")
+}
+
+fn control_arg() -> Arg<'static, 'static> {
+    Arg::with_name("control")
+        .long("control")
+        .value_name("ADDRESS")
+        .help("Accepts Pause, Resume, Step, Clear, Position, Submit, Status and Close \
+               requests as JSON lines on a TCP address, like `rsbot remote`")
+}
+
+fn keep_open_arg() -> Arg<'static, 'static> {
+    Arg::with_name("keep-open")
+        .long("keep-open")
+        .requires("control")
+        .help("Waits for commands submitted over `--control` once the others have run, \
+               until a Close request")
+}
+
+fn map_arg() -> Arg<'static, 'static> {
+    Arg::with_name("MAP")
+        .help("Path to the map file")
+        .required(true)
+}
+
+fn positive_degrees(value: String) -> Result<(), String> {
+    match value.parse::<f32>() {
+        Ok(degrees) if degrees > 0.0 && degrees.is_finite() => Ok(()),
+        Ok(_) => Err(String::from("must be more than 0 degrees")),
+        Err(err) => Err(err.to_string()),
+    }
+}
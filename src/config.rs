@@ -0,0 +1,251 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use serde_json;
+use toml;
+
+use sysfs_gpio::Pin;
+
+use controller::WallFollow;
+use distance;
+use drivetrain;
+use grid;
+use motor;
+use servo;
+use stepper;
+use threshold::Thresholds;
+use super::Result;
+
+/// A robot's hardware profile: which BCM pins everything is wired to, and how it
+/// should be tuned.
+///
+/// Profiles are read from TOML, or from JSON when the file name ends in `.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RobotConfig {
+    #[serde(default)]
+    pub drivetrain: drivetrain::Kind,
+    /// The microstep select pins shared by the stepper drivers, if they aren't jumpered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub microstep_pins: Option<[u64; 3]>,
+    /// A pair of dual H-bridge controllers for a four wheeled robot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motors: Option<Motors>,
+    #[serde(default)]
+    pub speeds: motor::Speeds,
+    #[serde(default)]
+    pub stop: motor::Stop,
+    #[serde(default)]
+    pub wall_follow: WallFollow,
+    #[serde(default)]
+    pub stepper: stepper::Settings,
+    /// How `--grid` maps the course
+    #[serde(default)]
+    pub grid: grid::Settings,
+    pub sensors: Sensors,
+    /// A distance sensor on a servo, to scan the angles the fixed sensors can't see
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scanner: Option<ScannerPins>,
+    #[serde(default)]
+    pub thresholds: Thresholds,
+    /// Individual motor channels, for any other layout
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<ChannelPins>,
+    /// Stepper motors, for the stepper drivetrain
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steppers: Vec<StepperPins>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Motors {
+    pub front: MotorPins,
+    pub rear: MotorPins,
+}
+
+/// Pins for one dual H-bridge motor controller, and which wheels they drive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MotorPins {
+    pub enable_a: u64,
+    pub in_a1: u64,
+    pub in_a2: u64,
+    pub enable_b: u64,
+    pub in_b1: u64,
+    pub in_b2: u64,
+    #[serde(default)]
+    pub wiring: motor::Wiring,
+}
+
+/// Pins for one H-bridge channel, and the wheel it drives.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChannelPins {
+    pub role: motor::Role,
+    pub enable: u64,
+    pub in1: u64,
+    pub in2: u64,
+    #[serde(default)]
+    pub invert: bool,
+}
+
+/// Pins for one STEP/DIR stepper driver, and the wheel it drives.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StepperPins {
+    pub role: motor::Role,
+    pub step: u64,
+    pub dir: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable: Option<u64>,
+    #[serde(default)]
+    pub invert: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sensors {
+    /// Milliseconds between one ultrasonic sensor's ping and the next one's, so they
+    /// don't pick up each other's echoes
+    #[serde(default = "Sensors::default_ping_gap_ms")]
+    pub ping_gap_ms: u64,
+    /// Milliseconds between the start of one round of readings and the next, or 0 to
+    /// read the sensors as fast as they'll go
+    #[serde(default)]
+    pub sample_ms: u64,
+    pub front: SensorConfig,
+    pub rear: SensorConfig,
+    pub left: SensorConfig,
+    pub right: SensorConfig,
+}
+
+impl Sensors {
+    fn default_ping_gap_ms() -> u64 {
+        distance::DEFAULT_PING_GAP_MS
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScannerPins {
+    pub servo: u64,
+    pub trigger: u64,
+    pub echo: u64,
+    #[serde(default)]
+    pub calibration: servo::Settings,
+}
+
+/// One range sensor, and where it's connected.
+///
+/// HC-SR04s need `trigger` and `echo` pins. Time-of-flight sensors use an I2C `bus`
+/// and `address`, and Sharp IR sensors an MCP3008 `channel` on an `spi` device, all
+/// of which have defaults.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SensorConfig {
+    #[serde(default)]
+    pub kind: distance::Kind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trigger: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub echo: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bus: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spi: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<distance::SharpModel>,
+    /// The ADC's reference voltage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vref: Option<f32>,
+}
+
+impl SensorConfig {
+    /// How to set up the sensor, or `None` if an HC-SR04 is missing a pin.
+    pub fn spec(&self) -> Option<distance::Spec> {
+        let bus = || self.bus.clone().unwrap_or_else(|| "/dev/i2c-1".to_string());
+        let address = self.address.unwrap_or(distance::DEFAULT_ADDRESS);
+        match self.kind {
+            distance::Kind::HcSr04 => {
+                Some(distance::Spec::HcSr04 {
+                    trigger: Pin::new(self.trigger?),
+                    echo: Pin::new(self.echo?),
+                })
+            }
+            distance::Kind::Vl53l0x => {
+                Some(distance::Spec::Vl53l0x {
+                    bus: bus(),
+                    address: address,
+                })
+            }
+            distance::Kind::Vl53l1x => {
+                Some(distance::Spec::Vl53l1x {
+                    bus: bus(),
+                    address: address,
+                })
+            }
+            distance::Kind::Sharp => {
+                Some(distance::Spec::Sharp {
+                    spi: self.spi.clone().unwrap_or_else(|| "/dev/spidev0.0".to_string()),
+                    channel: self.channel.unwrap_or(0),
+                    model: self.model.unwrap_or_default(),
+                    vref: self.vref.unwrap_or(3.3),
+                })
+            }
+        }
+    }
+}
+
+impl RobotConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RobotConfig> {
+        let path = path.as_ref();
+        RobotConfig::read(path).map_err(|err| err.in_file(path))
+    }
+
+    fn read(path: &Path) -> Result<RobotConfig> {
+        let mut source = String::new();
+        fs::File::open(path)?.read_to_string(&mut source)?;
+        if is_json(path) {
+            Ok(serde_json::from_str(&source)?)
+        } else {
+            Ok(toml::from_str(&source)?)
+        }
+    }
+
+    /// Writes the profile back out, in the same format `load` would read it as.
+    ///
+    /// Any comments in an existing file are not preserved.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let contents = if is_json(path) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml::to_string(self)?
+        };
+        fs::File::create(path)?.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "json")
+}
+
+#[cfg(test)]
+mod tests {
+    use toml;
+
+    use super::RobotConfig;
+
+    const EXAMPLE: &str = include_str!("../robots/example.toml");
+
+    #[test]
+    fn example_profile_loads() {
+        let config: RobotConfig = toml::from_str(EXAMPLE).unwrap();
+        assert!(config.microstep_pins.is_none());
+    }
+
+    #[test]
+    fn example_microstep_pins_are_top_level() {
+        let source = EXAMPLE.replace("# microstep_pins =", "microstep_pins =");
+        let config: RobotConfig = toml::from_str(&source).unwrap();
+        assert_eq!(config.microstep_pins, Some([12, 16, 20]));
+    }
+}
@@ -1,53 +1,164 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::time;
+
 use futures_cpupool;
 use retry;
 use sysfs_gpio;
 
+use {Command, Direction};
+use config;
 use distance;
+use drivetrain::{self, Drivetrain};
 use error::{BuilderError, Error};
 use map;
-use motor;
-use super::Controller;
+use motor::{self, Role};
+use servo;
+use state::RunState;
+use stepper;
+use threshold::Thresholds;
+use trace;
+use super::{Controller, Queue, Scanner, WallFollow};
 use super::super::Result;
 
+// Every move blocks a pool thread on each sensor it watches, on top of the move itself
+// and the wall follower, so there need to be more threads than a Pi has cores.
+const POOL_THREADS: usize = 8;
+
 macro_rules! build {
-    ($self_: ident, $obj: ty, $err: path, {$($opt_gpio: ident),+}) => ({
-        $(
-            let $opt_gpio = $self_.$opt_gpio.ok_or(Error::Build($err))?;
-        )+
-        <$obj>::new($($opt_gpio),+)?
-    });
+    ($self_: ident, $opt_spec: ident, $err: path) => (
+        $self_.$opt_spec.as_ref().ok_or(Error::Build($err))?.build()?
+    );
+}
+
+/// Pins for one dual H-bridge controller driving the wheels at one end of the robot.
+#[derive(Debug)]
+struct DualPins {
+    enable_a: sysfs_gpio::Pin,
+    in_a1: sysfs_gpio::Pin,
+    in_a2: sysfs_gpio::Pin,
+    enable_b: sysfs_gpio::Pin,
+    in_b1: sysfs_gpio::Pin,
+    in_b2: sysfs_gpio::Pin,
+    wiring: motor::Wiring,
+}
+
+impl DualPins {
+    fn new(enable_a: u64, in_a1: u64, in_a2: u64, enable_b: u64, in_b1: u64, in_b2: u64)
+           -> Self {
+        DualPins {
+            enable_a: sysfs_gpio::Pin::new(enable_a),
+            in_a1: sysfs_gpio::Pin::new(in_a1),
+            in_a2: sysfs_gpio::Pin::new(in_a2),
+            enable_b: sysfs_gpio::Pin::new(enable_b),
+            in_b1: sysfs_gpio::Pin::new(in_b1),
+            in_b2: sysfs_gpio::Pin::new(in_b2),
+            wiring: motor::Wiring::default(),
+        }
+    }
+
+    // Channel B drives the left wheel unless the sides are swapped
+    fn channels(&self, left: Role, right: Role) -> Vec<ChannelPins> {
+        let (a, b) = if self.wiring.swap_sides {
+            (left, right)
+        } else {
+            (right, left)
+        };
+        vec![ChannelPins {
+                 role: a,
+                 enable: self.enable_a,
+                 in1: self.in_a1,
+                 in2: self.in_a2,
+                 invert: self.wiring.invert_a,
+             },
+             ChannelPins {
+                 role: b,
+                 enable: self.enable_b,
+                 in1: self.in_b1,
+                 in2: self.in_b2,
+                 invert: self.wiring.invert_b,
+             }]
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ChannelPins {
+    role: Role,
+    enable: sysfs_gpio::Pin,
+    in1: sysfs_gpio::Pin,
+    in2: sysfs_gpio::Pin,
+    invert: bool,
+}
+
+impl ChannelPins {
+    fn pins(&self) -> Vec<sysfs_gpio::Pin> {
+        vec![self.enable, self.in1, self.in2]
+    }
+
+    fn build(&self) -> Result<(Role, motor::Channel)> {
+        let channel = motor::Channel::new(self.enable, self.in1, self.in2)?
+            .with_invert(self.invert);
+        Ok((self.role, channel))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct StepperPins {
+    role: Role,
+    step: sysfs_gpio::Pin,
+    dir: sysfs_gpio::Pin,
+    enable: Option<sysfs_gpio::Pin>,
+    invert: bool,
+}
+
+impl StepperPins {
+    fn pins(&self) -> Vec<sysfs_gpio::Pin> {
+        let mut pins = vec![self.step, self.dir];
+        pins.extend(self.enable);
+        pins
+    }
+
+    fn build(&self, settings: stepper::Settings) -> Result<stepper::Stepper> {
+        Ok(stepper::Stepper::new(self.step, self.dir, self.enable, settings)?
+            .with_invert(self.invert))
+    }
 }
 
 // Options are used because there is no clear default for pins
 #[derive(Debug, Default)]
 pub struct Builder {
-    front_enable_a: Option<sysfs_gpio::Pin>,
-    front_in_a1: Option<sysfs_gpio::Pin>,
-    front_in_a2: Option<sysfs_gpio::Pin>,
-    front_enable_b: Option<sysfs_gpio::Pin>,
-    front_in_b1: Option<sysfs_gpio::Pin>,
-    front_in_b2: Option<sysfs_gpio::Pin>,
-
-    rear_enable_a: Option<sysfs_gpio::Pin>,
-    rear_in_a1: Option<sysfs_gpio::Pin>,
-    rear_in_a2: Option<sysfs_gpio::Pin>,
-    rear_enable_b: Option<sysfs_gpio::Pin>,
-    rear_in_b1: Option<sysfs_gpio::Pin>,
-    rear_in_b2: Option<sysfs_gpio::Pin>,
+    front_motors: Option<DualPins>,
+    rear_motors: Option<DualPins>,
+    channels: Vec<ChannelPins>,
+    steppers: Vec<StepperPins>,
+    microstep_pins: Option<[sysfs_gpio::Pin; 3]>,
+    stepper_settings: stepper::Settings,
+    drivetrain: drivetrain::Kind,
 
-    front_trigger: Option<sysfs_gpio::Pin>,
-    front_echo: Option<sysfs_gpio::Pin>,
+    front_sensor: Option<distance::Spec>,
+    rear_sensor: Option<distance::Spec>,
+    left_sensor: Option<distance::Spec>,
+    right_sensor: Option<distance::Spec>,
+    ping_gap: Option<time::Duration>,
+    sample_period: Option<time::Duration>,
 
-    rear_trigger: Option<sysfs_gpio::Pin>,
-    rear_echo: Option<sysfs_gpio::Pin>,
+    scanner_servo: Option<sysfs_gpio::Pin>,
+    scanner_trigger: Option<sysfs_gpio::Pin>,
+    scanner_echo: Option<sysfs_gpio::Pin>,
+    servo: servo::Settings,
 
-    left_trigger: Option<sysfs_gpio::Pin>,
-    left_echo: Option<sysfs_gpio::Pin>,
-
-    right_trigger: Option<sysfs_gpio::Pin>,
-    right_echo: Option<sysfs_gpio::Pin>,
+    thresholds: Thresholds,
+    speeds: motor::Speeds,
+    stop: motor::Stop,
+    wall_follow: WallFollow,
 
     map: map::Map,
+    commands: Option<Vec<Command>>,
+    observers: Vec<Arc<dyn trace::Observer>>,
+    replay: Option<trace::Trace>,
+    state_file: Option<PathBuf>,
+    resume: Option<RunState>,
 }
 
 impl Builder {
@@ -55,6 +166,65 @@ impl Builder {
         Builder::default()
     }
 
+    /// Creates a builder with the pins and tuning from a robot profile.
+    pub fn from_config(config: &config::RobotConfig) -> Builder {
+        let sensors = &config.sensors;
+        let mut builder = Builder::new().drivetrain(config.drivetrain);
+        if let Some(ref motors) = config.motors {
+            builder = builder.front_motor_pins(motors.front.enable_a,
+                                  motors.front.in_a1,
+                                  motors.front.in_a2,
+                                  motors.front.enable_b,
+                                  motors.front.in_b1,
+                                  motors.front.in_b2)
+                .rear_motor_pins(motors.rear.enable_a,
+                                 motors.rear.in_a1,
+                                 motors.rear.in_a2,
+                                 motors.rear.enable_b,
+                                 motors.rear.in_b1,
+                                 motors.rear.in_b2)
+                .front_motor_wiring(motors.front.wiring)
+                .rear_motor_wiring(motors.rear.wiring);
+        }
+        for channel in &config.channels {
+            builder = builder.channel(channel.role,
+                                      channel.enable,
+                                      channel.in1,
+                                      channel.in2,
+                                      channel.invert);
+        }
+        for stepper in &config.steppers {
+            builder = builder.stepper(stepper.role,
+                                      stepper.step,
+                                      stepper.dir,
+                                      stepper.enable,
+                                      stepper.invert);
+        }
+        if let Some(pins) = config.microstep_pins {
+            builder = builder.microstep_pins(pins[0], pins[1], pins[2]);
+        }
+        builder = builder.ping_gap(time::Duration::from_millis(sensors.ping_gap_ms))
+            .sample_period(time::Duration::from_millis(sensors.sample_ms));
+        // Incomplete sensors are left out, so building reports which one it was
+        for &(direction, sensor) in &[(Direction::Forward, &sensors.front),
+                                      (Direction::Backward, &sensors.rear),
+                                      (Direction::Left, &sensors.left),
+                                      (Direction::Right, &sensors.right)] {
+            if let Some(spec) = sensor.spec() {
+                builder = builder.distance_sensor(direction, spec);
+            }
+        }
+        if let Some(ref scanner) = config.scanner {
+            builder = builder.scanner_pins(scanner.servo, scanner.trigger, scanner.echo)
+                .servo(scanner.calibration);
+        }
+        builder.stepper_settings(config.stepper)
+            .speeds(config.speeds)
+            .stop(config.stop)
+            .wall_follow(config.wall_follow)
+            .thresholds(config.thresholds)
+    }
+
     pub fn front_motor_pins(mut self,
                             enable_a: u64,
                             in_a1: u64,
@@ -63,12 +233,7 @@ impl Builder {
                             in_b1: u64,
                             in_b2: u64)
                             -> Self {
-        self.front_enable_a = Some(sysfs_gpio::Pin::new(enable_a));
-        self.front_in_a1 = Some(sysfs_gpio::Pin::new(in_a1));
-        self.front_in_a2 = Some(sysfs_gpio::Pin::new(in_a2));
-        self.front_enable_b = Some(sysfs_gpio::Pin::new(enable_b));
-        self.front_in_b1 = Some(sysfs_gpio::Pin::new(in_b1));
-        self.front_in_b2 = Some(sysfs_gpio::Pin::new(in_b2));
+        self.front_motors = Some(DualPins::new(enable_a, in_a1, in_a2, enable_b, in_b1, in_b2));
         self
     }
 
@@ -80,36 +245,151 @@ impl Builder {
                            in_b1: u64,
                            in_b2: u64)
                            -> Self {
-        self.rear_enable_a = Some(sysfs_gpio::Pin::new(enable_a));
-        self.rear_in_a1 = Some(sysfs_gpio::Pin::new(in_a1));
-        self.rear_in_a2 = Some(sysfs_gpio::Pin::new(in_a2));
-        self.rear_enable_b = Some(sysfs_gpio::Pin::new(enable_b));
-        self.rear_in_b1 = Some(sysfs_gpio::Pin::new(in_b1));
-        self.rear_in_b2 = Some(sysfs_gpio::Pin::new(in_b2));
+        self.rear_motors = Some(DualPins::new(enable_a, in_a1, in_a2, enable_b, in_b1, in_b2));
         self
     }
 
-    pub fn front_distance_pins(mut self, trigger: u64, echo: u64) -> Self {
-        self.front_trigger = Some(sysfs_gpio::Pin::new(trigger));
-        self.front_echo = Some(sysfs_gpio::Pin::new(echo));
+    /// Sets how the front motor controller is wired, once its pins have been given.
+    pub fn front_motor_wiring(mut self, wiring: motor::Wiring) -> Self {
+        if let Some(ref mut motors) = self.front_motors {
+            motors.wiring = wiring;
+        }
         self
     }
 
-    pub fn rear_distance_pins(mut self, trigger: u64, echo: u64) -> Self {
-        self.rear_trigger = Some(sysfs_gpio::Pin::new(trigger));
-        self.rear_echo = Some(sysfs_gpio::Pin::new(echo));
+    /// Sets how the rear motor controller is wired, once its pins have been given.
+    pub fn rear_motor_wiring(mut self, wiring: motor::Wiring) -> Self {
+        if let Some(ref mut motors) = self.rear_motors {
+            motors.wiring = wiring;
+        }
         self
     }
 
-    pub fn left_distance_pins(mut self, trigger: u64, echo: u64) -> Self {
-        self.left_trigger = Some(sysfs_gpio::Pin::new(trigger));
-        self.left_echo = Some(sysfs_gpio::Pin::new(echo));
+    /// Adds a single motor channel driving the wheel given by `role`.
+    ///
+    /// Channels can be mixed with the front and rear motor pins, as long as every wheel
+    /// of the drivetrain ends up with exactly one.
+    pub fn channel(mut self, role: Role, enable: u64, in1: u64, in2: u64, invert: bool) -> Self {
+        self.channels.push(ChannelPins {
+            role: role,
+            enable: sysfs_gpio::Pin::new(enable),
+            in1: sysfs_gpio::Pin::new(in1),
+            in2: sysfs_gpio::Pin::new(in2),
+            invert: invert,
+        });
         self
     }
 
-    pub fn right_distance_pins(mut self, trigger: u64, echo: u64) -> Self {
-        self.right_trigger = Some(sysfs_gpio::Pin::new(trigger));
-        self.right_echo = Some(sysfs_gpio::Pin::new(echo));
+    /// Adds a stepper motor driving the wheel given by `role`, for the stepper
+    /// drivetrain. The enable pin is optional, since it's often tied low.
+    pub fn stepper(mut self,
+                   role: Role,
+                   step: u64,
+                   dir: u64,
+                   enable: Option<u64>,
+                   invert: bool)
+                   -> Self {
+        self.steppers.push(StepperPins {
+            role: role,
+            step: sysfs_gpio::Pin::new(step),
+            dir: sysfs_gpio::Pin::new(dir),
+            enable: enable.map(sysfs_gpio::Pin::new),
+            invert: invert,
+        });
+        self
+    }
+
+    /// Sets the microstep select pins shared by the stepper drivers, which are set
+    /// to match `stepper_settings`.
+    pub fn microstep_pins(mut self, ms1: u64, ms2: u64, ms3: u64) -> Self {
+        self.microstep_pins = Some([sysfs_gpio::Pin::new(ms1),
+                                    sysfs_gpio::Pin::new(ms2),
+                                    sysfs_gpio::Pin::new(ms3)]);
+        self
+    }
+
+    pub fn stepper_settings(mut self, settings: stepper::Settings) -> Self {
+        self.stepper_settings = settings;
+        self
+    }
+
+    pub fn drivetrain(mut self, kind: drivetrain::Kind) -> Self {
+        self.drivetrain = kind;
+        self
+    }
+
+    pub fn speeds(mut self, speeds: motor::Speeds) -> Self {
+        self.speeds = speeds;
+        self
+    }
+
+    /// Sets how the motors stop at the end of each move.
+    pub fn stop(mut self, stop: motor::Stop) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    pub fn wall_follow(mut self, wall_follow: WallFollow) -> Self {
+        self.wall_follow = wall_follow;
+        self
+    }
+
+    /// Sets the sensor facing `direction`, which can be any kind of range sensor.
+    pub fn distance_sensor(mut self, direction: Direction, spec: distance::Spec) -> Self {
+        match direction {
+            Direction::Forward => self.front_sensor = Some(spec),
+            Direction::Backward => self.rear_sensor = Some(spec),
+            Direction::Left => self.left_sensor = Some(spec),
+            Direction::Right => self.right_sensor = Some(spec),
+        }
+        self
+    }
+
+    /// Sets how long ultrasonic sensors wait after each other's pings before pinging.
+    pub fn ping_gap(mut self, gap: time::Duration) -> Self {
+        self.ping_gap = Some(gap);
+        self
+    }
+
+    /// Sets how often the sensors are read. They're read as fast as they can be by
+    /// default.
+    pub fn sample_period(mut self, period: time::Duration) -> Self {
+        self.sample_period = Some(period);
+        self
+    }
+
+    pub fn front_distance_pins(self, trigger: u64, echo: u64) -> Self {
+        self.distance_sensor(Direction::Forward, hc_sr04(trigger, echo))
+    }
+
+    pub fn rear_distance_pins(self, trigger: u64, echo: u64) -> Self {
+        self.distance_sensor(Direction::Backward, hc_sr04(trigger, echo))
+    }
+
+    pub fn left_distance_pins(self, trigger: u64, echo: u64) -> Self {
+        self.distance_sensor(Direction::Left, hc_sr04(trigger, echo))
+    }
+
+    pub fn right_distance_pins(self, trigger: u64, echo: u64) -> Self {
+        self.distance_sensor(Direction::Right, hc_sr04(trigger, echo))
+    }
+
+    /// Sets the pins for a distance sensor mounted on a servo, for `Controller::scan`.
+    pub fn scanner_pins(mut self, servo: u64, trigger: u64, echo: u64) -> Self {
+        self.scanner_servo = Some(sysfs_gpio::Pin::new(servo));
+        self.scanner_trigger = Some(sysfs_gpio::Pin::new(trigger));
+        self.scanner_echo = Some(sysfs_gpio::Pin::new(echo));
+        self
+    }
+
+    /// Sets how the scanning sensor's servo turns.
+    pub fn servo(mut self, settings: servo::Settings) -> Self {
+        self.servo = settings;
+        self
+    }
+
+    pub fn thresholds(mut self, thresholds: Thresholds) -> Self {
+        self.thresholds = thresholds;
         self
     }
 
@@ -118,62 +398,225 @@ impl Builder {
         self
     }
 
-    pub fn build(self) -> Result<Controller> {
-        gpio_export!(self, {
-            front_enable_a, front_in_a1, front_in_a2, front_enable_b, front_in_b1, front_in_b2,
-            rear_enable_a, rear_in_a1, rear_in_a2, rear_enable_b, rear_in_b1, rear_in_b2,
-            front_trigger, front_echo, rear_trigger, rear_echo,
-            left_trigger, left_echo, right_trigger, right_echo
-        });
+    /// Runs the given commands instead of the path through the map.
+    pub fn commands(mut self, commands: Vec<Command>) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    /// Tells `observer` about the sensor readings, drivetrain calls and commands
+    /// during the run, such as to record them to a trace.
+    pub fn observer(mut self, observer: Arc<dyn trace::Observer>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Plays back a recorded run instead of driving the robot. No pins are touched,
+    /// and the sensors give back the trace's readings at the pace they were recorded.
+    pub fn replay(mut self, trace: trace::Trace) -> Self {
+        self.replay = Some(trace);
+        self
+    }
+
+    /// Saves where the run is up to after each command, for `resume` to carry on from.
+    pub fn state_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// Carries on a run through the map from where an earlier one got to, instead of
+    /// starting from the first node.
+    pub fn resume(mut self, state: RunState) -> Self {
+        self.resume = Some(state);
+        self
+    }
+
+    pub fn build(mut self) -> Result<Controller> {
+        // Plan before touching any pins, so a bad map doesn't leave them exported
+        let plan = match self.commands {
+            Some(ref commands) => commands.iter().map(|&command| (command, None)).collect(),
+            None if self.map.is_empty() => vec![],
+            None => {
+                let path = match self.resume {
+                    Some(RunState { node: Some(node), heading, .. }) => {
+                        info!("Resuming from node {}", node);
+                        self.map.path_from(node, heading)?
+                    }
+                    _ => self.map.path()?,
+                };
+                if self.drivetrain.strafes() {
+                    path.into_strafe_steps()
+                } else {
+                    path.into_steps()
+                }
+            }
+        };
+        if let Some(trace) = self.replay.take() {
+            let drivetrain = Arc::new(trace::NullDrivetrain::new(self.drivetrain));
+            return Ok(self.controller(drivetrain, trace.sensors(), None, plan));
+        }
+
+        let steps = self.drivetrain == drivetrain::Kind::Stepper;
+        let channels = if steps { vec![] } else { self.motor_channels() };
+        let steppers = if steps { self.steppers.clone() } else { vec![] };
+        let roles = channels.iter()
+            .map(|c| c.role)
+            .chain(steppers.iter().map(|s| s.role))
+            .collect::<Vec<_>>();
+        if !self.drivetrain.fits(&roles) {
+            return Err(Error::Build(BuilderError::MotorChannels));
+        }
+        let settings = self.stepper_settings;
+        let mode = settings.driver.mode(settings.microsteps);
+        if steps && mode.is_none() {
+            return Err(Error::Build(BuilderError::Microsteps));
+        }
+        let mut motor_pins = channels.iter()
+            .flat_map(|c| c.pins())
+            .chain(steppers.iter().flat_map(|s| s.pins()))
+            .collect::<Vec<_>>();
+        if steps {
+            motor_pins.extend(self.microstep_pins.iter().flat_map(|pins| pins.to_vec()));
+        }
+        for pin in &motor_pins {
+            pin.export()?;
+        }
+        for pin in self.sensor_pins() {
+            pin.export()?;
+        }
+        gpio_export!(self, {scanner_servo, scanner_trigger, scanner_echo});
 
         // Make sure export is finished
-        self.poll_pin_init()?;
-
-        let front_motors = build!(self, motor::Controller, BuilderError::FrontMotorPins,
-                                  {front_enable_a, front_in_a1, front_in_a2,
-                                   front_enable_b, front_in_b1, front_in_b2});
-        let rear_motors = build!(self, motor::Controller, BuilderError::RearMotorPins,
-                                 {rear_enable_a, rear_in_a1, rear_in_a2,
-                                  rear_enable_b, rear_in_b1, rear_in_b2});
-        let front_distance_sensor = build!(self, distance::Sensor,
-                                           BuilderError::FrontDistancePins,
-                                           {front_trigger, front_echo});
-        let rear_distance_sensor = build!(self, distance::Sensor,
-                                          BuilderError::RearDistancePins,
-                                          {rear_trigger, rear_echo});
-        let left_distance_sensor = build!(self, distance::Sensor,
-                                          BuilderError::LeftDistancePins,
-                                          {left_trigger, left_echo});
-        let right_distance_sensor = build!(self, distance::Sensor,
-                                           BuilderError::RightDistancePins,
-                                           {right_trigger, right_echo});
-        let commands = self.map.path().into_commands();
-        Ok(Controller {
-            front_motors: front_motors,
-            rear_motors: rear_motors,
-            front_distance_sensor: front_distance_sensor,
-            rear_distance_sensor: rear_distance_sensor,
-            left_distance_sensor: left_distance_sensor,
-            right_distance_sensor: right_distance_sensor,
-
-            pool: futures_cpupool::CpuPool::new_num_cpus(),
+        self.poll_pin_init(&motor_pins)?;
+
+        let drivetrain: Arc<dyn Drivetrain> = match (steps, self.microstep_pins, mode) {
+            (true, pins, Some(mode)) => {
+                if let Some(pins) = pins {
+                    for (pin, &level) in pins.iter().zip(mode.iter()) {
+                        pin.set_direction(sysfs_gpio::Direction::Out)?;
+                        pin.set_value(level)?;
+                    }
+                }
+                let stepper = |role: Role| {
+                    steppers.iter()
+                        .find(|s| s.role == role)
+                        .ok_or(Error::Build(BuilderError::MotorChannels))
+                        .and_then(|s| s.build(settings))
+                };
+                Arc::new(stepper::Steppers::new(stepper(Role::Left)?,
+                                                stepper(Role::Right)?,
+                                                settings))
+            }
+            _ => {
+                let channels = channels.iter().map(|c| c.build()).collect::<Result<Vec<_>>>()?;
+                self.drivetrain
+                    .build(channels)
+                    .ok_or(Error::Build(BuilderError::MotorChannels))?
+            }
+        };
+        // Every sensor that could hear another's pings takes turns with the rest
+        let gap = time::Duration::from_millis(distance::DEFAULT_PING_GAP_MS);
+        let scheduler = distance::Scheduler::new(self.ping_gap.unwrap_or(gap));
+        let front_distance_sensor =
+            scheduler.schedule(build!(self, front_sensor, BuilderError::FrontDistancePins));
+        let rear_distance_sensor =
+            scheduler.schedule(build!(self, rear_sensor, BuilderError::RearDistancePins));
+        let left_distance_sensor =
+            scheduler.schedule(build!(self, left_sensor, BuilderError::LeftDistancePins));
+        let right_distance_sensor =
+            scheduler.schedule(build!(self, right_sensor, BuilderError::RightDistancePins));
+        // The scanner is optional, so it's only built if all of its pins were given
+        let scanner = match (self.scanner_servo, self.scanner_trigger, self.scanner_echo) {
+            (Some(servo), Some(trigger), Some(echo)) => {
+                Some(Scanner {
+                    servo: servo::Servo::new(servo, self.servo)?,
+                    sensor: scheduler.schedule(Arc::new(distance::HcSr04::new(trigger, echo)?)),
+                })
+            }
+            _ => None,
+        };
+        let sensors = vec![(Direction::Forward, front_distance_sensor),
+                           (Direction::Backward, rear_distance_sensor),
+                           (Direction::Left, left_distance_sensor),
+                           (Direction::Right, right_distance_sensor)];
+        Ok(self.controller(drivetrain, sensors, scanner, plan))
+    }
+
+    fn controller(self,
+                  drivetrain: Arc<dyn Drivetrain>,
+                  sensors: Vec<(Direction, distance::Sensor)>,
+                  scanner: Option<Scanner>,
+                  plan: Vec<(Command, Option<u32>)>)
+                  -> Controller {
+        let mut drivetrain = drivetrain;
+        let mut sensors = sensors;
+        for observer in &self.observers {
+            drivetrain = trace::observe_drivetrain(drivetrain, observer.clone());
+            sensors = sensors.into_iter()
+                .map(|(direction, sensor)| {
+                    (direction, trace::observe_sensor(direction, sensor, observer.clone()))
+                })
+                .collect();
+        }
+        // Everything else reads the sensors through the sampler, without pinging them
+        let sampler = distance::Sampler::new(sensors, self.sample_period.unwrap_or_default());
+        let thresholds = self.map.thresholds().apply(self.thresholds);
+        let queue = Queue::starting_at(self.resume.map_or(0, |state| state.index));
+        for (command, node) in plan {
+            queue.submit_at(command, node);
+        }
+        Controller {
+            drivetrain: drivetrain,
+            front_distance_sensor: sampler.sensor(Direction::Forward),
+            rear_distance_sensor: sampler.sensor(Direction::Backward),
+            left_distance_sensor: sampler.sensor(Direction::Left),
+            right_distance_sensor: sampler.sensor(Direction::Right),
+            sampler: sampler,
+            scanner: scanner,
+
+            pool: futures_cpupool::CpuPool::new(POOL_THREADS),
+            speeds: self.speeds,
+            stop: self.stop,
+            wall_follow: self.wall_follow,
+            base_thresholds: thresholds,
+            thresholds: thresholds,
             map: self.map,
-            commands: commands,
-        })
-    }
-
-    fn poll_pin_init(&self) -> Result<()> {
-        // Unwrapping is fine here; if this fails, it means is_some is broken.
-        let pins = vec![&self.front_enable_a, &self.front_in_a1, &self.front_in_a2,
-                        &self.front_enable_b, &self.front_in_b1, &self.front_in_b2,
-                        &self.rear_enable_a, &self.rear_in_a1, &self.rear_in_a2,
-                        &self.rear_enable_b, &self.rear_in_b1, &self.rear_in_b2,
-                        &self.front_trigger, &self.front_echo,
-                        &self.rear_trigger, &self.rear_echo,
-                        &self.left_trigger, &self.left_echo,
-                        &self.right_trigger, &self.right_echo]
-            .into_iter()
-            .filter_map(|pin| pin.as_ref())
+            queue: queue,
+            state_file: self.state_file,
+            heading: self.resume.map_or(0, |state| state.heading),
+            interrupts: Arc::new(AtomicUsize::new(0)),
+            observers: self.observers,
+        }
+    }
+
+    // Every motor channel, from both the front and rear controllers and single channels
+    fn motor_channels(&self) -> Vec<ChannelPins> {
+        let mut channels = vec![];
+        if let Some(ref motors) = self.front_motors {
+            channels.extend(motors.channels(Role::FrontLeft, Role::FrontRight));
+        }
+        if let Some(ref motors) = self.rear_motors {
+            channels.extend(motors.channels(Role::RearLeft, Role::RearRight));
+        }
+        channels.extend(self.channels.iter().cloned());
+        channels
+    }
+
+    // Pins for whichever of the four range sensors use GPIO
+    fn sensor_pins(&self) -> Vec<sysfs_gpio::Pin> {
+        [&self.front_sensor, &self.rear_sensor, &self.left_sensor, &self.right_sensor]
+            .iter()
+            .filter_map(|spec| spec.as_ref())
+            .flat_map(|spec| spec.pins())
+            .collect()
+    }
+
+    fn poll_pin_init(&self, motor_pins: &[sysfs_gpio::Pin]) -> Result<()> {
+        let scanner = vec![&self.scanner_servo, &self.scanner_trigger, &self.scanner_echo];
+        let pins = motor_pins.iter()
+            .cloned()
+            .chain(self.sensor_pins())
+            .chain(scanner.into_iter().filter_map(|pin| pin.as_ref()).cloned())
             .collect::<Vec<_>>();
         retry::retry(10,
                      50,
@@ -187,3 +630,43 @@ impl Builder {
             .and_then(|_| Ok(()))
     }
 }
+
+fn hc_sr04(trigger: u64, echo: u64) -> distance::Spec {
+    distance::Spec::HcSr04 {
+        trigger: sysfs_gpio::Pin::new(trigger),
+        echo: sysfs_gpio::Pin::new(echo),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use motor::{Role, Wiring};
+    use super::DualPins;
+
+    // Which pins and inversion each role ends up with, as enable pin numbers
+    fn wired(wiring: Wiring) -> Vec<(Role, u64, bool)> {
+        let mut pins = DualPins::new(1, 2, 3, 4, 5, 6);
+        pins.wiring = wiring;
+        pins.channels(Role::FrontLeft, Role::FrontRight)
+            .iter()
+            .map(|channel| (channel.role, channel.enable.get_pin(), channel.invert))
+            .collect()
+    }
+
+    #[test]
+    fn channel_b_drives_the_left_wheel() {
+        assert_eq!(wired(Wiring::default()),
+                   vec![(Role::FrontRight, 1, false), (Role::FrontLeft, 4, false)]);
+    }
+
+    #[test]
+    fn swapped_sides_keep_their_inversion() {
+        let wiring = Wiring {
+            swap_sides: true,
+            invert_a: true,
+            invert_b: false,
+        };
+        assert_eq!(wired(wiring),
+                   vec![(Role::FrontLeft, 1, true), (Role::FrontRight, 4, false)]);
+    }
+}
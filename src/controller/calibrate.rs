@@ -0,0 +1,163 @@
+use std::time;
+
+use futures::Future;
+
+use Direction;
+use error;
+use threshold::{Threshold, Thresholds};
+use super::Controller;
+
+// A band of three standard deviations keeps noise from crossing a threshold
+const NOISE_DEVIATIONS: f32 = 3.0;
+const MIN_HYSTERESIS: f32 = 0.5;
+// Openings to the side have to read at least this much further than the corridor walls
+const SIDE_MARGIN: f32 = 0.2;
+
+const SENSORS: [Direction; 4] = [Direction::Forward,
+                                 Direction::Backward,
+                                 Direction::Left,
+                                 Direction::Right];
+
+/// What `Controller::calibrate` measured, and the thresholds it derived from that.
+#[derive(Debug)]
+pub struct Calibration {
+    /// Distance to the nearest wall seen by each sensor while rotating,
+    /// in front, rear, left, right order.
+    pub walls: [f32; 4],
+    /// Standard deviation of each sensor's readings while standing still,
+    /// in front, rear, left, right order.
+    pub noise: [f32; 4],
+    pub thresholds: Thresholds,
+}
+
+impl Controller {
+    /// Measures the sensors and derives thresholds for them.
+    ///
+    /// The robot should be placed in the middle of a straight corridor of the width used
+    /// in the maze. Each sensor is sampled `samples` times standing still to measure its
+    /// noise, then the robot rotates in place for `turn` in `steps` increments, keeping the
+    /// closest reading from each sensor. That is the distance from the sensor to a corridor
+    /// wall when facing it square on, so `turn` should be long enough for at least a
+    /// half turn.
+    ///
+    /// Front and rear sensors become blocked at their wall distance, which stops the robot
+    /// centered in a junction, while the side sensors only become clear well beyond their
+    /// wall distance. Every hysteresis band covers the sensor's noise.
+    pub fn calibrate(&mut self,
+                     samples: usize,
+                     turn: time::Duration,
+                     steps: u32)
+                     -> Result<Calibration, error::Error> {
+        let mut readings = vec![vec![]; SENSORS.len()];
+        for _ in 0..samples {
+            for (i, &direction) in SENSORS.iter().enumerate() {
+                readings[i].push(self.distance(direction).wait()?.distance);
+            }
+        }
+        let mut noise = [0.0; 4];
+        let mut walls = [0.0; 4];
+        for i in 0..SENSORS.len() {
+            noise[i] = std_dev(&readings[i]);
+            walls[i] = readings[i].iter().cloned().fold(::std::f32::INFINITY, f32::min);
+        }
+
+        let step = turn / steps.max(1);
+        for _ in 0..steps {
+            self.turn_for(Direction::Left, step).wait()?;
+            for (i, &direction) in SENSORS.iter().enumerate() {
+                let distance = self.distance(direction).wait()?.distance;
+                trace!("{:?} distance {}", direction, distance);
+                walls[i] = walls[i].min(distance);
+            }
+        }
+
+        Ok(Calibration {
+            walls: walls,
+            noise: noise,
+            thresholds: thresholds(&walls, &noise),
+        })
+    }
+}
+
+// Thresholds for walls and noise in front, rear, left, right order
+fn thresholds(walls: &[f32; 4], noise: &[f32; 4]) -> Thresholds {
+    let hysteresis = |i: usize| (noise[i] * NOISE_DEVIATIONS).max(MIN_HYSTERESIS);
+    let front_back = |i: usize| {
+        Threshold {
+            distance: walls[i] + hysteresis(i),
+            hysteresis: hysteresis(i),
+        }
+    };
+    let left_right = |i: usize| {
+        let margin = (walls[i] * SIDE_MARGIN).max(2.0 * hysteresis(i));
+        Threshold {
+            distance: walls[i] + margin,
+            hysteresis: hysteresis(i),
+        }
+    };
+    Thresholds {
+        front: front_back(0),
+        rear: front_back(1),
+        left: left_right(2),
+        right: left_right(3),
+    }
+}
+
+fn std_dev(values: &[f32]) -> f32 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() /
+                   (values.len() - 1) as f32;
+    variance.sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use threshold::Threshold;
+    use super::{std_dev, thresholds};
+
+    #[test]
+    fn sample_standard_deviation() {
+        assert_eq!(std_dev(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]),
+                   (32.0f32 / 7.0).sqrt());
+        assert_eq!(std_dev(&[3.0, 3.0, 3.0]), 0.0);
+        // Too few readings to tell
+        assert_eq!(std_dev(&[3.0]), 0.0);
+        assert_eq!(std_dev(&[]), 0.0);
+    }
+
+    #[test]
+    fn front_and_rear_block_at_the_wall() {
+        let derived = thresholds(&[30.0, 20.0, 40.0, 40.0], &[1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(derived.front,
+                   Threshold {
+                       distance: 33.0,
+                       hysteresis: 3.0,
+                   });
+        // Quiet sensors still get the smallest band
+        assert_eq!(derived.rear,
+                   Threshold {
+                       distance: 20.5,
+                       hysteresis: 0.5,
+                   });
+    }
+
+    #[test]
+    fn sides_clear_well_past_the_wall() {
+        let derived = thresholds(&[30.0, 30.0, 40.0, 10.0], &[0.0, 0.0, 0.5, 1.0]);
+        // A fifth of the wall distance past it
+        assert_eq!(derived.left,
+                   Threshold {
+                       distance: 48.0,
+                       hysteresis: 1.5,
+                   });
+        // Or twice the hysteresis if that's further
+        assert_eq!(derived.right,
+                   Threshold {
+                       distance: 16.0,
+                       hysteresis: 3.0,
+                   });
+    }
+}
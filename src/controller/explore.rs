@@ -0,0 +1,75 @@
+use futures::Future;
+
+use Direction;
+use error;
+use map;
+use super::Controller;
+
+impl Controller {
+    /// Explores an unknown maze depth-first and returns the map of the junctions found.
+    ///
+    /// The robot should start at a junction facing into the maze. The maze is assumed
+    /// to be a tree, so a loop back to an already visited junction shows up as a new node.
+    /// No more than `max_nodes` nodes are explored, and the robot finishes back where it
+    /// started, facing the same way. Every node is marked as a non-goal.
+    pub fn explore(&mut self, max_nodes: usize) -> Result<map::Map, error::Error> {
+        let mut nodes = vec![false];
+        let mut edges = vec![];
+        self.explore_node(0, &mut nodes, &mut edges, max_nodes)?;
+        Ok(map::Map::from_nodes_edges(nodes, edges)?)
+    }
+
+    fn explore_node(&mut self,
+                    node: u32,
+                    nodes: &mut Vec<bool>,
+                    edges: &mut Vec<map::Edge<Direction>>,
+                    max_nodes: usize)
+                    -> Result<(), error::Error> {
+        for direction in self.open_directions()? {
+            if nodes.len() >= max_nodes {
+                break;
+            }
+            let child = nodes.len() as u32;
+            nodes.push(false);
+            edges.push(map::Edge::new(node, child, direction));
+            info!("Exploring {:?} from node {} to node {}", direction, node, child);
+
+            // The same moves `Path::into_commands` uses to follow this edge
+            match direction {
+                Direction::Left | Direction::Right => self.turn(direction).wait()?,
+                Direction::Forward | Direction::Backward => {}
+            }
+            self.travel(Direction::Forward).wait()?;
+
+            self.explore_node(child, nodes, edges, max_nodes)?;
+
+            // Turn around, head back, then face the way we were facing when we first
+            // arrived here so the remaining directions are still correct.
+            self.turn(Direction::Left).wait()?;
+            self.turn(Direction::Left).wait()?;
+            self.travel(Direction::Forward).wait()?;
+            match direction {
+                Direction::Forward | Direction::Backward => {
+                    self.turn(Direction::Left).wait()?;
+                    self.turn(Direction::Left).wait()?;
+                }
+                Direction::Left => self.turn(Direction::Left).wait()?,
+                Direction::Right => self.turn(Direction::Right).wait()?,
+            }
+        }
+        Ok(())
+    }
+
+    // Backward is never open: it's where we came from
+    fn open_directions(&mut self) -> Result<Vec<Direction>, error::Error> {
+        let mut open = vec![];
+        // A scanning sensor looks at the same three directions as the fixed sensors
+        for vector in self.scan(-90.0, 90.0, 90.0).wait()? {
+            let threshold = self.thresholds.get(vector.direction);
+            if vector.distance > threshold.distance {
+                open.push(vector.direction);
+            }
+        }
+        Ok(open)
+    }
+}
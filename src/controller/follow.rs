@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time;
+
+use futures::Future;
+use futures_cpupool as cpupool;
+
+use distance;
+use drivetrain::Drivetrain;
+use error;
+use pid;
+use threshold::Thresholds;
+
+/// Settings for keeping the robot centered in corridors while it moves forward.
+///
+/// With walls on both sides the robot steers to keep the two side readings equal. When
+/// one side opens up it keeps the same distance from the remaining wall instead, and
+/// with no walls at all it drives straight.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct WallFollow {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The most either side's speed is changed by
+    #[serde(default = "WallFollow::default_max_correction")]
+    pub max_correction: f32,
+    /// Distance to keep from a single wall until both walls have been seen
+    #[serde(default = "WallFollow::default_wall_distance")]
+    pub wall_distance: f32,
+    // Last, since TOML can't have plain values after a table
+    #[serde(default = "WallFollow::default_gains")]
+    pub gains: pid::Gains,
+}
+
+impl Default for WallFollow {
+    fn default() -> Self {
+        WallFollow {
+            enabled: false,
+            max_correction: WallFollow::default_max_correction(),
+            wall_distance: WallFollow::default_wall_distance(),
+            gains: WallFollow::default_gains(),
+        }
+    }
+}
+
+impl WallFollow {
+    fn default_gains() -> pid::Gains {
+        pid::Gains {
+            kp: 0.02,
+            ki: 0.0,
+            kd: 0.002,
+        }
+    }
+
+    fn default_max_correction() -> f32 {
+        0.3
+    }
+
+    fn default_wall_distance() -> f32 {
+        20.0
+    }
+}
+
+/// Everything the wall follower needs, cloned out of the controller.
+pub struct Follower {
+    pub drivetrain: Arc<dyn Drivetrain>,
+    pub left_sensor: distance::Sensor,
+    pub right_sensor: distance::Sensor,
+    pub thresholds: Thresholds,
+    pub settings: WallFollow,
+    pub speed: f32,
+}
+
+/// A running wall follower.
+pub struct Following {
+    done: Arc<AtomicBool>,
+    future: cpupool::CpuFuture<(), error::Error>,
+}
+
+impl Following {
+    /// Stops steering, leaving the motors running at whatever speeds they were last set to.
+    pub fn finish(self) -> Result<(), error::Error> {
+        self.done.store(true, Ordering::SeqCst);
+        self.future.wait()
+    }
+}
+
+impl Follower {
+    pub fn spawn(self, pool: &cpupool::CpuPool) -> Following {
+        let done = Arc::new(AtomicBool::new(false));
+        let finished = done.clone();
+        let future = pool.spawn_fn(move || {
+            let mut pid = pid::Pid::new(self.settings.gains, self.settings.max_correction);
+            let mut target = self.settings.wall_distance;
+            let mut last = time::Instant::now();
+            while !finished.load(Ordering::SeqCst) {
+                let left = self.left_sensor.value()?;
+                let right = self.right_sensor.value()?;
+                let now = time::Instant::now();
+                let elapsed = now.duration_since(last);
+                let dt = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1e9f32;
+                last = now;
+
+                // A positive error means we're closer to the left wall and should steer right
+                let left_wall = left < self.thresholds.left.distance;
+                let right_wall = right < self.thresholds.right.distance;
+                let error = match (left_wall, right_wall) {
+                    (true, true) => {
+                        target = (left + right) / 2.0;
+                        (right - left) / 2.0
+                    }
+                    (true, false) => target - left,
+                    (false, true) => right - target,
+                    (false, false) => {
+                        pid.reset();
+                        0.0
+                    }
+                };
+                let correction = pid.update(error, dt);
+                let left_speed = (self.speed + correction).max(0.0).min(1.0);
+                let right_speed = (self.speed - correction).max(0.0).min(1.0);
+                self.drivetrain.drive(left_speed, right_speed)?;
+            }
+            Ok(())
+        });
+        Following {
+            done: done,
+            future: future,
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use futures::Future;
+
+use Direction;
+use error::{self, MapError};
+use map::{self, QUARTERS};
+use super::Controller;
+
+// How often a sensor tells an opening from a wall correctly
+const SENSOR_ACCURACY: f32 = 0.9;
+
+/// Somewhere the robot might be, and how likely it is to be there.
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Candidate {
+    pub node: u32,
+    /// Quarter turns clockwise from the way the robot faces at the map's start node
+    pub heading: u8,
+    pub probability: f32,
+}
+
+impl Controller {
+    /// Works out where on `map` the robot is, having been put down at a junction facing
+    /// any of the four ways, by comparing the openings around it with each node's edges.
+    ///
+    /// A discrete Bayes filter keeps a probability for every node and heading. While no
+    /// candidate reaches `confidence`, the robot moves through whichever opening best
+    /// tells the candidates apart and looks again, up to `max_moves` times. Candidates
+    /// are for where the robot ends up, most likely first.
+    pub fn localize(&mut self,
+                    map: &map::Map,
+                    confidence: f32,
+                    max_moves: usize)
+                    -> Result<Vec<Candidate>, error::Error> {
+        let exits = map.exits();
+        if exits.is_empty() {
+            return Err(MapError::Empty.into());
+        }
+        // Indexed by node * 4 + heading
+        let mut belief = vec![1.0 / (exits.len() * 4) as f32; exits.len() * 4];
+        let mut moves = 0;
+        loop {
+            let open = self.openings()?;
+            debug!("Open, clockwise from forward: {:?}", open);
+            for (i, probability) in belief.iter_mut().enumerate() {
+                let (node, heading) = (i / 4, i % 4);
+                for (q, &open) in open.iter().enumerate() {
+                    let expected = exits[node][(heading + q) % 4].is_some();
+                    *probability *= if expected == open {
+                        SENSOR_ACCURACY
+                    } else {
+                        1.0 - SENSOR_ACCURACY
+                    };
+                }
+            }
+            normalize(&mut belief)?;
+
+            let best = belief.iter().cloned().fold(0.0, f32::max);
+            if best >= confidence || moves >= max_moves {
+                break;
+            }
+            let strafes = self.drivetrain.strafes();
+            let way = match (0..4)
+                .filter(|&q| open[q])
+                .min_by_key(|&q| (overlap(&belief, &exits, q, strafes) * 1e6) as u64) {
+                Some(way) => way,
+                None => break,
+            };
+            let direction = QUARTERS[way];
+            info!("Moving {:?} to narrow down {:.0}% sure", direction, best * 100.0);
+            // The same moves `Path::into_commands` uses, unless the robot can strafe
+            match direction {
+                Direction::Left | Direction::Right if !strafes => {
+                    self.turn(direction).wait()?;
+                    self.travel(Direction::Forward).wait()?;
+                }
+                _ => self.travel(direction).wait()?,
+            }
+            belief = moved(&belief, &exits, way, strafes);
+            normalize(&mut belief)?;
+            moves += 1;
+        }
+
+        let mut candidates = belief.iter()
+            .enumerate()
+            .filter(|&(_, &probability)| probability > 0.0)
+            .map(|(i, &probability)| {
+                Candidate {
+                    node: (i / 4) as u32,
+                    heading: (i % 4) as u8,
+                    probability: probability,
+                }
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| b.probability.partial_cmp(&a.probability).unwrap());
+        Ok(candidates)
+    }
+
+    // Whether each of the fixed sensors sees an opening, clockwise from forward
+    fn openings(&mut self) -> Result<[bool; 4], error::Error> {
+        let mut open = [false; 4];
+        for (q, &direction) in QUARTERS.iter().enumerate() {
+            let distance = self.distance(direction).wait()?.distance;
+            open[q] = distance > self.thresholds.get(direction).distance;
+        }
+        Ok(open)
+    }
+}
+
+fn normalize(belief: &mut [f32]) -> Result<(), error::Error> {
+    let total = belief.iter().sum::<f32>();
+    if total <= 0.0 {
+        return Err(MapError::NoMatch.into());
+    }
+    for probability in belief.iter_mut() {
+        *probability /= total;
+    }
+    Ok(())
+}
+
+// Where every candidate would be after moving `way` quarter turns from forward. Any
+// without an edge that way are ruled out.
+fn moved(belief: &[f32], exits: &[[Option<u32>; 4]], way: usize, strafes: bool) -> Vec<f32> {
+    let mut next = vec![0.0; belief.len()];
+    for (i, &probability) in belief.iter().enumerate() {
+        let (node, heading) = (i / 4, i % 4);
+        let out = (heading + way) % 4;
+        if let Some(other) = exits[node][out] {
+            // Turning to go left or right leaves the robot facing the way it went
+            let heading = if strafes || way == 0 || way == 2 { heading } else { out };
+            next[other as usize * 4 + heading] += probability;
+        }
+    }
+    next
+}
+
+// How likely the candidates are to still look alike after moving `way`, as the chance
+// that two of them picked at random would see the same openings. Lower tells them
+// apart better.
+fn overlap(belief: &[f32], exits: &[[Option<u32>; 4]], way: usize, strafes: bool) -> f32 {
+    let mut seen = [0.0; 16];
+    for (i, &probability) in moved(belief, exits, way, strafes).iter().enumerate() {
+        let (node, heading) = (i / 4, i % 4);
+        let pattern = (0..4)
+            .filter(|&q| exits[node][(heading + q) % 4].is_some())
+            .fold(0, |pattern, q| pattern | 1 << q);
+        seen[pattern] += probability;
+    }
+    seen.iter().map(|&p| p * p).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{moved, normalize, overlap};
+
+    // A corridor running forward from node 0 through 1 to 2
+    fn corridor() -> Vec<[Option<u32>; 4]> {
+        vec![[Some(1), None, None, None],
+             [Some(2), None, Some(0), None],
+             [None, None, Some(1), None]]
+    }
+
+    fn at(candidates: &[(usize, usize, f32)]) -> Vec<f32> {
+        let mut belief = vec![0.0; 12];
+        for &(node, heading, probability) in candidates {
+            belief[node * 4 + heading] = probability;
+        }
+        belief
+    }
+
+    #[test]
+    fn normalizes_to_one() {
+        let mut belief = at(&[(0, 0, 1.0), (1, 2, 3.0)]);
+        normalize(&mut belief).unwrap();
+        assert_eq!(belief, at(&[(0, 0, 0.25), (1, 2, 0.75)]));
+        assert!(normalize(&mut [0.0; 12]).is_err());
+    }
+
+    #[test]
+    fn moving_rules_out_candidates_without_an_edge() {
+        let belief = at(&[(0, 0, 0.5), (2, 0, 0.5)]);
+        assert_eq!(moved(&belief, &corridor(), 0, false), at(&[(1, 0, 0.5)]));
+        // Facing back down the corridor, forward is the way to node 1 again
+        let belief = at(&[(2, 2, 1.0)]);
+        assert_eq!(moved(&belief, &corridor(), 0, false), at(&[(1, 2, 1.0)]));
+    }
+
+    #[test]
+    fn turning_changes_heading_unless_strafing() {
+        // Facing right, the corridor carries on to the left
+        let belief = at(&[(1, 1, 1.0)]);
+        assert_eq!(moved(&belief, &corridor(), 3, false), at(&[(2, 0, 1.0)]));
+        assert_eq!(moved(&belief, &corridor(), 3, true), at(&[(2, 1, 1.0)]));
+        assert_eq!(moved(&belief, &corridor(), 2, false), at(&[]));
+    }
+
+    #[test]
+    fn overlap_is_lower_for_moves_that_tell_candidates_apart() {
+        // Either end of the corridor looks the same after going forward from the middle
+        let facing = at(&[(1, 0, 0.5), (1, 2, 0.5)]);
+        assert_eq!(overlap(&facing, &corridor(), 0, false), 1.0);
+        // Going forward from the start reaches the middle, from the middle the end
+        let along = at(&[(0, 0, 0.5), (1, 0, 0.5)]);
+        assert_eq!(overlap(&along, &corridor(), 0, false), 0.5);
+    }
+}
@@ -1,4 +1,7 @@
 use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
 use std::time;
 
@@ -8,16 +11,29 @@ use futures_cpupool as cpupool;
 
 use {Command, Direction};
 use distance;
+use drivetrain::Drivetrain;
 use error;
 use map;
-use motor;
+use motor::{self, Role};
+use state::RunState;
+use threshold::{Overrides, Thresholds};
+use trace;
 
 mod builder;
-
-const FB_THRESHOLD: f32 = 35.75;
-const LR_THRESHOLD: f32 = 43.0;
+mod calibrate;
+mod explore;
+mod follow;
+mod localize;
+mod queue;
+mod remote;
+mod scan;
 
 pub use self::builder::Builder;
+pub use self::calibrate::Calibration;
+pub use self::follow::WallFollow;
+pub use self::localize::Candidate;
+pub use self::queue::{Handle, Position, Queue, Status};
+pub use self::scan::Scanner;
 
 enum ThresholdLimit {
     LessThan,
@@ -25,164 +41,383 @@ enum ThresholdLimit {
     Either,
 }
 
+// Whether the loops watching the sensors for a move should carry on. They stop once
+// one of them gets to its threshold, or once the move is interrupted.
+#[derive(Clone)]
+struct Watch {
+    done: Arc<AtomicBool>,
+    interrupts: Arc<AtomicUsize>,
+    started: usize,
+}
+
+impl Watch {
+    fn new(interrupts: &Arc<AtomicUsize>) -> Watch {
+        Watch {
+            done: Arc::new(AtomicBool::new(false)),
+            interrupts: interrupts.clone(),
+            started: interrupts.load(Ordering::SeqCst),
+        }
+    }
+
+    fn watching(&self) -> bool {
+        !self.done.load(Ordering::SeqCst) && !self.interrupted()
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupts.load(Ordering::SeqCst) != self.started
+    }
+
+    // Lets the sensors that lost go, so they don't hold on to pool threads
+    fn finish(&self) {
+        self.done.store(true, Ordering::SeqCst);
+    }
+}
+
 pub struct Controller {
-    front_motors: motor::Controller,
-    rear_motors: motor::Controller,
+    drivetrain: Arc<dyn Drivetrain>,
     front_distance_sensor: distance::Sensor,
     rear_distance_sensor: distance::Sensor,
     left_distance_sensor: distance::Sensor,
     right_distance_sensor: distance::Sensor,
+    sampler: distance::Sampler,
+    scanner: Option<Scanner>,
 
     pool: cpupool::CpuPool,
+    speeds: motor::Speeds,
+    stop: motor::Stop,
+    wall_follow: WallFollow,
+    // Thresholds from the builder and map, and the ones in use after any per-edge overrides
+    base_thresholds: Thresholds,
+    thresholds: Thresholds,
     map: map::Map,
-    commands: Vec<Command>,
+    queue: Queue,
+    // Where to save the run's progress, and which way the robot faces, in quarter turns
+    // clockwise from the way it started
+    state_file: Option<PathBuf>,
+    heading: u8,
+    // Counts calls to `interrupt`, so moves can tell one happened since they started
+    interrupts: Arc<AtomicUsize>,
+    observers: Vec<Arc<dyn trace::Observer>>,
 }
 
+#[derive(Debug, Copy, Clone)]
 pub struct DistanceVector {
     pub distance: f32,
+    /// The fixed sensor direction closest to `angle`
     pub direction: Direction,
+    /// Degrees from straight ahead, positive to the left
+    pub angle: f32,
+}
+
+impl DistanceVector {
+    /// A reading from the fixed sensor facing `direction`.
+    pub fn fixed(direction: Direction, distance: f32) -> DistanceVector {
+        DistanceVector {
+            distance: distance,
+            direction: direction,
+            angle: scan::angle(direction),
+        }
+    }
 }
 
 impl Drop for Controller {
     fn drop(&mut self) {
-        self.front_motors.unexport();
-        self.rear_motors.unexport();
-        self.front_distance_sensor.unexport();
-        self.rear_distance_sensor.unexport();
-        self.left_distance_sensor.unexport();
-        self.right_distance_sensor.unexport();
+        self.drivetrain.unexport();
+        self.sampler.unexport();
+        if let Some(ref mut scanner) = self.scanner {
+            scanner.unexport();
+        }
+        for observer in &self.observers {
+            observer.finish();
+        }
     }
 }
 
 impl fmt::Debug for Controller {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Controller")
-            .field("front_motors", &self.front_motors)
-            .field("rear_motors", &self.rear_motors)
+            .field("drivetrain", &self.drivetrain)
             .field("front_distance_sensor", &self.front_distance_sensor)
             .field("rear_distance_sensor", &self.rear_distance_sensor)
             .field("left_distance_sensor", &self.left_distance_sensor)
             .field("right_distance_sensor", &self.right_distance_sensor)
+            .field("sampler", &self.sampler)
+            .field("scanner", &self.scanner)
             .finish()
     }
 }
 
 impl Controller {
+    /// Runs commands from the queue until it's empty, starting with the path through
+    /// the map or the builder's commands. Commands submitted while it runs are run too,
+    /// and if the queue was kept open this waits for more until it's closed.
+    ///
+    /// A command that fails cancels the rest. While the queue is paused, the motors
+    /// are stopped until it's resumed or stepped. Once every command has run, the
+    /// state file is marked finished so resuming doesn't repeat the end of the run.
     pub fn run(&mut self) -> Result<(), error::Error> {
-        for command in self.commands.clone() {
-            println!("Running command {:?}", command);
-            match command {
-                Command::Move(d) => self.travel(d).wait()?,
-                Command::Stop => {
-                    self.front_motors.disable(motor::Device::A)?;
-                    self.front_motors.disable(motor::Device::B)?;
-                    self.rear_motors.disable(motor::Device::A)?;
-                    self.rear_motors.disable(motor::Device::B)?;
+        let mut last = None;
+        loop {
+            let drivetrain = self.drivetrain.clone();
+            let stop = self.stop;
+            let job = self.queue.take(|next| {
+                match next.node() {
+                    Some(node) => {
+                        info!("Paused before command #{} {:?} from node {}",
+                              next.index(),
+                              next.command(),
+                              node)
+                    }
+                    None => info!("Paused before command #{} {:?}", next.index(), next.command()),
+                }
+                if let Err(err) = drivetrain.stop(stop) {
+                    warn!("Couldn't stop the motors while paused: {}", err);
+                }
+            });
+            let job = match job {
+                Some(job) => job,
+                None => break,
+            };
+            info!("Running command {:?}", job.command);
+            self.observe(trace::Event::Command {
+                command: format!("{:?}", job.command),
+                index: Some(job.index),
+                node: job.node,
+            });
+            let (index, node) = (job.index, job.node);
+            let result = self.perform(job.command);
+            job.finish(&result);
+            if result.is_ok() {
+                self.save_state(index, node, false);
+                last = Some((index, node));
+            }
+            if result.is_err() {
+                let cancelled = self.queue.clear();
+                if cancelled > 0 {
+                    warn!("Cancelled {} commands after a failure", cancelled);
+                }
+            }
+            result?;
+        }
+        // A queue cleared from the keyboard still has commands left to resume
+        let drained = self.queue.last().map_or(false, |handle| handle.status() == Status::Done);
+        if let (Some((index, node)), true) = (last, drained) {
+            self.save_state(index, node, true);
+        }
+        Ok(())
+    }
+
+    // The robot is now wherever the next command starts from
+    fn save_state(&self, index: usize, node: Option<u32>, finished: bool) {
+        if let Some(ref path) = self.state_file {
+            let state = RunState {
+                node: self.queue.upcoming().and_then(|next| next.node()).or(node),
+                heading: self.heading,
+                index: index + 1,
+                finished: finished,
+            };
+            if let Err(err) = state.save(path) {
+                warn!("Couldn't save the run's state to {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    /// The commands waiting for `run`, to submit more from any thread or pause them.
+    pub fn queue(&self) -> Queue {
+        self.queue.clone()
+    }
+
+    pub fn execute(&mut self, command: Command) -> Result<(), error::Error> {
+        self.observe(trace::Event::Command {
+            command: format!("{:?}", command),
+            index: None,
+            node: None,
+        });
+        self.perform(command)
+    }
+
+    fn observe(&self, event: trace::Event) {
+        for observer in &self.observers {
+            observer.observe(&event);
+        }
+    }
+
+    fn perform(&mut self, command: Command) -> Result<(), error::Error> {
+        match command {
+            Command::Move(d) => {
+                self.travel(d).wait()?;
+                if !self.drivetrain.strafes() {
+                    self.heading = map::turn(self.heading, d);
                 }
             }
+            Command::MoveFor(d, duration) => self.travel_for(d, duration).wait()?,
+            Command::MoveUntilWall(d) => self.travel_until_wall(d).wait()?,
+            Command::Wait(duration) => thread::sleep(duration),
+            Command::Distance(d) => {
+                let vector = self.distance(d).wait()?;
+                info!("{:?} distance {}", vector.direction, vector.distance);
+            }
+            Command::SetThresholds(overrides) => self.set_thresholds(overrides),
+            Command::Stop => self.stop()?,
         }
         Ok(())
     }
 
+    /// Replaces the base thresholds with any in `overrides` until the next call.
+    ///
+    /// Passing empty overrides restores the base thresholds.
+    pub fn set_thresholds(&mut self, overrides: Overrides) {
+        self.thresholds = overrides.apply(self.base_thresholds);
+    }
+
+    pub fn thresholds(&self) -> Thresholds {
+        self.thresholds
+    }
+
+    /// Moves to the next node in the given direction.
+    ///
+    /// Left and right turn 90 degrees in place, unless the drivetrain strafes, in which
+    /// case they slide sideways like forward and backward moves.
     pub fn travel(&mut self, direction: Direction) -> cpupool::CpuFuture<(), error::Error> {
-        let front_motors = self.front_motors.clone();
-        let rear_motors = self.rear_motors.clone();
+        if self.drivetrain.counts_steps() {
+            return self.move_exactly(direction);
+        }
+        let strafes = self.drivetrain.strafes();
+        let (sensor, sides) = match direction {
+            Direction::Forward => (self.front_distance_sensor.clone(), [Direction::Left, Direction::Right]),
+            Direction::Backward => (self.rear_distance_sensor.clone(), [Direction::Left, Direction::Right]),
+            Direction::Left if strafes => {
+                (self.left_distance_sensor.clone(), [Direction::Forward, Direction::Backward])
+            }
+            Direction::Right if strafes => {
+                (self.right_distance_sensor.clone(), [Direction::Forward, Direction::Backward])
+            }
+            Direction::Left | Direction::Right => return self.turn(direction),
+        };
+        let drivetrain = self.drivetrain.clone();
+        let stop = self.stop;
+        let speed = self.speeds.travel;
+        let first_side = self.sensor(sides[0]);
+        let second_side = self.sensor(sides[1]);
+        let thresholds = self.thresholds;
+        let follower = self.follower(direction, speed);
+        let pool = self.pool.clone();
+        let watch = Watch::new(&self.interrupts);
+        self.pool.spawn_fn(move || {
+            drivetrain.start(direction, speed)?;
+
+            // We need to keep moving until one of the following is true:
+            // - The sensor we're moving towards hits its threshold
+            // - Either sensor to the side of the move exceeds its threshold
+            // The first case means we've hit a wall and thus a new node, and the second case
+            // means that a wall has opened up and represents a new node.
+            let following = follower.map(|f| f.spawn(&pool));
+            let primary = reach_threshold(&pool,
+                                          direction,
+                                          ThresholdLimit::LessThan,
+                                          thresholds,
+                                          sensor,
+                                          &watch);
+            let first = reach_threshold(&pool,
+                                        sides[0],
+                                        ThresholdLimit::GreaterThan,
+                                        thresholds,
+                                        first_side,
+                                        &watch);
+            let second = reach_threshold(&pool,
+                                         sides[1],
+                                         ThresholdLimit::GreaterThan,
+                                         thresholds,
+                                         second_side,
+                                         &watch);
+            // Select2 will wait for either one of the futures in select to finish, or for
+            // second to finish.
+            let thresh_dir = primary.select(first)
+                .select2(second)
+                .map(|either| match either {
+                    // Type A is (Direction, SelectNext)
+                    // Type B is Direction
+                    future::Either::A((d, _)) => d.0,
+                    future::Either::B((d, _)) => d,
+                })
+                .map_err(|either| match either {
+                    // type of A is (error::Error, SelectNext)
+                    // type of B is error::Error
+                    future::Either::A((e, _)) => e.0,
+                    future::Either::B((e, _)) => e,
+                })
+                .wait();
+            watch.finish();
+            // The follower steers the motors, so it has to be done before they stop,
+            // whether or not the move got where it was going
+            let followed = following.map_or(Ok(()), |following| following.finish());
+            drivetrain.stop(stop)?;
+            let thresh_dir = thresh_dir?;
+            followed?;
+            info!("Threshold reached in {:?}", thresh_dir);
+
+            Ok(())
+        })
+    }
+
+    // The drivetrain knows how far the next node is, so the sensors aren't needed.
+    // Interrupting stops the motors, which ends the move early without an error
+    // from the drivetrain, so it has to be checked for here.
+    fn move_exactly(&mut self, direction: Direction) -> cpupool::CpuFuture<(), error::Error> {
+        let drivetrain = self.drivetrain.clone();
+        let stop = self.stop;
+        let speed = self.speeds.get(direction);
+        let watch = Watch::new(&self.interrupts);
+        self.pool.spawn_fn(move || {
+            let moved = drivetrain.move_exactly(direction, speed);
+            drivetrain.stop(stop)?;
+            moved?;
+            if watch.interrupted() {
+                return Err(error::Error::Interrupted);
+            }
+            Ok(())
+        })
+    }
+
+    /// Turns 90 degrees in place, whether or not the drivetrain can strafe.
+    pub fn turn(&mut self, direction: Direction) -> cpupool::CpuFuture<(), error::Error> {
+        if self.drivetrain.counts_steps() {
+            return self.move_exactly(direction);
+        }
+        let drivetrain = self.drivetrain.clone();
+        let stop = self.stop;
+        let speed = self.speeds.turn;
         // We use a sensor to indicate whether or not to stop moving
         // A sensor changes when it goes from "clear" (greater than threshold)
         // to "blocked" (less than threshold) or vice-versa.
-        //
-        // When turning, we care about the opposite direction from the turn direction,
-        // because that sensor will change from "clear" to "blocked".
-        let sensor = match direction {
-            Direction::Forward => self.front_distance_sensor.clone(),
-            Direction::Backward => self.rear_distance_sensor.clone(),
-            Direction::Left => self.right_distance_sensor.clone(),
-            Direction::Right => self.left_distance_sensor.clone(),
-        };
         let left_sensor = self.left_distance_sensor.clone();
         let right_sensor = self.right_distance_sensor.clone();
+        let thresholds = self.thresholds;
         let pool = self.pool.clone();
+        let watch = Watch::new(&self.interrupts);
         self.pool.spawn_fn(move || {
-            match direction {
-                Direction::Forward => {
-                    front_motors.set_direction(Direction::Forward)?;
-                    rear_motors.set_direction(Direction::Forward)?;
-                }
-                Direction::Backward => {
-                    front_motors.set_direction(Direction::Backward)?;
-                    rear_motors.set_direction(Direction::Backward)?;
-                }
-                Direction::Left => {
-                    front_motors.set_direction(Direction::Left)?;
-                    rear_motors.set_direction(Direction::Left)?;
-                }
-                Direction::Right => {
-                    front_motors.set_direction(Direction::Right)?;
-                    rear_motors.set_direction(Direction::Right)?;
-                }
-            }
-            front_motors.enable(motor::Device::A)?;
-            front_motors.enable(motor::Device::B)?;
-            rear_motors.enable(motor::Device::A)?;
-            rear_motors.enable(motor::Device::B)?;
-
-            match direction {
-                // Simply move until we hit the threshold
-                Direction::Left | Direction::Right => {
-                    let left = reach_threshold(&pool,
-                                               Direction::Left,
-                                               ThresholdLimit::Either,
-                                               left_sensor);
-                    let right = reach_threshold(&pool,
-                                                Direction::Right,
-                                                ThresholdLimit::Either,
-                                                right_sensor);
-                    left.select(right)
-                        .map_err(|e| e.0)
-                        .wait()?;
-                }
-                // This one is a bit more complex: We need to keep moving until one of the
-                // following is true:
-                // - The front or back sensor hits its threshold
-                // - The left or right sensor exceed their threshold
-                // The first case means we've hit a wall and thus a new node, and the second case
-                // means that a wall has opened up and represents a new node.
-                d @ Direction::Forward |
-                d @ Direction::Backward => {
-                    let primary = reach_threshold(&pool, d, ThresholdLimit::LessThan, sensor);
-                    let left = reach_threshold(&pool,
-                                               Direction::Left,
-                                               ThresholdLimit::GreaterThan,
-                                               left_sensor);
-                    let right = reach_threshold(&pool,
-                                                Direction::Right,
-                                                ThresholdLimit::GreaterThan,
-                                                right_sensor);
-                    // Select2 will wait for either one of the futures in select to finish, or for
-                    // right to finish.
-                    let thresh_dir = primary.select(left)
-                        .select2(right)
-                        .map(|either| match either {
-                            // Type A is (Direction, SelectNext)
-                            // Type B is Direction
-                            future::Either::A((d, _)) => d.0,
-                            future::Either::B((d, _)) => d,
-                        })
-                        .map_err(|either| match either {
-                            // type of A is (error::Error, SelectNext)
-                            // type of B is error::Error
-                            future::Either::A((e, _)) => e.0,
-                            future::Either::B((e, _)) => e,
-                        })
-                        .wait()?;
-                    println!("Threshold reached in {:?}", thresh_dir);
-                }
-            }
+            drivetrain.rotate(direction, speed)?;
 
-            front_motors.disable(motor::Device::A)?;
-            front_motors.disable(motor::Device::B)?;
-            rear_motors.disable(motor::Device::A)?;
-            rear_motors.disable(motor::Device::B)?;
+            // Simply move until we hit the threshold
+            let left = reach_threshold(&pool,
+                                       Direction::Left,
+                                       ThresholdLimit::Either,
+                                       thresholds,
+                                       left_sensor,
+                                       &watch);
+            let right = reach_threshold(&pool,
+                                        Direction::Right,
+                                        ThresholdLimit::Either,
+                                        thresholds,
+                                        right_sensor,
+                                        &watch);
+            let reached = left.select(right)
+                .map(|d| d.0)
+                .map_err(|e| e.0)
+                .wait();
+            watch.finish();
+            drivetrain.stop(stop)?;
+            reached?;
 
             Ok(())
         })
@@ -193,112 +428,226 @@ impl Controller {
                       direction: Direction,
                       duration: time::Duration)
                       -> cpupool::CpuFuture<(), error::Error> {
-        let front_motors = self.front_motors.clone();
-        let rear_motors = self.rear_motors.clone();
+        let drivetrain = self.drivetrain.clone();
+        let stop = self.stop;
+        let speed = self.speed(direction);
+        let watch = Watch::new(&self.interrupts);
         self.pool.spawn_fn(move || {
-            match direction {
-                Direction::Forward => {
-                    front_motors.set_direction(Direction::Forward)?;
-                    rear_motors.set_direction(Direction::Forward)?;
-                }
-                Direction::Backward => {
-                    front_motors.set_direction(Direction::Backward)?;
-                    rear_motors.set_direction(Direction::Backward)?;
-                }
-                Direction::Left => {
-                    front_motors.set_direction(Direction::Left)?;
-                    rear_motors.set_direction(Direction::Left)?;
-                }
-                Direction::Right => {
-                    front_motors.set_direction(Direction::Right)?;
-                    rear_motors.set_direction(Direction::Right)?;
-                }
-            }
-            front_motors.enable(motor::Device::A)?;
-            front_motors.enable(motor::Device::B)?;
-            rear_motors.enable(motor::Device::A)?;
-            rear_motors.enable(motor::Device::B)?;
+            drivetrain.start(direction, speed)?;
 
             // Let the motors move
             thread::sleep(duration);
 
-            front_motors.disable(motor::Device::A)?;
-            front_motors.disable(motor::Device::B)?;
-            rear_motors.disable(motor::Device::A)?;
-            rear_motors.disable(motor::Device::B)?;
+            drivetrain.stop(stop)?;
+            if watch.interrupted() {
+                return Err(error::Error::Interrupted);
+            }
             Ok(())
         })
     }
 
-    pub fn distance(&mut self,
-                    direction: Direction)
-                    -> cpupool::CpuFuture<DistanceVector, error::Error> {
-        let sensor = match direction {
+    /// Turns in place for the given duration, whether or not the drivetrain can strafe.
+    pub fn turn_for(&mut self,
+                    direction: Direction,
+                    duration: time::Duration)
+                    -> cpupool::CpuFuture<(), error::Error> {
+        let drivetrain = self.drivetrain.clone();
+        let stop = self.stop;
+        let speed = self.speeds.turn;
+        let watch = Watch::new(&self.interrupts);
+        self.pool.spawn_fn(move || {
+            drivetrain.rotate(direction, speed)?;
+            thread::sleep(duration);
+            drivetrain.stop(stop)?;
+            if watch.interrupted() {
+                return Err(error::Error::Interrupted);
+            }
+            Ok(())
+        })
+    }
+
+    /// Moves in the given direction until the sensor facing that way is blocked.
+    ///
+    /// Unlike `travel`, openings to either side are ignored, so this can be used to
+    /// drive past junctions to the end of a corridor.
+    pub fn travel_until_wall(&mut self,
+                             direction: Direction)
+                             -> cpupool::CpuFuture<(), error::Error> {
+        let drivetrain = self.drivetrain.clone();
+        let stop = self.stop;
+        let speed = self.speed(direction);
+        let sensor = self.sensor(direction);
+        let thresholds = self.thresholds;
+        let follower = self.follower(direction, speed);
+        let pool = self.pool.clone();
+        let watch = Watch::new(&self.interrupts);
+        self.pool.spawn_fn(move || {
+            drivetrain.start(direction, speed)?;
+            let following = follower.map(|f| f.spawn(&pool));
+            let reached = reach_threshold(&pool,
+                                          direction,
+                                          ThresholdLimit::LessThan,
+                                          thresholds,
+                                          sensor,
+                                          &watch)
+                .wait();
+            let followed = following.map_or(Ok(()), |following| following.finish());
+            drivetrain.stop(stop)?;
+            reached?;
+            followed
+        })
+    }
+
+    // Strafing runs at travel speed, since it moves between nodes
+    fn speed(&self, direction: Direction) -> f32 {
+        if self.drivetrain.strafes() {
+            self.speeds.travel
+        } else {
+            self.speeds.get(direction)
+        }
+    }
+
+    fn sensor(&self, direction: Direction) -> distance::Sensor {
+        match direction {
             Direction::Forward => self.front_distance_sensor.clone(),
             Direction::Backward => self.rear_distance_sensor.clone(),
             Direction::Left => self.left_distance_sensor.clone(),
             Direction::Right => self.right_distance_sensor.clone(),
-        };
+        }
+    }
+
+    // Wall following only steers forward moves
+    fn follower(&self, direction: Direction, speed: f32) -> Option<follow::Follower> {
+        match direction {
+            Direction::Forward if self.wall_follow.enabled => {
+                Some(follow::Follower {
+                    drivetrain: self.drivetrain.clone(),
+                    left_sensor: self.left_distance_sensor.clone(),
+                    right_sensor: self.right_distance_sensor.clone(),
+                    thresholds: self.thresholds,
+                    settings: self.wall_follow,
+                    speed: speed,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Drives the wheels on each side at a signed speed between -1 (full reverse) and
+    /// 1 (full forward), until told otherwise.
+    ///
+    /// Unlike the other moves this returns as soon as the motors are set, so the robot
+    /// can arc or correct its course while doing something else.
+    pub fn drive(&mut self, left: f32, right: f32) -> Result<(), error::Error> {
+        self.drivetrain.drive(left, right)
+    }
+
+    /// Starts moving in the given direction at the configured speed, until stopped.
+    pub fn start(&mut self, direction: Direction) -> Result<(), error::Error> {
+        let speed = self.speed(direction);
+        self.drivetrain.start(direction, speed)
+    }
+
+    /// Changes the speeds used by later moves.
+    pub fn set_speeds(&mut self, speeds: motor::Speeds) {
+        self.speeds = speeds;
+    }
+
+    /// Stops the motors the configured way.
+    pub fn stop(&mut self) -> Result<(), error::Error> {
+        self.drivetrain.stop(self.stop)
+    }
+
+    /// Stops the motors, and gives up on any move that's waiting for a sensor to reach
+    /// its threshold, which then fails with `Error::Interrupted`.
+    pub fn interrupt(&mut self) -> Result<(), error::Error> {
+        self.interrupts.fetch_add(1, Ordering::SeqCst);
+        self.stop()
+    }
+
+    /// The wheels the drivetrain has a motor channel for.
+    pub fn wheels(&self) -> Vec<Role> {
+        self.drivetrain.wheels()
+    }
+
+    /// Drives a single wheel forward for the given duration, to check how it's wired.
+    pub fn pulse_wheel(&mut self,
+                       wheel: Role,
+                       duration: time::Duration)
+                       -> cpupool::CpuFuture<(), error::Error> {
+        let drivetrain = self.drivetrain.clone();
+        self.pool.spawn_fn(move || {
+            drivetrain.drive_wheel(wheel, 1.0)?;
+            thread::sleep(duration);
+            drivetrain.drive_wheel(wheel, 0.0)
+        })
+    }
+
+    /// A subscription to the latest sensor readings, which are read continuously in
+    /// the background.
+    pub fn readings(&self) -> distance::Readings {
+        self.sampler.readings()
+    }
+
+    pub fn distance(&mut self,
+                    direction: Direction)
+                    -> cpupool::CpuFuture<DistanceVector, error::Error> {
+        let readings = self.readings();
         self.pool.spawn_fn(move || {
-            let distance = sensor.value()?;
-            Ok(DistanceVector {
-                distance: distance,
-                direction: direction,
-            })
+            // The next reading, so it's never from before whatever the robot just did
+            let reading = readings.next(direction)?;
+            Ok(DistanceVector::fixed(direction, reading.distance))
         })
     }
 }
 
-// Loop until the sensor value goes below its threshold
+// Loop until the sensor value goes below its threshold, or until `watch` says some
+// other sensor got there first. An interrupted move fails.
 fn reach_threshold(pool: &cpupool::CpuPool,
                    direction: Direction,
                    limit: ThresholdLimit,
-                   sensor: distance::Sensor)
+                   thresholds: Thresholds,
+                   sensor: distance::Sensor,
+                   watch: &Watch)
                    -> cpupool::CpuFuture<Direction, error::Error> {
 
-    let threshold = match direction {
-        Direction::Forward | Direction::Backward => FB_THRESHOLD,
-        Direction::Left | Direction::Right => LR_THRESHOLD,
-    };
+    let threshold = thresholds.get(direction);
+    let watch = watch.clone();
     pool.spawn_fn(move || {
         // A limit less than the threshold means that we want to avoid going below
         // the threshold. A limit greater than the threshold means we want to avoid
-        // going above the threshold.
+        // going above the threshold. Either way, the reading has to get all the way
+        // through the hysteresis band.
         match limit {
             ThresholdLimit::LessThan => {
-                loop {
+                while watch.watching() {
                     let value = sensor.value()?;
-                    println!("{:?} Distance {}", direction, value);
-                    if value <= threshold {
+                    if value <= threshold.enter() {
                         break;
                     }
                 }
             }
             ThresholdLimit::GreaterThan => {
-                loop {
+                while watch.watching() {
                     let value = sensor.value()?;
-                    println!("{:?} Distance {}", direction, value);
-                    if value >= threshold {
+                    if value >= threshold.leave() {
                         break;
                     }
                 }
             }
             ThresholdLimit::Either => {
                 let value = sensor.value()?;
-                println!("{:?} Distance {}", direction, value);
-                if value > threshold {
-                    loop {
+                if value > threshold.distance {
+                    while watch.watching() {
                         let v = sensor.value()?;
-                        println!("{:?} Distance {}", direction, v);
-                        if v <= threshold {
+                        if v <= threshold.enter() {
                             break;
                         }
                     }
-                } else if value < threshold {
-                    loop {
+                } else if value < threshold.distance {
+                    while watch.watching() {
                         let v = sensor.value()?;
-                        println!("{:?} Distance {}", direction, v);
-                        if v >= threshold {
+                        if v >= threshold.leave() {
                             break;
                         }
                     }
@@ -307,6 +656,12 @@ fn reach_threshold(pool: &cpupool::CpuPool,
                 }
             }
         }
+        if watch.interrupted() {
+            return Err(error::Error::Interrupted);
+        }
+        if watch.watching() {
+            debug!("{:?} sensor crossed {}cm", direction, threshold.distance);
+        }
         Ok(direction)
     })
 }
@@ -0,0 +1,462 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+
+use Command;
+
+// How many submitted commands can still be looked up by their index
+const HISTORY: usize = 1000;
+
+/// How far along a submitted command is.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Status {
+    /// Waiting for the commands ahead of it, or for the queue to be resumed
+    Pending,
+    Running,
+    Done,
+    /// The command's error, which also clears the rest of the queue
+    Failed(String),
+    /// Cleared from the queue before it ran
+    Cancelled,
+}
+
+impl Status {
+    /// Whether the command is done with, one way or another.
+    pub fn finished(&self) -> bool {
+        match *self {
+            Status::Pending | Status::Running => false,
+            Status::Done | Status::Failed(_) | Status::Cancelled => true,
+        }
+    }
+}
+
+/// Commands waiting for a `Controller` to run them, in the order they were submitted.
+///
+/// Commands can be submitted from any thread while the controller runs the ones
+/// before them. Once the queue runs out the controller stops, unless the queue is
+/// kept open, in which case it waits for more until the queue is closed. Clones share
+/// the same queue.
+#[derive(Clone, Debug)]
+pub struct Queue {
+    shared: Arc<(Mutex<State>, Condvar)>,
+}
+
+#[derive(Debug)]
+struct State {
+    jobs: VecDeque<Handle>,
+    next_index: usize,
+    paused: bool,
+    // Commands that can still be taken while paused
+    steps: usize,
+    // The command taken most recently
+    current: Option<Handle>,
+    // Whether taking from an empty queue waits for more to be submitted
+    open: bool,
+    // The most recently submitted commands, oldest first
+    history: VecDeque<Handle>,
+}
+
+/// A way to follow a submitted command. Clones follow the same command.
+#[derive(Clone, Debug)]
+pub struct Handle {
+    index: usize,
+    command: Command,
+    node: Option<u32>,
+    status: Arc<(Mutex<Status>, Condvar)>,
+}
+
+/// A snapshot of a command in the queue, for reporting where a run is up to.
+#[derive(Debug, Clone, Serialize)]
+pub struct Position {
+    pub index: usize,
+    pub command: String,
+    pub node: Option<u32>,
+    pub status: Status,
+}
+
+/// A command taken off the queue to run, which is reported on with `finish`.
+#[derive(Debug)]
+pub struct Job {
+    pub command: Command,
+    /// How many commands were submitted before this one
+    pub index: usize,
+    /// The map node the robot should be at when it starts, if it came from a map
+    pub node: Option<u32>,
+    handle: Handle,
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue::starting_at(0)
+    }
+
+    /// A queue that counts `index` commands before its first one, to carry on from
+    /// an earlier run.
+    pub fn starting_at(index: usize) -> Queue {
+        let state = State {
+            jobs: VecDeque::new(),
+            next_index: index,
+            paused: false,
+            steps: 0,
+            current: None,
+            open: false,
+            history: VecDeque::new(),
+        };
+        Queue { shared: Arc::new((Mutex::new(state), Condvar::new())) }
+    }
+
+    /// Adds a command to the end of the queue.
+    pub fn submit(&self, command: Command) -> Handle {
+        self.submit_at(command, None)
+    }
+
+    /// Adds a command that starts from a map node to the end of the queue.
+    pub fn submit_at(&self, command: Command, node: Option<u32>) -> Handle {
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        let handle = Handle {
+            index: state.next_index,
+            command: command,
+            node: node,
+            status: Arc::new((Mutex::new(Status::Pending), Condvar::new())),
+        };
+        state.next_index += 1;
+        state.jobs.push_back(handle.clone());
+        if state.history.len() == HISTORY {
+            state.history.pop_front();
+        }
+        state.history.push_back(handle.clone());
+        cvar.notify_all();
+        handle
+    }
+
+    /// Keeps the queue open once it runs out, so whatever takes from it waits for
+    /// more commands to be submitted instead of finishing, until `close` is called.
+    pub fn keep_open(&self) {
+        self.set_open(true);
+    }
+
+    /// Lets the queue finish once the commands already in it have run.
+    pub fn close(&self) {
+        self.set_open(false);
+    }
+
+    pub fn is_open(&self) -> bool {
+        let &(ref state, _) = &*self.shared;
+        state.lock().unwrap().open
+    }
+
+    /// Holds the queue once the running command finishes, until `resume` is called.
+    pub fn pause(&self) {
+        self.set_paused(true);
+    }
+
+    pub fn resume(&self) {
+        self.set_paused(false);
+    }
+
+    /// Lets one more command run and then holds the queue, whether or not it was
+    /// already paused.
+    pub fn step(&self) {
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.paused = true;
+        state.steps += 1;
+        cvar.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        let &(ref state, _) = &*self.shared;
+        state.lock().unwrap().paused
+    }
+
+    /// How many commands are waiting to run.
+    pub fn len(&self) -> usize {
+        let &(ref state, _) = &*self.shared;
+        state.lock().unwrap().jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Cancels every command waiting to run, returning how many there were. A running
+    /// command carries on.
+    pub fn clear(&self) -> usize {
+        let &(ref state, ref cvar) = &*self.shared;
+        let jobs = state.lock().unwrap().jobs.drain(..).collect::<Vec<_>>();
+        for handle in &jobs {
+            handle.set(Status::Cancelled);
+        }
+        cvar.notify_all();
+        jobs.len()
+    }
+
+    /// The command running, or the last one to run if the queue is paused or empty.
+    pub fn current(&self) -> Option<Position> {
+        let &(ref state, _) = &*self.shared;
+        state.lock().unwrap().current.as_ref().map(Handle::position)
+    }
+
+    /// The next command waiting to run.
+    pub fn upcoming(&self) -> Option<Handle> {
+        let &(ref state, _) = &*self.shared;
+        state.lock().unwrap().jobs.front().cloned()
+    }
+
+    /// The next command waiting to run, waiting for one to be submitted if the queue
+    /// is empty but open.
+    pub fn wait_upcoming(&self) -> Option<Handle> {
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        while state.jobs.is_empty() && state.open {
+            state = cvar.wait(state).unwrap();
+        }
+        state.jobs.front().cloned()
+    }
+
+    /// A command submitted to this queue, by its index, unless it was submitted too
+    /// long ago to remember.
+    pub fn find(&self, index: usize) -> Option<Handle> {
+        let &(ref state, _) = &*self.shared;
+        let state = state.lock().unwrap();
+        state.history.iter().find(|handle| handle.index == index).cloned()
+    }
+
+    /// The last command submitted to this queue.
+    pub fn last(&self) -> Option<Handle> {
+        let &(ref state, _) = &*self.shared;
+        state.lock().unwrap().history.back().cloned()
+    }
+
+    /// Takes the next command to run, waiting for the queue to be resumed or stepped
+    /// if it's paused. `pausing` is called with the command it's waiting to take
+    /// before it first waits, without the queue being usable. Once the queue is empty
+    /// there's nothing to take, paused or not, unless it's open, in which case this
+    /// waits for a command to be submitted or for the queue to be closed.
+    pub fn take<F: FnOnce(&Handle)>(&self, pausing: F) -> Option<Job> {
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        let mut pausing = Some(pausing);
+        loop {
+            let held = state.paused && state.steps == 0 && !state.jobs.is_empty();
+            let waiting = state.open && state.jobs.is_empty();
+            if !(held || waiting) {
+                break;
+            }
+            if let (true, Some(next)) = (held, state.jobs.front()) {
+                if let Some(pausing) = pausing.take() {
+                    pausing(next);
+                }
+            }
+            state = cvar.wait(state).unwrap();
+        }
+        let handle = state.jobs.pop_front()?;
+        if state.paused {
+            state.steps -= 1;
+        }
+        handle.set(Status::Running);
+        state.current = Some(handle.clone());
+        Some(Job {
+            command: handle.command,
+            index: handle.index,
+            node: handle.node,
+            handle: handle,
+        })
+    }
+
+    fn set_open(&self, open: bool) {
+        let &(ref state, ref cvar) = &*self.shared;
+        state.lock().unwrap().open = open;
+        cvar.notify_all();
+    }
+
+    fn set_paused(&self, paused: bool) {
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.paused = paused;
+        state.steps = 0;
+        cvar.notify_all();
+    }
+}
+
+impl Default for Queue {
+    fn default() -> Queue {
+        Queue::new()
+    }
+}
+
+impl Handle {
+    /// How many commands were submitted before this one.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    /// The map node the robot should be at when the command starts, if it came from
+    /// a map.
+    pub fn node(&self) -> Option<u32> {
+        self.node
+    }
+
+    pub fn position(&self) -> Position {
+        Position {
+            index: self.index,
+            command: format!("{:?}", self.command),
+            node: self.node,
+            status: self.status(),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let &(ref status, _) = &*self.status;
+        status.lock().unwrap().clone()
+    }
+
+    /// Waits for the command to be done with, and returns how it went.
+    pub fn wait(&self) -> Status {
+        let &(ref status, ref cvar) = &*self.status;
+        let mut status = status.lock().unwrap();
+        while !status.finished() {
+            status = cvar.wait(status).unwrap();
+        }
+        status.clone()
+    }
+
+    fn set(&self, new: Status) {
+        let &(ref status, ref cvar) = &*self.status;
+        *status.lock().unwrap() = new;
+        cvar.notify_all();
+    }
+}
+
+impl Job {
+    /// Reports how running the command went to anyone following it.
+    pub fn finish<E: ToString>(self, result: &Result<(), E>) {
+        self.handle.set(match *result {
+            Ok(()) => Status::Done,
+            Err(ref err) => Status::Failed(err.to_string()),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time;
+
+    use {Command, Direction};
+    use super::{Queue, Status};
+
+    fn finish(queue: &Queue) -> usize {
+        let job = queue.take(|_| {}).unwrap();
+        let index = job.index;
+        job.finish::<String>(&Ok(()));
+        index
+    }
+
+    #[test]
+    fn runs_in_order_and_reports_status() {
+        let queue = Queue::starting_at(3);
+        let first = queue.submit(Command::Stop);
+        let second = queue.submit_at(Command::Move(Direction::Left), Some(4));
+        assert_eq!(queue.len(), 2);
+        assert_eq!((first.index(), second.index(), second.node()), (3, 4, Some(4)));
+        assert_eq!(first.status(), Status::Pending);
+
+        let job = queue.take(|_| {}).unwrap();
+        assert_eq!((job.command, job.index), (Command::Stop, 3));
+        assert_eq!(first.status(), Status::Running);
+        assert_eq!(queue.current().unwrap().index, 3);
+        job.finish::<String>(&Ok(()));
+        assert_eq!(first.wait(), Status::Done);
+
+        let job = queue.take(|_| {}).unwrap();
+        job.finish(&Err("no wall"));
+        assert_eq!(second.status(), Status::Failed(String::from("no wall")));
+        assert!(queue.take(|_| {}).is_none());
+    }
+
+    #[test]
+    fn clear_cancels_what_is_waiting() {
+        let queue = Queue::new();
+        let running = queue.submit(Command::Stop);
+        let waiting = queue.submit(Command::Stop);
+        let job = queue.take(|_| {}).unwrap();
+        assert_eq!(queue.clear(), 1);
+        assert_eq!(waiting.wait(), Status::Cancelled);
+        assert_eq!(running.status(), Status::Running);
+        job.finish::<String>(&Ok(()));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn pausing_holds_the_next_command() {
+        let queue = Queue::new();
+        queue.submit(Command::Stop);
+        queue.pause();
+        let resumer = queue.clone();
+        let resuming = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(20));
+            resumer.resume();
+        });
+        let mut paused_at = None;
+        let job = queue.take(|next| paused_at = Some(next.index())).unwrap();
+        assert_eq!(paused_at, Some(0));
+        assert_eq!(job.index, 0);
+        assert!(!queue.is_paused());
+        resuming.join().unwrap();
+    }
+
+    #[test]
+    fn stepping_lets_one_command_through() {
+        let queue = Queue::new();
+        for _ in 0..3 {
+            queue.submit(Command::Stop);
+        }
+        queue.step();
+        assert!(queue.is_paused());
+        assert_eq!(finish(&queue), 0);
+        assert_eq!(queue.upcoming().unwrap().index(), 1);
+        assert_eq!(queue.current().unwrap().status, Status::Done);
+
+        queue.step();
+        queue.step();
+        assert_eq!(finish(&queue), 1);
+        assert_eq!(finish(&queue), 2);
+        assert!(queue.is_paused());
+        assert!(queue.upcoming().is_none());
+    }
+
+    #[test]
+    fn an_open_queue_waits_for_more() {
+        let queue = Queue::new();
+        queue.keep_open();
+        let submitter = queue.clone();
+        let submitting = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(20));
+            let handle = submitter.submit(Command::Stop);
+            handle.wait();
+            submitter.close();
+            handle
+        });
+        assert_eq!(finish(&queue), 0);
+        assert!(queue.take(|_| {}).is_none());
+        assert!(!queue.is_open());
+        assert_eq!(submitting.join().unwrap().status(), Status::Done);
+    }
+
+    #[test]
+    fn finds_submitted_commands_by_index() {
+        let queue = Queue::starting_at(10);
+        queue.submit(Command::Stop);
+        queue.submit(Command::Move(Direction::Forward));
+        finish(&queue);
+        assert_eq!(queue.find(10).unwrap().status(), Status::Done);
+        assert_eq!(queue.find(11).unwrap().command(), Command::Move(Direction::Forward));
+        assert!(queue.find(9).is_none());
+        assert!(queue.find(12).is_none());
+    }
+}
@@ -0,0 +1,585 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use futures::Future;
+use futures_cpupool as cpupool;
+use serde_json;
+
+use Direction;
+use error;
+use motor;
+use script;
+use super::{Controller, Position, Queue};
+
+/// A request from a remote operator, one JSON object per line, such as
+/// `{"Move":{"direction":"Forward","ms":null}}` or `"Stop"`.
+#[derive(Debug, Deserialize)]
+pub enum Request {
+    /// Starts moving until told otherwise, or for `ms` milliseconds
+    Move {
+        direction: Direction,
+        ms: Option<u64>,
+    },
+    /// Moves to the next node, like a map's `Move` commands
+    Travel { direction: Direction },
+    /// Stops the motors, and interrupts a `Travel` or timed `Move` that's running
+    Stop,
+    /// Sets the duty cycles for moving and turning, leaving out either to keep it
+    Speed {
+        travel: Option<f32>,
+        turn: Option<f32>,
+    },
+    /// Reads the sensor facing `direction`
+    Distance { direction: Direction },
+    /// Does nothing but keep the watchdog from stopping the robot
+    Ping,
+    /// Holds the commands being run once the current one finishes
+    Pause,
+    Resume,
+    /// Runs one more command, then holds the rest
+    Step,
+    /// Cancels the commands still waiting to run
+    Clear,
+    /// Reports the command being run, or the last one
+    Position,
+    /// Adds the commands in a script, such as `forward 2; left`, to the end of the
+    /// queue, and reports their indexes
+    Submit { script: String },
+    /// Reports how far along a submitted command is
+    Status { index: usize },
+    /// Lets a run that was kept open finish once its queue is empty
+    Close,
+    /// Stops the motors and ends `remote`, so whatever's recording the session can
+    /// write it out
+    Quit,
+}
+
+/// The reply to each request. A `Travel` or timed `Move` is answered once it finishes,
+/// so any requests sent meanwhile are answered first.
+#[derive(Debug, Default, Serialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speeds: Option<motor::Speeds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<Position>,
+    /// The commands a `Submit` added
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub submitted: Option<Vec<Position>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paused: Option<bool>,
+    /// How many commands are waiting to run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending: Option<usize>,
+}
+
+impl Controller {
+    /// Lets one operator at a time drive the robot over TCP, with line delimited JSON
+    /// `Request`s, each answered with a `Response` line.
+    ///
+    /// Any line counts as a heartbeat. If none arrives for `heartbeat` while the robot
+    /// is moving, or the operator disconnects, the motors are stopped and any move
+    /// that's running is interrupted. Operators take turns until one sends `Quit`.
+    pub fn remote(&mut self,
+                  listener: TcpListener,
+                  heartbeat: time::Duration)
+                  -> Result<(), error::Error> {
+        info!("Waiting for an operator on {}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let peer = stream.peer_addr()?;
+            info!("Operator {} connected", peer);
+            let result = self.operate(stream, heartbeat);
+            // Whatever happened, nobody is driving any more
+            self.interrupt()?;
+            match result {
+                Ok(true) => {
+                    info!("Operator {} quit", peer);
+                    break;
+                }
+                Ok(false) => info!("Operator {} disconnected", peer),
+                Err(err) => warn!("Operator {} disconnected: {}", peer, err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets operators pause, step through, clear and submit the commands `run` is
+    /// running, over TCP on another thread, with the same requests and responses as
+    /// `remote`. Only the `Pause`, `Resume`, `Step`, `Clear`, `Position`, `Submit`,
+    /// `Status` and `Close` requests are allowed.
+    pub fn serve_control(&self, listener: TcpListener) -> Result<(), error::Error> {
+        info!("Accepting control requests on {}", listener.local_addr()?);
+        let queue = self.queue();
+        thread::spawn(move || for stream in listener.incoming() {
+            let result = stream.map_err(error::Error::from)
+                .and_then(|stream| serve_queue(&queue, stream));
+            if let Err(err) = result {
+                warn!("Control connection failed: {}", err);
+            }
+        });
+        Ok(())
+    }
+
+    // Returns whether the operator quit
+    fn operate(&mut self,
+               stream: TcpStream,
+               heartbeat: time::Duration)
+               -> Result<bool, error::Error> {
+        stream.set_nodelay(true)?;
+        let mut writer = stream.try_clone()?;
+        let (events, incoming) = mpsc::channel();
+        read_lines(stream.try_clone()?, events.clone());
+        let mut session = Session::new(events);
+        let result = self.converse(&mut session, &incoming, &mut writer, heartbeat);
+        // Lets the reading thread go, if the connection is still open
+        stream.shutdown(Shutdown::Both).ok();
+        result.map(|()| session.quit)
+    }
+
+    fn converse<W: Write>(&mut self,
+                          session: &mut Session,
+                          incoming: &mpsc::Receiver<Incoming>,
+                          writer: &mut W,
+                          heartbeat: time::Duration)
+                          -> Result<(), error::Error> {
+        // Only whole lines count, so a trickle of bytes can't keep the robot going
+        let mut last_line = time::Instant::now();
+        loop {
+            let wait = match heartbeat.checked_sub(last_line.elapsed()) {
+                Some(wait) => wait,
+                None => {
+                    if session.moving || session.busy {
+                        warn!("No heartbeat for {:?}, stopping", heartbeat);
+                        self.interrupt()?;
+                        session.moving = false;
+                    }
+                    heartbeat
+                }
+            };
+            let response = match incoming.recv_timeout(wait) {
+                Ok(Incoming::Line(line)) => {
+                    last_line = time::Instant::now();
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str(&line) {
+                        Ok(request) => {
+                            debug!("Operator request {:?}", request);
+                            match self.respond(request, session) {
+                                Some(response) => response,
+                                None => continue,
+                            }
+                        }
+                        Err(err) => {
+                            Response {
+                                error: Some(format!("bad request: {}", err)),
+                                ..Response::default()
+                            }
+                        }
+                    }
+                }
+                Ok(Incoming::Moved(result)) => {
+                    session.busy = false;
+                    outcome(Response::default(), result)
+                }
+                Ok(Incoming::Closed(result)) => return result.map_err(error::Error::from),
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+            if session.quit {
+                return Ok(());
+            }
+        }
+    }
+
+    // Answers a request, or returns `None` if the answer comes once a move finishes
+    fn respond(&mut self, request: Request, session: &mut Session) -> Option<Response> {
+        let mut response = Response::default();
+        let result = match request {
+            Request::Move { .. } |
+            Request::Travel { .. } if session.busy => {
+                response.error = Some("a move is still running, wait for it or send Stop"
+                    .to_string());
+                return Some(response);
+            }
+            Request::Move { direction, ms: None } => {
+                session.moving = true;
+                self.start(direction)
+            }
+            Request::Move { direction, ms: Some(ms) } => {
+                let future = self.travel_for(direction, time::Duration::from_millis(ms));
+                return session.follow(&self.pool, future);
+            }
+            Request::Travel { direction } => {
+                let future = self.travel(direction);
+                return session.follow(&self.pool, future);
+            }
+            Request::Stop => {
+                session.moving = false;
+                self.interrupt()
+            }
+            Request::Quit => {
+                session.moving = false;
+                session.quit = true;
+                self.interrupt()
+            }
+            Request::Speed { travel, turn } => {
+                let mut speeds = self.speeds;
+                let clamp = |speed: f32| speed.max(0.0).min(1.0);
+                speeds.travel = travel.map_or(speeds.travel, clamp);
+                speeds.turn = turn.map_or(speeds.turn, clamp);
+                self.set_speeds(speeds);
+                response.speeds = Some(speeds);
+                Ok(())
+            }
+            Request::Distance { direction } => {
+                self.distance(direction).wait().map(|vector| {
+                    response.distance = Some(vector.distance);
+                })
+            }
+            Request::Ping => Ok(()),
+            // Nothing runs the queue here, so submitted commands would never run
+            Request::Submit { .. } => {
+                response.error = Some("Submit needs `run` or `script` with `--control`, \
+                                       use Travel here"
+                    .to_string());
+                return Some(response);
+            }
+            Request::Close => {
+                self.queue.close();
+                return Some(control(&self.queue, request));
+            }
+            Request::Pause | Request::Resume | Request::Step | Request::Clear |
+            Request::Position | Request::Status { .. } => {
+                return Some(control(&self.queue, request))
+            }
+        };
+        Some(outcome(response, result))
+    }
+}
+
+// What comes in while an operator is connected
+enum Incoming {
+    Line(String),
+    /// The operator disconnected, or reading from them failed
+    Closed(io::Result<()>),
+    /// A move the operator asked for finished
+    Moved(Result<(), error::Error>),
+}
+
+// An operator's connection
+struct Session {
+    // The motors were started and are running until told otherwise
+    moving: bool,
+    // A move is running, and gets answered when it finishes
+    busy: bool,
+    // The operator asked to end the session
+    quit: bool,
+    events: mpsc::Sender<Incoming>,
+}
+
+impl Session {
+    fn new(events: mpsc::Sender<Incoming>) -> Session {
+        Session {
+            moving: false,
+            busy: false,
+            quit: false,
+            events: events,
+        }
+    }
+
+    // Sends how the move went once it's finished, while the connection carries on
+    // reading requests and watching for heartbeats
+    fn follow(&mut self,
+              pool: &cpupool::CpuPool,
+              future: cpupool::CpuFuture<(), error::Error>)
+              -> Option<Response> {
+        self.moving = false;
+        self.busy = true;
+        let events = self.events.clone();
+        pool.spawn(future.then(move |result| {
+                events.send(Incoming::Moved(result)).ok();
+                Ok::<(), ()>(())
+            }))
+            .forget();
+        None
+    }
+}
+
+// Lines are read on their own thread, so the connection doesn't block on them
+fn read_lines(stream: TcpStream, events: mpsc::Sender<Incoming>) {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            match line {
+                Ok(line) => {
+                    if events.send(Incoming::Line(line)).is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    events.send(Incoming::Closed(Err(err))).ok();
+                    return;
+                }
+            }
+        }
+        events.send(Incoming::Closed(Ok(()))).ok();
+    });
+}
+
+fn outcome(mut response: Response, result: Result<(), error::Error>) -> Response {
+    match result {
+        Ok(()) => response.ok = true,
+        Err(err) => response.error = Some(err.to_string()),
+    }
+    response
+}
+
+fn serve_queue(queue: &Queue, stream: TcpStream) -> Result<(), error::Error> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut closing = false;
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => {
+                debug!("Control request {:?}", request);
+                if let Request::Close = request {
+                    closing = true;
+                }
+                control(queue, request)
+            }
+            Err(err) => {
+                Response {
+                    error: Some(format!("bad request: {}", err)),
+                    ..Response::default()
+                }
+            }
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+        // Closing can let the run finish and the program exit, so answer first
+        if closing {
+            queue.close();
+        }
+    }
+    Ok(())
+}
+
+// Requests about the queue, answered with where it's up to
+fn control(queue: &Queue, request: Request) -> Response {
+    match request {
+        Request::Pause => queue.pause(),
+        Request::Resume => queue.resume(),
+        Request::Step => queue.step(),
+        Request::Clear => {
+            let cancelled = queue.clear();
+            info!("Cancelled {} commands", cancelled);
+        }
+        Request::Position => {}
+        Request::Submit { script } => {
+            let commands = match script::parse(&script) {
+                Ok(commands) => commands,
+                Err(err) => {
+                    return Response {
+                        error: Some(format!("script: {}", err)),
+                        ..Response::default()
+                    }
+                }
+            };
+            let submitted = commands.into_iter()
+                .map(|command| queue.submit(command).position())
+                .collect::<Vec<_>>();
+            info!("Submitted {} commands", submitted.len());
+            return Response {
+                ok: true,
+                submitted: Some(submitted),
+                pending: Some(queue.len()),
+                ..Response::default()
+            };
+        }
+        Request::Status { index } => {
+            return match queue.find(index) {
+                Some(handle) => {
+                    Response {
+                        ok: true,
+                        position: Some(handle.position()),
+                        ..Response::default()
+                    }
+                }
+                None => {
+                    Response {
+                        error: Some(format!("no command #{} in the queue", index)),
+                        ..Response::default()
+                    }
+                }
+            };
+        }
+        // Whoever asked closes the queue once they've answered
+        Request::Close => {}
+        _ => {
+            return Response {
+                error: Some("only Pause, Resume, Step, Clear, Position, Submit, Status and \
+                             Close are allowed here"
+                    .to_string()),
+                ..Response::default()
+            }
+        }
+    }
+    Response {
+        ok: true,
+        position: queue.current(),
+        paused: Some(queue.is_paused()),
+        pending: Some(queue.len()),
+        ..Response::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::thread;
+    use std::time;
+
+    use Direction;
+    use controller::{Builder, Controller, Queue};
+    use trace::{Event, Observer, Trace};
+    use super::{Incoming, Request, Session, control};
+
+    // Keeps every event it's told about
+    #[derive(Debug, Default)]
+    struct Events(Mutex<Vec<Event>>);
+
+    impl Observer for Events {
+        fn observe(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    // A controller driving nothing, replaying a trace with no readings in it
+    fn controller(name: &str, events: Arc<Events>) -> Controller {
+        let path = env::temp_dir().join(format!("rsbot-{}-{}.jsonl", name, process::id()));
+        fs::File::create(&path).unwrap();
+        let trace = Trace::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        Builder::new().replay(trace).observer(events).build().unwrap()
+    }
+
+    // Feeds the lines to a session, one every `gap`, then hangs up and gives back
+    // the replies
+    fn converse(controller: &mut Controller,
+                lines: &[&str],
+                gap: time::Duration,
+                heartbeat: time::Duration)
+                -> Vec<String> {
+        let (events, incoming) = mpsc::channel();
+        let mut session = Session::new(events.clone());
+        let lines = lines.iter().map(|line| line.to_string()).collect::<Vec<_>>();
+        thread::spawn(move || {
+            for line in lines {
+                if events.send(Incoming::Line(line)).is_err() {
+                    return;
+                }
+                thread::sleep(gap);
+            }
+            events.send(Incoming::Closed(Ok(()))).ok();
+        });
+        let mut written = vec![];
+        controller.converse(&mut session, &incoming, &mut written, heartbeat).unwrap();
+        String::from_utf8(written).unwrap().lines().map(String::from).collect()
+    }
+
+    fn error(response: super::Response) -> String {
+        assert!(!response.ok);
+        response.error.unwrap()
+    }
+
+    #[test]
+    fn parses_requests() {
+        match ::serde_json::from_str(r#"{"Move":{"direction":"Left","ms":250}}"#).unwrap() {
+            Request::Move { direction: Direction::Left, ms: Some(250) } => {}
+            request => panic!("parsed {:?}", request),
+        }
+        match ::serde_json::from_str(r#""Stop""#).unwrap() {
+            Request::Stop => {}
+            request => panic!("parsed {:?}", request),
+        }
+        match ::serde_json::from_str(r#"{"Speed":{"travel":0.5}}"#).unwrap() {
+            Request::Speed { travel: Some(travel), turn: None } => assert_eq!(travel, 0.5),
+            request => panic!("parsed {:?}", request),
+        }
+        assert!(::serde_json::from_str::<Request>(r#""Jump""#).is_err());
+        assert!(::serde_json::from_str::<Request>(r#"{"Move":{"direction":"Up"}}"#).is_err());
+    }
+
+    #[test]
+    fn controls_the_queue() {
+        let queue = Queue::new();
+        let response = control(&queue, Request::Pause);
+        assert_eq!((response.ok, response.paused), (true, Some(true)));
+
+        let response = control(&queue, Request::Submit { script: String::from("forward 2") });
+        assert_eq!(response.submitted.unwrap().len(), 2);
+        assert_eq!(response.pending, Some(2));
+        assert!(error(control(&queue, Request::Submit { script: String::from("jump") }))
+            .starts_with("script: "));
+        assert!(control(&queue, Request::Status { index: 1 }).ok);
+        assert_eq!(error(control(&queue, Request::Status { index: 7 })),
+                   "no command #7 in the queue");
+
+        assert_eq!(control(&queue, Request::Clear).pending, Some(0));
+        assert!(error(control(&queue, Request::Ping)).starts_with("only Pause, Resume"));
+    }
+
+    #[test]
+    fn stops_when_the_heartbeat_lapses() {
+        let events = Arc::new(Events::default());
+        let mut controller = controller("remote-heartbeat", events.clone());
+        let replies = converse(&mut controller,
+                               &[r#"{"Move":{"direction":"Forward","ms":null}}"#],
+                               time::Duration::from_millis(200),
+                               time::Duration::from_millis(50));
+        assert_eq!(replies, vec![r#"{"ok":true}"#]);
+        let events = events.0.lock().unwrap();
+        let moves = events.iter()
+            .filter_map(|event| match *event {
+                Event::Start { direction: Direction::Forward, .. } => Some("start"),
+                Event::Stop { .. } => Some("stop"),
+                _ => None,
+            })
+            .skip_while(|&event| event != "start")
+            .collect::<Vec<_>>();
+        assert_eq!(moves, vec!["start", "stop"], "events {:?}", *events);
+    }
+
+    #[test]
+    fn answers_bad_requests_and_quits() {
+        let mut controller = controller("remote-quit", Arc::new(Events::default()));
+        let heartbeat = time::Duration::from_millis(500);
+        let (events, incoming) = mpsc::channel();
+        let mut session = Session::new(events.clone());
+        for line in &["{\"Move\":", "\"Ping\"", "\"Quit\"", "\"Ping\""] {
+            events.send(Incoming::Line(line.to_string())).unwrap();
+        }
+        let mut written = vec![];
+        controller.converse(&mut session, &incoming, &mut written, heartbeat).unwrap();
+        assert!(session.quit);
+        let written = String::from_utf8(written).unwrap();
+        let replies = written.lines().collect::<Vec<_>>();
+        assert_eq!(replies.len(), 3, "replies {:?}", replies);
+        assert!(replies[0].starts_with(r#"{"ok":false,"error":"bad request: "#));
+        assert_eq!(&replies[1..], &[r#"{"ok":true}"#, r#"{"ok":true}"#]);
+    }
+}
@@ -0,0 +1,108 @@
+use std::thread;
+
+use futures_cpupool as cpupool;
+
+use Direction;
+use distance;
+use error;
+use servo;
+use super::{Controller, DistanceVector};
+
+/// A distance sensor on a servo, which can point it anywhere in front of the robot.
+#[derive(Clone, Debug)]
+pub struct Scanner {
+    pub servo: servo::Servo,
+    pub sensor: distance::Sensor,
+}
+
+impl Scanner {
+    pub fn unexport(&mut self) {
+        self.servo.unexport();
+        self.sensor.unexport();
+    }
+}
+
+/// The angle a fixed sensor faces, in degrees with positive angles to the left.
+pub fn angle(direction: Direction) -> f32 {
+    match direction {
+        Direction::Forward => 0.0,
+        Direction::Left => 90.0,
+        Direction::Right => -90.0,
+        Direction::Backward => 180.0,
+    }
+}
+
+/// The fixed sensor direction closest to `angle`.
+pub fn nearest(angle: f32) -> Direction {
+    let angle = (angle % 360.0 + 360.0) % 360.0;
+    if angle <= 45.0 || angle > 315.0 {
+        Direction::Forward
+    } else if angle <= 135.0 {
+        Direction::Left
+    } else if angle <= 225.0 {
+        Direction::Backward
+    } else {
+        Direction::Right
+    }
+}
+
+impl Controller {
+    /// Takes readings every `step` degrees from `from` to `to`, with 0 straight ahead
+    /// and positive angles to the left.
+    ///
+    /// Without a scanning sensor this falls back to the fixed sensors facing between
+    /// the two angles, so callers don't need to care which the robot has. The sweep
+    /// goes whichever way `to` is from `from`, so a `step` that isn't more than 0 is
+    /// an error.
+    pub fn scan(&mut self,
+                from: f32,
+                to: f32,
+                step: f32)
+                -> cpupool::CpuFuture<Vec<DistanceVector>, error::Error> {
+        if step <= 0.0 || !step.is_finite() {
+            let err = error::Error::Argument("scan step must be more than 0 degrees");
+            return self.pool.spawn_fn(move || Err(err));
+        }
+        let step = if to < from { -step } else { step };
+        let scanner = self.scanner.clone();
+        let fixed = [Direction::Right, Direction::Forward, Direction::Left, Direction::Backward]
+            .iter()
+            .map(|&d| (angle(d), self.sensor(d)))
+            .filter(|&(a, _)| a >= from.min(to) && a <= from.max(to))
+            .collect::<Vec<_>>();
+        self.pool.spawn_fn(move || {
+            let mut vectors = vec![];
+            match scanner {
+                Some(scanner) => {
+                    let mut angle = from;
+                    // Allow for rounding in the last step
+                    while (angle - to) * step.signum() <= step.abs() * 1e-3 {
+                        scanner.servo.set_angle(angle)?;
+                        thread::sleep(scanner.servo.settle_time());
+                        vectors.push(DistanceVector {
+                            distance: scanner.sensor.value()?,
+                            direction: nearest(angle),
+                            angle: angle,
+                        });
+                        angle += step;
+                    }
+                    scanner.servo.set_angle(0.0)?;
+                }
+                None => {
+                    let mut fixed = fixed;
+                    if step < 0.0 {
+                        fixed.reverse();
+                    }
+                    for (angle, sensor) in fixed {
+                        vectors.push(DistanceVector {
+                            distance: sensor.value()?,
+                            direction: nearest(angle),
+                            angle: angle,
+                        });
+                    }
+                }
+            }
+            Ok(vectors)
+        })
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::sync::Arc;
+
+use sysfs_gpio::Pin;
+
+use super::Result;
+
+mod sample;
+mod schedule;
+mod sharp;
+mod tof;
+mod ultrasonic;
+
+pub use self::sample::{Readings, Sampler};
+pub use self::schedule::{DEFAULT_PING_GAP_MS, Scheduler};
+pub use self::sharp::{Model as SharpModel, SharpIr};
+pub use self::tof::{DEFAULT_ADDRESS, Vl53l0x, Vl53l1x};
+pub use self::ultrasonic::HcSr04;
+
+/// Anything that can measure the distance to the nearest obstacle.
+pub trait RangeSensor: fmt::Debug + Send + Sync {
+    /// The distance in centimeters.
+    fn value(&self) -> Result<f32>;
+
+    /// Releases whatever the sensor holds on to, such as exported pins.
+    fn unexport(&self) {}
+
+    /// Whether the sensor can pick up other sensors' signals, and so needs a
+    /// `Scheduler` to take turns with them.
+    fn interferes(&self) -> bool {
+        false
+    }
+}
+
+/// A shared handle to a range sensor. Clones read the same sensor.
+pub type Sensor = Arc<dyn RangeSensor>;
+
+str_enum! {
+    /// The kinds of range sensor the robot can use.
+    pub enum Kind {
+        /// HC-SR04 ultrasonic, on a trigger and an echo pin
+        HcSr04 => "hc-sr04",
+        /// VL53L0X time-of-flight, over I2C
+        Vl53l0x => "vl53l0x",
+        /// VL53L1X time-of-flight, over I2C
+        Vl53l1x => "vl53l1x",
+        /// Sharp analog IR, through an MCP3008 ADC over SPI
+        Sharp => "sharp"
+    }
+}
+
+impl Default for Kind {
+    fn default() -> Self {
+        Kind::HcSr04
+    }
+}
+
+/// Everything needed to set up one range sensor.
+#[derive(Debug, Clone)]
+pub enum Spec {
+    HcSr04 { trigger: Pin, echo: Pin },
+    Vl53l0x { bus: String, address: u16 },
+    Vl53l1x { bus: String, address: u16 },
+    Sharp {
+        spi: String,
+        channel: u8,
+        model: SharpModel,
+        vref: f32,
+    },
+}
+
+impl Spec {
+    /// The GPIO pins the sensor needs exported.
+    pub fn pins(&self) -> Vec<Pin> {
+        match *self {
+            Spec::HcSr04 { trigger, echo } => vec![trigger, echo],
+            _ => vec![],
+        }
+    }
+
+    pub fn build(&self) -> Result<Sensor> {
+        Ok(match *self {
+            Spec::HcSr04 { trigger, echo } => Arc::new(HcSr04::new(trigger, echo)?),
+            Spec::Vl53l0x { ref bus, address } => Arc::new(Vl53l0x::new(bus, address)?),
+            Spec::Vl53l1x { ref bus, address } => Arc::new(Vl53l1x::new(bus, address)?),
+            Spec::Sharp { ref spi, channel, model, vref } => {
+                Arc::new(SharpIr::new(spi, channel, model, vref)?)
+            }
+        })
+    }
+}
@@ -0,0 +1,283 @@
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time;
+
+use Direction;
+use error::Error;
+use super::{RangeSensor, Sensor};
+use super::super::Result;
+
+/// One sensor's distance in centimeters, and when it was measured.
+#[derive(Debug, Copy, Clone)]
+pub struct Reading {
+    pub direction: Direction,
+    pub distance: f32,
+    pub time: time::SystemTime,
+}
+
+/// Reads every sensor in turn on a background thread, over and over, so any number of
+/// subscribers can share the readings instead of each pinging the sensors themselves.
+///
+/// Each round of readings starts at least `period` after the last one started.
+#[derive(Debug)]
+pub struct Sampler {
+    sensors: Vec<(Direction, Sensor)>,
+    readings: Readings,
+    running: Arc<AtomicBool>,
+    // Taken when unexporting, so the sensors aren't released mid-reading
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+/// A subscription to a `Sampler`'s readings. Clones share the same readings.
+#[derive(Clone, Debug)]
+pub struct Readings {
+    shared: Arc<(Mutex<Samples>, Condvar)>,
+}
+
+#[derive(Debug)]
+struct Samples {
+    slots: Vec<Slot>,
+    // Set once the sampler thread has finished, so nothing waits for it forever
+    stopped: bool,
+}
+
+// The newest sample from one sensor, and how many samples came before it
+#[derive(Debug)]
+struct Slot {
+    direction: Direction,
+    count: u64,
+    latest: Option<Reading>,
+    error: Option<(io::ErrorKind, String)>,
+}
+
+impl Sampler {
+    pub fn new(sensors: Vec<(Direction, Sensor)>, period: time::Duration) -> Sampler {
+        let slots = sensors.iter()
+            .map(|&(direction, _)| {
+                Slot {
+                    direction: direction,
+                    count: 0,
+                    latest: None,
+                    error: None,
+                }
+            })
+            .collect();
+        let samples = Samples {
+            slots: slots,
+            stopped: false,
+        };
+        let readings = Readings { shared: Arc::new((Mutex::new(samples), Condvar::new())) };
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread_sensors = sensors.clone();
+        let thread_readings = readings.clone();
+        let thread_running = running.clone();
+        let thread = thread::spawn(move || {
+            while thread_running.load(Ordering::SeqCst) {
+                let start = time::Instant::now();
+                for &(direction, ref sensor) in &thread_sensors {
+                    thread_readings.publish(direction, sensor.value());
+                }
+                if let Some(wait) = period.checked_sub(start.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+            thread_readings.stop();
+        });
+
+        Sampler {
+            sensors: sensors,
+            readings: readings,
+            running: running,
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    pub fn readings(&self) -> Readings {
+        self.readings.clone()
+    }
+
+    /// A sensor that reads through the sampler, for code that wants a `Sensor`.
+    pub fn sensor(&self, direction: Direction) -> Sensor {
+        Arc::new(Sampled {
+            direction: direction,
+            readings: self.readings.clone(),
+        })
+    }
+
+    /// Stops sampling and releases the sensors, once the round of readings under way
+    /// has finished.
+    pub fn unexport(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            if thread.join().is_err() {
+                warn!("The sensor sampler panicked");
+            }
+        }
+        for &(_, ref sensor) in &self.sensors {
+            sensor.unexport();
+        }
+    }
+}
+
+impl Readings {
+    /// The newest good reading from the sensor facing `direction`, without waiting.
+    pub fn latest(&self, direction: Direction) -> Option<Reading> {
+        let &(ref samples, _) = &*self.shared;
+        let samples = samples.lock().unwrap();
+        samples.slots.iter().find(|slot| slot.direction == direction).and_then(|slot| slot.latest)
+    }
+
+    /// Waits for the sensor facing `direction` to be read again, and returns the new
+    /// reading, or the error reading it. Once the sampler has stopped there won't be
+    /// another reading, so that's an error too.
+    pub fn next(&self, direction: Direction) -> Result<Reading> {
+        let &(ref samples, ref cvar) = &*self.shared;
+        let mut samples = samples.lock().unwrap();
+        let index = match samples.slots.iter().position(|slot| slot.direction == direction) {
+            Some(index) => index,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "no sensor in that direction")
+                    .into())
+            }
+        };
+        let count = samples.slots[index].count;
+        while samples.slots[index].count == count {
+            if samples.stopped {
+                return Err(io::Error::new(io::ErrorKind::NotConnected,
+                                          "the sensors are no longer being read")
+                    .into());
+            }
+            samples = cvar.wait(samples).unwrap();
+        }
+        let slot = &samples.slots[index];
+        match slot.error {
+            Some((kind, ref error)) => Err(io::Error::new(kind, error.clone()).into()),
+            None => Ok(slot.latest.unwrap()),
+        }
+    }
+
+    fn publish(&self, direction: Direction, value: Result<f32>) {
+        let &(ref samples, ref cvar) = &*self.shared;
+        let mut samples = samples.lock().unwrap();
+        if let Some(slot) = samples.slots.iter_mut().find(|slot| slot.direction == direction) {
+            slot.count += 1;
+            match value {
+                Ok(distance) => {
+                    trace!("{:?} distance {}", direction, distance);
+                    slot.latest = Some(Reading {
+                        direction: direction,
+                        distance: distance,
+                        time: time::SystemTime::now(),
+                    });
+                    slot.error = None;
+                }
+                Err(error) => {
+                    // Errors can't be cloned for every subscriber, so keep what they said
+                    let kind = match error {
+                        Error::Io(ref error) => error.kind(),
+                        _ => io::ErrorKind::Other,
+                    };
+                    let message = error.to_string();
+                    // A sensor that's stopped working fails every time, so only say so once
+                    if slot.error.as_ref().map(|&(_, ref last)| last) != Some(&message) {
+                        warn!("Couldn't read the {:?} sensor: {}", direction, message);
+                    }
+                    slot.error = Some((kind, message));
+                }
+            }
+        }
+        cvar.notify_all();
+    }
+
+    fn stop(&self) {
+        let &(ref samples, ref cvar) = &*self.shared;
+        samples.lock().unwrap().stopped = true;
+        cvar.notify_all();
+    }
+}
+
+// Each reading waits for the sampler's next one, so loops polling it don't go any
+// faster than the sensor is actually read
+#[derive(Debug)]
+struct Sampled {
+    direction: Direction,
+    readings: Readings,
+}
+
+impl RangeSensor for Sampled {
+    fn value(&self) -> Result<f32> {
+        Ok(self.readings.next(self.direction)?.distance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time;
+
+    use {Direction, Result};
+    use distance::RangeSensor;
+    use super::Sampler;
+
+    #[derive(Debug)]
+    struct Fixed(f32);
+
+    impl RangeSensor for Fixed {
+        fn value(&self) -> Result<f32> {
+            Ok(self.0)
+        }
+    }
+
+    // Takes a while to read, and remembers if it was released while being read
+    #[derive(Debug, Default)]
+    struct Slow {
+        reading: AtomicBool,
+        released_mid_reading: AtomicBool,
+    }
+
+    impl RangeSensor for Slow {
+        fn value(&self) -> Result<f32> {
+            self.reading.store(true, Ordering::SeqCst);
+            thread::sleep(time::Duration::from_millis(20));
+            self.reading.store(false, Ordering::SeqCst);
+            Ok(50.0)
+        }
+
+        fn unexport(&self) {
+            if self.reading.load(Ordering::SeqCst) {
+                self.released_mid_reading.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[test]
+    fn unexport_waits_for_the_reading_under_way() {
+        let sensor = Arc::new(Slow::default());
+        let sampler = Sampler::new(vec![(Direction::Forward, sensor.clone())],
+                                   time::Duration::from_millis(0));
+        while !sensor.reading.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        sampler.unexport();
+        assert!(!sensor.released_mid_reading.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn next_fails_once_the_sampler_stops() {
+        let sampler = Sampler::new(vec![(Direction::Left, Arc::new(Fixed(12.5)))],
+                                   time::Duration::from_millis(1));
+        let readings = sampler.readings();
+        assert_eq!(readings.next(Direction::Left).unwrap().distance, 12.5);
+        assert!(readings.next(Direction::Right).is_err());
+        sampler.unexport();
+        // A round might already be under way, but there's no waiting after that
+        while readings.next(Direction::Left).is_ok() {}
+        assert!(readings.next(Direction::Left).is_err());
+        assert_eq!(readings.latest(Direction::Left).unwrap().distance, 12.5);
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time;
+
+use super::{RangeSensor, Sensor};
+use super::super::Result;
+
+/// Long enough for an HC-SR04's echoes from a few meters away to die down.
+pub const DEFAULT_PING_GAP_MS: u64 = 30;
+
+/// Takes turns pinging sensors that would otherwise hear each other's echoes.
+///
+/// Readings are handed out first come, first served, so sensors being read in a loop
+/// are pinged round-robin, and each ping waits for the gap since the last one ended.
+/// Clones share the same turns.
+#[derive(Clone, Debug)]
+pub struct Scheduler {
+    gap: time::Duration,
+    shared: Arc<(Mutex<Turns>, Condvar)>,
+}
+
+#[derive(Debug)]
+struct Turns {
+    next_ticket: u64,
+    serving: u64,
+    last_ping: Option<time::Instant>,
+}
+
+impl Scheduler {
+    pub fn new(gap: time::Duration) -> Scheduler {
+        Scheduler {
+            gap: gap,
+            shared: Arc::new((Mutex::new(Turns {
+                                  next_ticket: 0,
+                                  serving: 0,
+                                  last_ping: None,
+                              }),
+                              Condvar::new())),
+        }
+    }
+
+    /// Makes `sensor` take turns with the others, if it can interfere with them.
+    pub fn schedule(&self, sensor: Sensor) -> Sensor {
+        if sensor.interferes() {
+            Arc::new(Scheduled {
+                sensor: sensor,
+                scheduler: self.clone(),
+            })
+        } else {
+            sensor
+        }
+    }
+
+    fn take_turn<T, F: FnOnce() -> T>(&self, ping: F) -> T {
+        let &(ref turns, ref cvar) = &*self.shared;
+        let wait = {
+            let mut turns = turns.lock().unwrap();
+            let ticket = turns.next_ticket;
+            turns.next_ticket += 1;
+            while turns.serving != ticket {
+                turns = cvar.wait(turns).unwrap();
+            }
+            turns.last_ping.map(|last| self.gap.checked_sub(last.elapsed()))
+        };
+        let _turn = Turn(self);
+        // Let the last ping's echoes die down
+        if let Some(Some(wait)) = wait {
+            thread::sleep(wait);
+        }
+        ping()
+    }
+}
+
+// Hands the turn on to the next sensor when dropped, so a ping that panics doesn't
+// leave the others waiting forever
+struct Turn<'a>(&'a Scheduler);
+
+impl<'a> Drop for Turn<'a> {
+    fn drop(&mut self) {
+        let &(ref turns, ref cvar) = &*self.0.shared;
+        let mut turns = turns.lock().unwrap();
+        turns.last_ping = Some(time::Instant::now());
+        turns.serving += 1;
+        cvar.notify_all();
+    }
+}
+
+#[derive(Debug)]
+struct Scheduled {
+    sensor: Sensor,
+    scheduler: Scheduler,
+}
+
+impl RangeSensor for Scheduled {
+    fn value(&self) -> Result<f32> {
+        self.scheduler.take_turn(|| self.sensor.value())
+    }
+
+    fn unexport(&self) {
+        self.sensor.unexport()
+    }
+
+    fn interferes(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time;
+
+    use super::Scheduler;
+
+    #[test]
+    fn waits_for_the_gap_between_pings() {
+        let scheduler = Scheduler::new(time::Duration::from_millis(20));
+        let first = scheduler.take_turn(time::Instant::now);
+        let second = scheduler.take_turn(time::Instant::now);
+        assert!(second.duration_since(first) >= time::Duration::from_millis(20));
+    }
+
+    #[test]
+    fn a_panicking_ping_hands_on_its_turn() {
+        let scheduler = Scheduler::new(time::Duration::from_millis(0));
+        let panicking = scheduler.clone();
+        let pinged = thread::spawn(move || panicking.take_turn(|| panic!("sensor fell off")));
+        assert!(pinged.join().is_err());
+        assert_eq!(scheduler.take_turn(|| 5), 5);
+    }
+}
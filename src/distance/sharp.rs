@@ -0,0 +1,160 @@
+use std::fmt;
+use std::sync::Mutex;
+
+use spidev::{Spidev, SpidevOptions, SpidevTransfer, SpiModeFlags};
+
+use error::BuilderError;
+use super::RangeSensor;
+use super::super::Result;
+
+// The MCP3008 has eight single-ended inputs
+const CHANNELS: u8 = 8;
+
+str_enum! {
+    /// Sharp analog IR sensor models, which each have their own voltage to distance
+    /// curve.
+    pub enum Model {
+        /// 10cm to 80cm
+        Gp2y0a21 => "gp2y0a21",
+        /// 20cm to 150cm
+        Gp2y0a02 => "gp2y0a02"
+    }
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Model::Gp2y0a21
+    }
+}
+
+impl Model {
+    // Distance in cm is roughly `scale * volts ^ exponent`, fitted to the datasheet curves
+    fn curve(&self) -> (f32, f32) {
+        match *self {
+            Model::Gp2y0a21 => (27.728, -1.2045),
+            Model::Gp2y0a02 => (60.374, -1.16),
+        }
+    }
+
+    /// The farthest distance the sensor is any good at.
+    fn range(&self) -> f32 {
+        match *self {
+            Model::Gp2y0a21 => 80.0,
+            Model::Gp2y0a02 => 150.0,
+        }
+    }
+
+    // Nothing in range reads as a very low voltage, so cap it at the sensor's range
+    fn distance(&self, volts: f32) -> f32 {
+        let (scale, exponent) = self.curve();
+        (scale * volts.powf(exponent)).min(self.range())
+    }
+}
+
+/// A Sharp IR distance sensor, read through one channel of an MCP3008 ADC.
+pub struct SharpIr {
+    spi: Mutex<Spidev>,
+    channel: u8,
+    model: Model,
+    vref: f32,
+}
+
+impl fmt::Debug for SharpIr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharpIr")
+            .field("channel", &self.channel)
+            .field("model", &self.model)
+            .field("vref", &self.vref)
+            .finish()
+    }
+}
+
+impl SharpIr {
+    /// Opens the ADC on the SPI device at `spi`, such as `/dev/spidev0.0`, with the
+    /// sensor on `channel` 0 to 7 and the ADC's reference voltage at `vref`.
+    pub fn new(spi: &str, channel: u8, model: Model, vref: f32) -> Result<SharpIr> {
+        if channel >= CHANNELS {
+            return Err(BuilderError::AdcChannel.into());
+        }
+        let mut device = Spidev::open(spi)?;
+        device.configure(&SpidevOptions::new()
+                .bits_per_word(8)
+                .max_speed_hz(1000000)
+                .mode(SpiModeFlags::SPI_MODE_0)
+                .build())?;
+        Ok(SharpIr {
+            spi: Mutex::new(device),
+            channel: channel,
+            model: model,
+            vref: vref,
+        })
+    }
+
+    fn read_adc(&self) -> Result<u16> {
+        let tx = request(self.channel);
+        let mut rx = [0; 3];
+        {
+            let mut transfer = SpidevTransfer::read_write(&tx, &mut rx);
+            self.spi.lock().unwrap().transfer(&mut transfer)?;
+        }
+        Ok(reading(&rx))
+    }
+}
+
+impl RangeSensor for SharpIr {
+    fn value(&self) -> Result<f32> {
+        Ok(self.model.distance(volts(self.read_adc()?, self.vref)))
+    }
+}
+
+// Start bit, then single-ended mode and the channel
+fn request(channel: u8) -> [u8; 3] {
+    [0x01, (0x08 | channel) << 4, 0x00]
+}
+
+// The 10 bits that come back after the request
+fn reading(rx: &[u8; 3]) -> u16 {
+    (rx[1] as u16 & 0x03) << 8 | rx[2] as u16
+}
+
+fn volts(reading: u16, vref: f32) -> f32 {
+    reading as f32 * vref / 1023.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Model, SharpIr, reading, request, volts};
+
+    #[test]
+    fn requests_a_single_ended_channel() {
+        assert_eq!(request(0), [0x01, 0x80, 0x00]);
+        assert_eq!(request(7), [0x01, 0xF0, 0x00]);
+    }
+
+    #[test]
+    fn readings_are_ten_bits() {
+        assert_eq!(reading(&[0xFF, 0x02, 0x34]), 0x234);
+        assert_eq!(reading(&[0xFF, 0xFF, 0xFF]), 1023);
+        assert_eq!(volts(1023, 3.3), 3.3);
+        assert_eq!(volts(0, 3.3), 0.0);
+    }
+
+    #[test]
+    fn distance_falls_as_voltage_rises() {
+        assert_eq!(Model::Gp2y0a21.distance(1.0), 27.728);
+        assert_eq!(Model::Gp2y0a02.distance(1.0), 60.374);
+        assert!(Model::Gp2y0a21.distance(2.0) < Model::Gp2y0a21.distance(1.5));
+    }
+
+    #[test]
+    fn low_voltages_read_as_the_sensors_range() {
+        assert_eq!(Model::Gp2y0a21.distance(0.1), 80.0);
+        assert_eq!(Model::Gp2y0a02.distance(0.0), 150.0);
+    }
+
+    #[test]
+    fn channels_past_the_adcs_are_rejected() {
+        let err = SharpIr::new("/nonexistent", 8, Model::Gp2y0a21, 3.3).unwrap_err();
+        assert_eq!(err.to_string(), "Builder: Sharp IR sensor's ADC channel must be 0 to 7");
+    }
+}
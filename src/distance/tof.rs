@@ -0,0 +1,266 @@
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+use i2cdev::core::I2CDevice;
+use i2cdev::linux::LinuxI2CDevice;
+
+use super::RangeSensor;
+use super::super::Result;
+
+/// The address both sensors start up at. Giving each sensor on a bus its own address
+/// has to be done by holding the others in reset, which isn't handled here.
+pub const DEFAULT_ADDRESS: u16 = 0x29;
+
+// How long to wait for a measurement before giving up
+const TIMEOUT_MS: u64 = 500;
+
+fn timeout(start: time::Instant) -> Result<()> {
+    if start.elapsed() > time::Duration::from_millis(TIMEOUT_MS) {
+        Err(io::Error::new(io::ErrorKind::TimedOut, "time-of-flight sensor timed out").into())
+    } else {
+        Ok(())
+    }
+}
+
+fn cm(mm: u16) -> f32 {
+    mm as f32 / 10.0
+}
+
+/// A VL53L0X time-of-flight sensor, good for up to about 2m.
+///
+/// This runs the sensor's reference calibrations but not ST's full tuning, so
+/// readings can be off by a centimeter or so.
+pub struct Vl53l0x {
+    device: Mutex<LinuxI2CDevice>,
+    address: u16,
+    stop_variable: u8,
+}
+
+impl fmt::Debug for Vl53l0x {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Vl53l0x")
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+impl Vl53l0x {
+    pub fn new(bus: &str, address: u16) -> Result<Vl53l0x> {
+        let mut device = LinuxI2CDevice::new(bus, address)?;
+        if device.smbus_read_byte_data(0xC0)? != 0xEE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a VL53L0X").into());
+        }
+        // 2.8V I/O and standard I2C mode
+        let pad = device.smbus_read_byte_data(0x89)?;
+        device.smbus_write_byte_data(0x89, pad | 0x01)?;
+        device.smbus_write_byte_data(0x88, 0x00)?;
+
+        let stop_variable = {
+            device.smbus_write_byte_data(0x80, 0x01)?;
+            device.smbus_write_byte_data(0xFF, 0x01)?;
+            device.smbus_write_byte_data(0x00, 0x00)?;
+            let stop_variable = device.smbus_read_byte_data(0x91)?;
+            device.smbus_write_byte_data(0x00, 0x01)?;
+            device.smbus_write_byte_data(0xFF, 0x00)?;
+            device.smbus_write_byte_data(0x80, 0x00)?;
+            stop_variable
+        };
+
+        // Skip the MSRC and pre-range signal checks
+        let msrc = device.smbus_read_byte_data(0x60)?;
+        device.smbus_write_byte_data(0x60, msrc | 0x12)?;
+
+        // Interrupt on new samples, active low
+        device.smbus_write_byte_data(0x0A, 0x04)?;
+        let mux = device.smbus_read_byte_data(0x84)?;
+        device.smbus_write_byte_data(0x84, mux & !0x10)?;
+        device.smbus_write_byte_data(0x0B, 0x01)?;
+
+        // VHV then phase reference calibration, each with only its step enabled
+        device.smbus_write_byte_data(0x01, 0x01)?;
+        Vl53l0x::calibrate(&mut device, 0x40)?;
+        device.smbus_write_byte_data(0x01, 0x02)?;
+        Vl53l0x::calibrate(&mut device, 0x00)?;
+        device.smbus_write_byte_data(0x01, 0xE8)?;
+
+        Ok(Vl53l0x {
+            device: Mutex::new(device),
+            address: address,
+            stop_variable: stop_variable,
+        })
+    }
+
+    fn calibrate(device: &mut LinuxI2CDevice, vhv_init: u8) -> Result<()> {
+        device.smbus_write_byte_data(0x00, 0x01 | vhv_init)?;
+        Vl53l0x::wait_for_interrupt(device)?;
+        device.smbus_write_byte_data(0x0B, 0x01)?;
+        device.smbus_write_byte_data(0x00, 0x00)?;
+        Ok(())
+    }
+
+    fn wait_for_interrupt(device: &mut LinuxI2CDevice) -> Result<()> {
+        let start = time::Instant::now();
+        while device.smbus_read_byte_data(0x13)? & 0x07 == 0 {
+            timeout(start)?;
+        }
+        Ok(())
+    }
+}
+
+impl RangeSensor for Vl53l0x {
+    fn value(&self) -> Result<f32> {
+        let mut device = self.device.lock().unwrap();
+        device.smbus_write_byte_data(0x80, 0x01)?;
+        device.smbus_write_byte_data(0xFF, 0x01)?;
+        device.smbus_write_byte_data(0x00, 0x00)?;
+        device.smbus_write_byte_data(0x91, self.stop_variable)?;
+        device.smbus_write_byte_data(0x00, 0x01)?;
+        device.smbus_write_byte_data(0xFF, 0x00)?;
+        device.smbus_write_byte_data(0x80, 0x00)?;
+
+        // Start a single measurement, and wait for it to start and then finish
+        device.smbus_write_byte_data(0x00, 0x01)?;
+        let start = time::Instant::now();
+        while device.smbus_read_byte_data(0x00)? & 0x01 != 0 {
+            timeout(start)?;
+        }
+        Vl53l0x::wait_for_interrupt(&mut device)?;
+
+        let high = device.smbus_read_byte_data(0x1E)?;
+        let low = device.smbus_read_byte_data(0x1F)?;
+        device.smbus_write_byte_data(0x0B, 0x01)?;
+        Ok(cm((high as u16) << 8 | low as u16))
+    }
+}
+
+// ST's default configuration for registers 0x2D to 0x87, from their Ultra Lite driver
+const VL53L1X_DEFAULT_CONFIGURATION: [u8; 91] =
+    [0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x02, 0x08, 0x00, 0x08, 0x10, 0x01, 0x01, 0x00,
+     0x00, 0x00, 0x00, 0xff, 0x00, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x0b, 0x00,
+     0x00, 0x02, 0x0a, 0x21, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0xc8, 0x00, 0x00,
+     0x38, 0xff, 0x01, 0x00, 0x08, 0x00, 0x00, 0x01, 0xcc, 0x0f, 0x01, 0xf1, 0x0d, 0x01,
+     0x68, 0x00, 0x80, 0x08, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x89, 0x00, 0x00, 0x00,
+     0x00, 0x00, 0x00, 0x00, 0x01, 0x0f, 0x0d, 0x0e, 0x0e, 0x00, 0x00, 0x02, 0xc7, 0xff,
+     0x9B, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00];
+
+/// A VL53L1X time-of-flight sensor, good for up to about 4m.
+///
+/// The sensor ranges continuously once started, and each reading waits for the
+/// next measurement.
+pub struct Vl53l1x {
+    device: Mutex<LinuxI2CDevice>,
+    address: u16,
+}
+
+impl fmt::Debug for Vl53l1x {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Vl53l1x")
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+impl Vl53l1x {
+    pub fn new(bus: &str, address: u16) -> Result<Vl53l1x> {
+        let mut device = LinuxI2CDevice::new(bus, address)?;
+        if Vl53l1x::read16(&mut device, 0x010F)? != 0xEACC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a VL53L1X").into());
+        }
+        let start = time::Instant::now();
+        while Vl53l1x::read(&mut device, 0x00E5)? & 0x01 == 0 {
+            timeout(start)?;
+        }
+        let mut config = vec![0x00, 0x2D];
+        config.extend_from_slice(&VL53L1X_DEFAULT_CONFIGURATION);
+        device.write(&config)?;
+
+        // One throwaway measurement, then switch off the VHV loop for later ones
+        Vl53l1x::write(&mut device, 0x0087, 0x40)?;
+        Vl53l1x::wait_for_data(&mut device)?;
+        Vl53l1x::write(&mut device, 0x0086, 0x01)?;
+        Vl53l1x::write(&mut device, 0x0087, 0x00)?;
+        Vl53l1x::write(&mut device, 0x0008, 0x09)?;
+        Vl53l1x::write(&mut device, 0x000B, 0x00)?;
+
+        Vl53l1x::write(&mut device, 0x0087, 0x40)?;
+        Ok(Vl53l1x {
+            device: Mutex::new(device),
+            address: address,
+        })
+    }
+
+    // Registers have 16 bit addresses, which SMBus can't do
+    fn read(device: &mut LinuxI2CDevice, register: u16) -> Result<u8> {
+        let mut data = [0; 1];
+        device.write(&[(register >> 8) as u8, register as u8])?;
+        device.read(&mut data)?;
+        Ok(data[0])
+    }
+
+    fn read16(device: &mut LinuxI2CDevice, register: u16) -> Result<u16> {
+        let mut data = [0; 2];
+        device.write(&[(register >> 8) as u8, register as u8])?;
+        device.read(&mut data)?;
+        Ok((data[0] as u16) << 8 | data[1] as u16)
+    }
+
+    fn write(device: &mut LinuxI2CDevice, register: u16, value: u8) -> Result<()> {
+        device.write(&[(register >> 8) as u8, register as u8, value])?;
+        Ok(())
+    }
+
+    fn wait_for_data(device: &mut LinuxI2CDevice) -> Result<()> {
+        // The interrupt pin's polarity decides what "ready" reads as
+        let ready = (Vl53l1x::read(device, 0x0030)? & 0x10 == 0) as u8;
+        let start = time::Instant::now();
+        while Vl53l1x::read(device, 0x0031)? & 0x01 != ready {
+            timeout(start)?;
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        Ok(())
+    }
+}
+
+impl RangeSensor for Vl53l1x {
+    fn value(&self) -> Result<f32> {
+        let mut device = self.device.lock().unwrap();
+        Vl53l1x::wait_for_data(&mut device)?;
+        let mm = Vl53l1x::read16(&mut device, 0x0096)?;
+        Vl53l1x::write(&mut device, 0x0086, 0x01)?;
+        Ok(cm(mm))
+    }
+
+    fn unexport(&self) {
+        let mut device = self.device.lock().unwrap();
+        Vl53l1x::write(&mut device, 0x0087, 0x00).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::{TIMEOUT_MS, VL53L1X_DEFAULT_CONFIGURATION, cm, timeout};
+
+    #[test]
+    fn millimeters_to_centimeters() {
+        assert_eq!(cm(0), 0.0);
+        assert_eq!(cm(1234), 123.4);
+        assert_eq!(cm(0xFFFF), 6553.5);
+    }
+
+    #[test]
+    fn times_out_after_the_limit() {
+        assert!(timeout(time::Instant::now()).is_ok());
+        let late = time::Instant::now() - time::Duration::from_millis(TIMEOUT_MS + 1);
+        assert!(timeout(late).is_err());
+    }
+
+    #[test]
+    fn default_configuration_covers_its_registers() {
+        assert_eq!(VL53L1X_DEFAULT_CONFIGURATION.len(), 0x87 - 0x2D + 1);
+    }
+}
@@ -0,0 +1,102 @@
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time;
+
+use sysfs_gpio as gpio;
+use sysfs_gpio::Pin;
+
+use super::RangeSensor;
+use super::super::Result;
+
+const SOUND_SPEED_CM: u64 = 34300;
+// An HC-SR04 gives up on an echo after about 38ms, so a ping that takes longer than
+// this isn't coming back, say if the sensor's come loose
+const ECHO_TIMEOUT_MS: u64 = 60;
+
+/// An HC-SR04 ultrasonic sensor, timed through its trigger and echo pins.
+#[derive(Debug)]
+pub struct HcSr04 {
+    trigger: Pin,
+    echo: Pin,
+    // Only one thread pings the sensor at a time
+    lock: Mutex<()>,
+}
+
+impl HcSr04 {
+    pub fn new(trigger: Pin, echo: Pin) -> Result<HcSr04> {
+        let sensor = HcSr04 {
+            trigger: trigger,
+            echo: echo,
+            lock: Mutex::new(()),
+        };
+        sensor.trigger.set_direction(gpio::Direction::Out)?;
+        sensor.echo.set_direction(gpio::Direction::In)?;
+        Ok(sensor)
+    }
+}
+
+impl RangeSensor for HcSr04 {
+    fn value(&self) -> Result<f32> {
+        let _lock = self.lock.lock().unwrap();
+        // 10μs pulse
+        self.trigger.set_value(1)?;
+        thread::sleep(time::Duration::new(0, 10000));
+        self.trigger.set_value(0)?;
+
+        let deadline = time::Instant::now() + time::Duration::from_millis(ECHO_TIMEOUT_MS);
+        while self.echo.get_value()? == 0 {
+            no_echo_by(deadline)?;
+        }
+        let pulse_start = time::Instant::now();
+        while self.echo.get_value()? == 1 {
+            no_echo_by(deadline)?;
+        }
+        Ok(distance(pulse_start.elapsed()))
+    }
+
+    fn unexport(&self) {
+        self.trigger.set_value(0).ok();
+        self.echo.set_value(0).ok();
+        gpio_unexport!(self, {trigger, echo})
+    }
+
+    // Any HC-SR04 hears every other one's pings
+    fn interferes(&self) -> bool {
+        true
+    }
+}
+
+fn no_echo_by(deadline: time::Instant) -> Result<()> {
+    if time::Instant::now() > deadline {
+        Err(io::Error::new(io::ErrorKind::TimedOut, "no echo from the ultrasonic sensor").into())
+    } else {
+        Ok(())
+    }
+}
+
+// The echo pulse lasts as long as the sound took to get there and back
+fn distance(pulse: time::Duration) -> f32 {
+    let seconds = pulse.as_secs() as f32 + pulse.subsec_nanos() as f32 / 1e9;
+    seconds / 2.0 * SOUND_SPEED_CM as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::{distance, no_echo_by};
+
+    #[test]
+    fn distance_is_half_the_round_trip() {
+        assert!((distance(time::Duration::from_millis(2)) - 34.3).abs() < 1e-4);
+        assert_eq!(distance(time::Duration::from_millis(0)), 0.0);
+    }
+
+    #[test]
+    fn echoes_time_out() {
+        let now = time::Instant::now();
+        assert!(no_echo_by(now + time::Duration::from_secs(1)).is_ok());
+        assert!(no_echo_by(now - time::Duration::from_millis(1)).is_err());
+    }
+}
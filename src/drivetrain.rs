@@ -0,0 +1,317 @@
+use std::fmt;
+use std::sync::Arc;
+
+use Direction;
+use error::Error;
+use motor::{self, Channel, Role};
+use super::Result;
+
+str_enum! {
+    /// The wheel layouts a robot can drive with.
+    pub enum Kind {
+        /// Two driven wheels, one on each side
+        Differential => "differential",
+        /// Four wheels, steered by driving each side at a different speed
+        Skid => "skid",
+        /// Four mecanum wheels, which can also slide sideways
+        Mecanum => "mecanum",
+        /// Two wheels on stepper motors, one on each side
+        Stepper => "stepper"
+    }
+}
+
+impl Default for Kind {
+    fn default() -> Self {
+        Kind::Skid
+    }
+}
+
+impl Kind {
+    /// The wheels this layout needs a motor channel for.
+    pub fn roles(&self) -> &'static [Role] {
+        const TWO: &'static [Role] = &[Role::Left, Role::Right];
+        const FOUR: &'static [Role] =
+            &[Role::FrontLeft, Role::FrontRight, Role::RearLeft, Role::RearRight];
+        match *self {
+            Kind::Differential | Kind::Stepper => TWO,
+            Kind::Skid | Kind::Mecanum => FOUR,
+        }
+    }
+
+    pub fn strafes(&self) -> bool {
+        *self == Kind::Mecanum
+    }
+
+    /// Whether `roles` has exactly one channel for each wheel this layout drives.
+    pub fn fits(&self, roles: &[Role]) -> bool {
+        roles.len() == self.roles().len() &&
+        self.roles().iter().all(|role| roles.contains(role))
+    }
+
+    /// Puts together a drivetrain from H-bridge channels for each of its `roles`.
+    ///
+    /// Returns `None` if the channels don't `fit` this layout, or it isn't driven by
+    /// H-bridges.
+    pub fn build(&self, channels: Vec<(Role, Channel)>) -> Option<Arc<dyn Drivetrain>> {
+        let roles = channels.iter().map(|&(role, _)| role).collect::<Vec<_>>();
+        if !self.fits(&roles) {
+            return None;
+        }
+        let find = |role: Role| {
+            channels.iter()
+                .find(|&&(r, _)| r == role)
+                .map(|&(_, ref channel)| channel.clone())
+        };
+        match *self {
+            Kind::Differential => {
+                Some(Arc::new(Differential {
+                    left: find(Role::Left)?,
+                    right: find(Role::Right)?,
+                }))
+            }
+            Kind::Skid => {
+                Some(Arc::new(SkidSteer(FourWheels {
+                    front_left: find(Role::FrontLeft)?,
+                    front_right: find(Role::FrontRight)?,
+                    rear_left: find(Role::RearLeft)?,
+                    rear_right: find(Role::RearRight)?,
+                })))
+            }
+            Kind::Mecanum => {
+                Some(Arc::new(Mecanum(FourWheels {
+                    front_left: find(Role::FrontLeft)?,
+                    front_right: find(Role::FrontRight)?,
+                    rear_left: find(Role::RearLeft)?,
+                    rear_right: find(Role::RearRight)?,
+                })))
+            }
+            Kind::Stepper => None,
+        }
+    }
+}
+
+/// The motors that move the robot around, for a particular wheel layout.
+pub trait Drivetrain: fmt::Debug + Send + Sync {
+    /// Drives the wheels on each side at a signed speed between -1 (full reverse)
+    /// and 1 (full forward).
+    fn drive(&self, left: f32, right: f32) -> Result<()>;
+
+    /// The H-bridge motor channels, and the wheels they drive.
+    fn channels(&self) -> Vec<(Role, &Channel)>;
+
+    /// The wheels that have a motor.
+    fn wheels(&self) -> Vec<Role> {
+        self.channels().into_iter().map(|(role, _)| role).collect()
+    }
+
+    /// Drives a single wheel at a signed speed, or stops it at 0. Wheels this
+    /// drivetrain doesn't have are ignored.
+    fn drive_wheel(&self, wheel: Role, speed: f32) -> Result<()> {
+        match self.channels().into_iter().find(|&(role, _)| role == wheel) {
+            Some((_, channel)) if speed == 0.0 => channel.disable(),
+            Some((_, channel)) => channel.drive(speed),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether moves can be measured out by counting steps with `move_exactly`,
+    /// instead of watching the sensors for the next node.
+    fn counts_steps(&self) -> bool {
+        false
+    }
+
+    /// Moves to the next node, or turns 90 degrees, and blocks until the move is done.
+    ///
+    /// Drivetrains that don't count steps can't tell how far they've gone, so by
+    /// default this fails without moving.
+    fn move_exactly(&self, _direction: Direction, _speed: f32) -> Result<()> {
+        Err(Error::Drivetrain("can't move an exact distance without counting steps"))
+    }
+
+    /// Whether `Direction::Left` and `Direction::Right` moves slide sideways
+    /// instead of turning in place.
+    fn strafes(&self) -> bool {
+        false
+    }
+
+    /// Starts moving in the given direction until stopped.
+    fn start(&self, direction: Direction, speed: f32) -> Result<()> {
+        match direction {
+            Direction::Forward => self.drive(speed, speed),
+            Direction::Backward => self.drive(-speed, -speed),
+            Direction::Left | Direction::Right => self.rotate(direction, speed),
+        }
+    }
+
+    /// Starts turning in place, whether or not the drivetrain strafes.
+    fn rotate(&self, direction: Direction, speed: f32) -> Result<()> {
+        // Turning spins the wheels on each side in opposite directions
+        match direction {
+            Direction::Left => self.drive(-speed, speed),
+            Direction::Right => self.drive(speed, -speed),
+            Direction::Forward => self.drive(speed, speed),
+            Direction::Backward => self.drive(-speed, -speed),
+        }
+    }
+
+    fn stop(&self, stop: motor::Stop) -> Result<()> {
+        let channels = self.channels().into_iter().map(|(_, c)| c).collect::<Vec<_>>();
+        stop.apply(&channels)
+    }
+
+    fn unexport(&self) {
+        for (_, channel) in self.channels() {
+            channel.unexport();
+        }
+    }
+}
+
+/// One motor on each side.
+#[derive(Debug)]
+pub struct Differential {
+    left: Channel,
+    right: Channel,
+}
+
+impl Drivetrain for Differential {
+    fn drive(&self, left: f32, right: f32) -> Result<()> {
+        self.left.drive(left)?;
+        self.right.drive(right)
+    }
+
+    fn channels(&self) -> Vec<(Role, &Channel)> {
+        vec![(Role::Left, &self.left), (Role::Right, &self.right)]
+    }
+}
+
+#[derive(Debug)]
+struct FourWheels {
+    front_left: Channel,
+    front_right: Channel,
+    rear_left: Channel,
+    rear_right: Channel,
+}
+
+impl FourWheels {
+    fn drive(&self, left: f32, right: f32) -> Result<()> {
+        self.front_left.drive(left)?;
+        self.rear_left.drive(left)?;
+        self.front_right.drive(right)?;
+        self.rear_right.drive(right)
+    }
+
+    fn channels(&self) -> Vec<(Role, &Channel)> {
+        vec![(Role::FrontLeft, &self.front_left),
+             (Role::FrontRight, &self.front_right),
+             (Role::RearLeft, &self.rear_left),
+             (Role::RearRight, &self.rear_right)]
+    }
+}
+
+/// Four wheels driven in pairs by side, turning by skidding.
+#[derive(Debug)]
+pub struct SkidSteer(FourWheels);
+
+impl Drivetrain for SkidSteer {
+    fn drive(&self, left: f32, right: f32) -> Result<()> {
+        self.0.drive(left, right)
+    }
+
+    fn channels(&self) -> Vec<(Role, &Channel)> {
+        self.0.channels()
+    }
+}
+
+/// Four mecanum wheels, with rollers at 45 degrees so the robot can slide sideways.
+///
+/// The rollers are expected in the usual X pattern when seen from above.
+#[derive(Debug)]
+pub struct Mecanum(FourWheels);
+
+impl Drivetrain for Mecanum {
+    fn drive(&self, left: f32, right: f32) -> Result<()> {
+        self.0.drive(left, right)
+    }
+
+    fn channels(&self) -> Vec<(Role, &Channel)> {
+        self.0.channels()
+    }
+
+    fn strafes(&self) -> bool {
+        true
+    }
+
+    fn start(&self, direction: Direction, speed: f32) -> Result<()> {
+        let speed = match direction {
+            Direction::Left => speed,
+            Direction::Right => -speed,
+            Direction::Forward => return self.drive(speed, speed),
+            Direction::Backward => return self.drive(-speed, -speed),
+        };
+        for &(wheel, speed) in &strafe_left(speed) {
+            self.drive_wheel(wheel, speed)?;
+        }
+        Ok(())
+    }
+}
+
+// Each wheel's speed to slide left, or right at a negative speed. Diagonal wheels
+// spin together, and opposite to the other diagonal.
+fn strafe_left(speed: f32) -> [(Role, f32); 4] {
+    [(Role::FrontLeft, -speed),
+     (Role::RearRight, -speed),
+     (Role::FrontRight, speed),
+     (Role::RearLeft, speed)]
+}
+
+#[cfg(test)]
+mod tests {
+    use motor::{Channel, Role};
+    use super::{Kind, strafe_left};
+
+    fn channels(roles: &[Role]) -> Vec<(Role, Channel)> {
+        roles.iter().map(|&role| (role, Channel::unexported(1, 2, 3))).collect()
+    }
+
+    #[test]
+    fn layouts_fit_one_channel_per_wheel() {
+        assert!(Kind::Differential.fits(&[Role::Right, Role::Left]));
+        assert!(Kind::Skid.fits(Kind::Mecanum.roles()));
+        assert!(!Kind::Differential.fits(&[Role::Left]));
+        assert!(!Kind::Differential.fits(&[Role::Left, Role::Left]));
+        assert!(!Kind::Differential.fits(&[Role::FrontLeft, Role::FrontRight]));
+        assert!(!Kind::Skid.fits(&[Role::Left, Role::Right]));
+    }
+
+    #[test]
+    fn builds_layouts_that_fit() {
+        let differential = Kind::Differential.build(channels(&[Role::Left, Role::Right]))
+            .unwrap();
+        assert_eq!(differential.wheels(), vec![Role::Left, Role::Right]);
+        assert!(!differential.strafes());
+        let mecanum = Kind::Mecanum.build(channels(Kind::Mecanum.roles())).unwrap();
+        assert_eq!(mecanum.wheels(), Kind::Mecanum.roles());
+        assert!(mecanum.strafes());
+        assert!(!Kind::Skid.build(channels(Kind::Skid.roles())).unwrap().strafes());
+    }
+
+    #[test]
+    fn builds_nothing_that_does_not_fit() {
+        assert!(Kind::Skid.build(channels(&[Role::Left, Role::Right])).is_none());
+        assert!(Kind::Differential.build(channels(&[Role::Left])).is_none());
+        // Steppers aren't driven by H-bridges
+        assert!(Kind::Stepper.build(channels(&[Role::Left, Role::Right])).is_none());
+    }
+
+    #[test]
+    fn strafing_spins_the_diagonals_apart() {
+        let left = strafe_left(0.5);
+        let speed = |wheel: Role| left.iter().find(|&&(role, _)| role == wheel).unwrap().1;
+        // The front wheels push the robot left by spinning away from each other, and
+        // the rear ones by spinning towards each other
+        assert_eq!(speed(Role::FrontLeft), -0.5);
+        assert_eq!(speed(Role::FrontRight), 0.5);
+        assert_eq!(speed(Role::RearLeft), 0.5);
+        assert_eq!(speed(Role::RearRight), -0.5);
+    }
+}
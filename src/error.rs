@@ -1,18 +1,39 @@
 use std::error;
 use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 
+use i2cdev::linux::LinuxI2CError;
+use serde_json;
 use sysfs_gpio as gpio;
+use toml;
 
 #[derive(Debug)]
 pub enum Error {
     Gpio(gpio::Error),
     Build(BuilderError),
+    Io(io::Error),
+    Script(ScriptError),
+    Json(serde_json::Error),
+    Map(MapError),
+    Toml(toml::de::Error),
+    TomlSer(toml::ser::Error),
+    I2c(LinuxI2CError),
+    /// Something the drivetrain can't do
+    Drivetrain(&'static str),
+    /// An argument the caller should have checked
+    Argument(&'static str),
+    /// A move that was stopped before it got where it was going
+    Interrupted,
+    /// An error reading one of the robot's input files, with the file's path
+    File(PathBuf, Box<Error>),
 }
 
 #[derive(Debug)]
 pub enum BuilderError {
-    FrontMotorPins,
-    RearMotorPins,
+    MotorChannels,
+    Microsteps,
+    AdcChannel,
     FrontDistancePins,
     RearDistancePins,
     LeftDistancePins,
@@ -20,11 +41,50 @@ pub enum BuilderError {
     ExportError,
 }
 
+#[derive(Debug)]
+pub enum MapError {
+    Empty,
+    NoGoal,
+    NoPath,
+    /// A node that isn't on the path from the start, so there's no knowing which way
+    /// the robot faces there
+    OffPath(u32),
+    /// Nowhere on the map looks like what the robot sees
+    NoMatch,
+    /// An edge between these nodes, when one of them isn't on the map
+    MissingNode(u32, u32),
+}
+
+/// An error in a command script, located by 1-based line and column.
+#[derive(Debug)]
+pub struct ScriptError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Gpio(ref err) => err.fmt(f),
             Error::Build(ref err) => err.fmt(f),
+            Error::Io(ref err) => err.fmt(f),
+            Error::Script(ref err) => err.fmt(f),
+            Error::Json(ref err) => err.fmt(f),
+            Error::Map(ref err) => err.fmt(f),
+            Error::Toml(ref err) => err.fmt(f),
+            Error::TomlSer(ref err) => err.fmt(f),
+            Error::I2c(ref err) => err.fmt(f),
+            Error::Drivetrain(message) => write!(f, "Drivetrain: {}", message),
+            Error::Argument(message) => write!(f, "Invalid argument: {}", message),
+            Error::Interrupted => write!(f, "Move interrupted before it finished"),
+            // Script errors already start with the line and column
+            Error::File(ref path, ref err) => {
+                match **err {
+                    Error::Script(ref err) => write!(f, "{}:{}", path.display(), err),
+                    ref err => write!(f, "{}: {}", path.display(), err),
+                }
+            }
         }
     }
 }
@@ -34,17 +94,66 @@ impl error::Error for Error {
         match *self {
             Error::Gpio(ref err) => err.description(),
             Error::Build(ref err) => err.description(),
+            Error::Io(ref err) => err.description(),
+            Error::Script(ref err) => err.description(),
+            Error::Json(ref err) => err.description(),
+            Error::Map(ref err) => err.description(),
+            Error::Toml(ref err) => err.description(),
+            Error::TomlSer(ref err) => err.description(),
+            Error::I2c(ref err) => err.description(),
+            Error::Drivetrain(_) => "Unsupported drivetrain move",
+            Error::Argument(_) => "Invalid argument",
+            Error::Interrupted => "Move interrupted",
+            Error::File(_, ref err) => err.description(),
         }
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
             Error::Gpio(ref err) => Some(err),
             Error::Build(ref err) => Some(err),
+            Error::Io(ref err) => Some(err),
+            Error::Script(ref err) => Some(err),
+            Error::Json(ref err) => Some(err),
+            Error::Map(ref err) => Some(err),
+            Error::Toml(ref err) => Some(err),
+            Error::TomlSer(ref err) => Some(err),
+            Error::I2c(ref err) => Some(err),
+            Error::Drivetrain(_) | Error::Argument(_) | Error::Interrupted => None,
+            Error::File(_, ref err) => Some(&**err),
         }
     }
 }
 
+impl Error {
+    /// The process exit code used when this error ends the program.
+    ///
+    /// These follow the BSD `sysexits.h` conventions, so scripts driving the robot
+    /// can tell bad input apart from hardware trouble.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            // EX_USAGE
+            Error::Argument(_) => 64,
+            // EX_DATAERR
+            Error::Script(_) | Error::Json(_) | Error::Map(_) => 65,
+            // EX_UNAVAILABLE
+            Error::Gpio(_) | Error::I2c(_) => 69,
+            // EX_IOERR
+            Error::Io(_) => 74,
+            // EX_TEMPFAIL
+            Error::Interrupted => 75,
+            // EX_CONFIG
+            Error::Build(_) | Error::Toml(_) | Error::TomlSer(_) | Error::Drivetrain(_) => 78,
+            Error::File(_, ref err) => err.exit_code(),
+        }
+    }
+
+    /// Attaches the path of the file being read when this happened.
+    pub fn in_file<P: AsRef<Path>>(self, path: P) -> Error {
+        Error::File(path.as_ref().to_path_buf(), Box::new(self))
+    }
+}
+
 impl From<gpio::Error> for Error {
     fn from(err: gpio::Error) -> Error {
         Error::Gpio(err)
@@ -57,12 +166,54 @@ impl From<BuilderError> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Json(err)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Error {
+        Error::Toml(err)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(err: toml::ser::Error) -> Error {
+        Error::TomlSer(err)
+    }
+}
+
+impl From<LinuxI2CError> for Error {
+    fn from(err: LinuxI2CError) -> Error {
+        Error::I2c(err)
+    }
+}
+
+impl From<MapError> for Error {
+    fn from(err: MapError) -> Error {
+        Error::Map(err)
+    }
+}
+
+impl From<ScriptError> for Error {
+    fn from(err: ScriptError) -> Error {
+        Error::Script(err)
+    }
+}
+
 impl error::Error for BuilderError {
     fn description(&self) -> &str {
         "Error building controller"
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         None
     }
 }
@@ -72,13 +223,61 @@ impl fmt::Display for BuilderError {
         write!(f,
                "Builder: {}",
                match *self {
-                   BuilderError::FrontMotorPins => "front motor controller pins not specified",
-                   BuilderError::RearMotorPins => "rear motor controller pins not specified",
-                   BuilderError::FrontDistancePins => "front distance sensor pins not specified",
-                   BuilderError::RearDistancePins => "rear distance sensor pins not specified",
-                   BuilderError::LeftDistancePins => "left distance sensor pins not specified",
-                   BuilderError::RightDistancePins => "right distance sensor pins not specified",
+                   BuilderError::MotorChannels => {
+                       "motor channels missing or doubled up for the drivetrain's wheels"
+                   }
+                   BuilderError::Microsteps => "stepper driver can't do that many microsteps",
+                   BuilderError::AdcChannel => "Sharp IR sensor's ADC channel must be 0 to 7",
+                   BuilderError::FrontDistancePins => "front distance sensor not specified",
+                   BuilderError::RearDistancePins => "rear distance sensor not specified",
+                   BuilderError::LeftDistancePins => "left distance sensor not specified",
+                   BuilderError::RightDistancePins => "right distance sensor not specified",
                    BuilderError::ExportError => "pin export failed",
                })
     }
 }
+
+impl error::Error for MapError {
+    fn description(&self) -> &str {
+        "Invalid map"
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapError::Empty => write!(f, "Map: map has no nodes"),
+            MapError::NoGoal => write!(f, "Map: map has no goal node"),
+            MapError::NoPath => write!(f, "Map: no path from the start node to a goal node"),
+            MapError::OffPath(node) => {
+                write!(f, "Map: node {} isn't on the path from the start node", node)
+            }
+            MapError::NoMatch => {
+                write!(f, "Map: nowhere on the map matches the robot's surroundings")
+            }
+            MapError::MissingNode(from, to) => {
+                write!(f, "Map: edge ({}, {}) refers to a missing node", from, to)
+            }
+        }
+    }
+}
+
+impl error::Error for ScriptError {
+    fn description(&self) -> &str {
+        "Error parsing command script"
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
@@ -1,3 +1,5 @@
+use sysfs_gpio::Pin;
+
 /// Exports one or more GPIO pins wrapped in an `Option`.
 ///
 /// The specified pins are assumed to be fields of `$p`.
@@ -15,14 +17,15 @@ macro_rules! gpio_export {
     });
 }
 
-/// Unexports one or more GPIO pins.
+/// Unexports one or more GPIO pins, logging any that couldn't be.
 ///
 /// The specified pins are assumed to be fields of `$p`.
-// TODO: Log any Errors
 macro_rules! gpio_unexport {
     ($p: ident, {$($gpio: ident),+}) => ({
         $(
-            $p.$gpio.unexport().ok();
+            if let Err(err) = $p.$gpio.unexport() {
+                warn!("Couldn't unexport pin {}: {}", $p.$gpio.get_pin(), err);
+            }
         )+
     });
 }
@@ -37,3 +40,26 @@ macro_rules! gpio_out {
         )+
     });
 }
+
+/// Writes pins from a background loop, which has nowhere to return errors to.
+///
+/// Failures are logged, but only the first of each run of them, since the loop goes
+/// on trying many times a second.
+#[derive(Debug, Default)]
+pub struct LoggedWrites {
+    failing: bool,
+}
+
+impl LoggedWrites {
+    pub fn set_value(&mut self, pin: Pin, value: u8) {
+        match pin.set_value(value) {
+            Ok(()) => self.failing = false,
+            Err(err) => {
+                if !self.failing {
+                    warn!("Couldn't write to pin {}: {}", pin.get_pin(), err);
+                }
+                self.failing = true;
+            }
+        }
+    }
+}
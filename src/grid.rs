@@ -0,0 +1,714 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time;
+
+use serde_json;
+
+use Direction;
+use controller::DistanceVector;
+use trace::{Event, Observer};
+use super::Result;
+
+// Log-odds added to the cell a reading ends in, and to the cells it passes through
+const HIT: f32 = 0.85;
+const MISS: f32 = -0.4;
+// Cells never get more certain than this, so they can still change their mind
+const CLAMP: f32 = 5.0;
+
+// PGM shades for cells that have never been seen, like the ROS map server
+const UNKNOWN_SHADE: u8 = 205;
+
+// The most a stored, uncompressed deflate block can hold
+const STORED_BLOCK: usize = 65535;
+
+/// How the occupancy grid is laid out, and how fast the robot moves, since only a
+/// stepper drivetrain knows how far it really went.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Settings {
+    /// Centimeters along each side of a cell
+    #[serde(default = "Settings::default_resolution")]
+    pub resolution: f32,
+    /// Readings this far or further, in centimeters, didn't hit anything
+    #[serde(default = "Settings::default_max_range")]
+    pub max_range: f32,
+    /// Centimeters per second moving at a speed of 1
+    #[serde(default = "Settings::default_cm_per_second")]
+    pub cm_per_second: f32,
+    /// Degrees per second turning in place at a speed of 1
+    #[serde(default = "Settings::default_degrees_per_second")]
+    pub degrees_per_second: f32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            resolution: Settings::default_resolution(),
+            max_range: Settings::default_max_range(),
+            cm_per_second: Settings::default_cm_per_second(),
+            degrees_per_second: Settings::default_degrees_per_second(),
+        }
+    }
+}
+
+impl Settings {
+    fn default_resolution() -> f32 {
+        2.0
+    }
+
+    fn default_max_range() -> f32 {
+        200.0
+    }
+
+    fn default_cm_per_second() -> f32 {
+        30.0
+    }
+
+    fn default_degrees_per_second() -> f32 {
+        180.0
+    }
+}
+
+/// Where the robot is relative to where it started: centimeters forward and to the
+/// left of the start, and degrees turned to the left.
+#[derive(Debug, Copy, Clone, Default, Serialize)]
+pub struct Pose {
+    pub x: f32,
+    pub y: f32,
+    pub theta: f32,
+}
+
+/// A metric map of which space around the robot is free and which is occupied, built
+/// up from distance readings, with the start at the origin.
+///
+/// Each cell keeps the log-odds of being occupied, so readings can be added one at a
+/// time. Cells are only stored once a reading has reached them.
+pub struct OccupancyGrid {
+    resolution: f32,
+    max_range: f32,
+    cells: HashMap<(i32, i32), f32>,
+}
+
+impl fmt::Debug for OccupancyGrid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OccupancyGrid")
+            .field("resolution", &self.resolution)
+            .field("max_range", &self.max_range)
+            .field("cells", &self.cells.len())
+            .finish()
+    }
+}
+
+/// The grid as it's written to JSON, laid out like a ROS `OccupancyGrid`.
+#[derive(Debug, Serialize)]
+struct Export {
+    /// Centimeters along each side of a cell
+    resolution: f32,
+    width: usize,
+    height: usize,
+    /// Where the bottom left corner of the grid is, in centimeters
+    origin: (f32, f32),
+    /// Row by row from the bottom: -1 for unknown, otherwise percent likely occupied
+    occupancy: Vec<i8>,
+}
+
+impl OccupancyGrid {
+    pub fn new(resolution: f32, max_range: f32) -> OccupancyGrid {
+        OccupancyGrid {
+            resolution: resolution,
+            max_range: max_range,
+            cells: HashMap::new(),
+        }
+    }
+
+    /// Adds a reading taken at `pose`. The cell it ends in gets more likely to be
+    /// occupied, unless it's out of range, and the cells it passed through get more
+    /// likely to be free.
+    pub fn update(&mut self, pose: Pose, vector: DistanceVector) {
+        let angle = (pose.theta + vector.angle) * PI / 180.0;
+        let (dx, dy) = (angle.cos(), angle.sin());
+        let hit = vector.distance < self.max_range;
+        let length = vector.distance.max(0.0).min(self.max_range);
+        let end = self.cell(pose.x + dx * length, pose.y + dy * length);
+        // Half a cell at a time, so the ray can't skip over a cell it crosses
+        let step = self.resolution / 2.0;
+        let mut passed = vec![];
+        let mut along = 0.0;
+        while along < length {
+            let cell = self.cell(pose.x + dx * along, pose.y + dy * along);
+            if cell != end && passed.last() != Some(&cell) {
+                passed.push(cell);
+            }
+            along += step;
+        }
+        for cell in passed {
+            self.add(cell, MISS);
+        }
+        if hit {
+            self.add(end, HIT);
+        }
+    }
+
+    /// Writes the grid as a binary PGM image, with free space white, walls black and
+    /// anything unknown gray. Up is to the left of the way the robot started.
+    pub fn write_pgm<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let (width, height, pixels) = self.pixels();
+        write!(writer, "P5\n{} {}\n255\n", width, height)?;
+        writer.write_all(&pixels)
+    }
+
+    /// Writes the grid as a grayscale PNG image, shaded like `write_pgm`. An empty
+    /// grid is a single unknown pixel, since a PNG can't be empty.
+    ///
+    /// The image data isn't compressed, which keeps this short and free of
+    /// dependencies. Grids are small enough that it doesn't matter much.
+    pub fn write_png<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let (width, height, pixels) = match self.pixels() {
+            (0, _, _) | (_, 0, _) => (1, 1, vec![UNKNOWN_SHADE]),
+            image => image,
+        };
+        writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+        let mut header = vec![];
+        header.extend_from_slice(&be32(width as u32));
+        header.extend_from_slice(&be32(height as u32));
+        // 8 bit grayscale, with the only compression, filtering and interlacing
+        // methods there are
+        header.extend_from_slice(&[8, 0, 0, 0, 0]);
+        png_chunk(writer, b"IHDR", &header)?;
+        // Each row starts with the filter it uses, which is none
+        let mut rows = Vec::with_capacity((width + 1) * height);
+        for row in pixels.chunks(width) {
+            rows.push(0);
+            rows.extend_from_slice(row);
+        }
+        png_chunk(writer, b"IDAT", &zlib_stored(&rows))?;
+        png_chunk(writer, b"IEND", &[])
+    }
+
+    /// Saves the grid as JSON when the file name ends in `.json`, as a PNG image when
+    /// it ends in `.png`, or otherwise as a PGM image.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::to_writer(&mut file, &self.export())?,
+            Some("png") => self.write_png(&mut file)?,
+            _ => self.write_pgm(&mut file)?,
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    // The width and height, and each pixel's shade row by row from the top
+    fn pixels(&self) -> (usize, usize, Vec<u8>) {
+        let ((left, bottom), (width, height)) = self.bounds();
+        let mut pixels = Vec::with_capacity(width * height);
+        for row in (0..height).rev() {
+            for column in 0..width {
+                let cell = (left + column as i32, bottom + row as i32);
+                pixels.push(match self.cells.get(&cell) {
+                    Some(&odds) => ((1.0 - probability(odds)) * 255.0).round() as u8,
+                    None => UNKNOWN_SHADE,
+                });
+            }
+        }
+        (width, height, pixels)
+    }
+
+    fn export(&self) -> Export {
+        let ((left, bottom), (width, height)) = self.bounds();
+        let mut occupancy = Vec::with_capacity(width * height);
+        for row in 0..height {
+            for column in 0..width {
+                let cell = (left + column as i32, bottom + row as i32);
+                occupancy.push(self.cells
+                    .get(&cell)
+                    .map_or(-1, |&odds| (probability(odds) * 100.0).round() as i8));
+            }
+        }
+        Export {
+            resolution: self.resolution,
+            width: width,
+            height: height,
+            origin: (left as f32 * self.resolution, bottom as f32 * self.resolution),
+            occupancy: occupancy,
+        }
+    }
+
+    fn cell(&self, x: f32, y: f32) -> (i32, i32) {
+        ((x / self.resolution).floor() as i32, (y / self.resolution).floor() as i32)
+    }
+
+    fn add(&mut self, cell: (i32, i32), odds: f32) {
+        let cell = self.cells.entry(cell).or_insert(0.0);
+        *cell = (*cell + odds).max(-CLAMP).min(CLAMP);
+    }
+
+    // The bottom left cell, and the width and height in cells, of everything seen
+    fn bounds(&self) -> ((i32, i32), (usize, usize)) {
+        if self.cells.is_empty() {
+            return ((0, 0), (0, 0));
+        }
+        let xs = self.cells.keys().map(|&(x, _)| x);
+        let ys = self.cells.keys().map(|&(_, y)| y);
+        let (left, right) = (xs.clone().min().unwrap(), xs.max().unwrap());
+        let (bottom, top) = (ys.clone().min().unwrap(), ys.max().unwrap());
+        ((left, bottom), ((right - left + 1) as usize, (top - bottom + 1) as usize))
+    }
+}
+
+fn probability(odds: f32) -> f32 {
+    1.0 - 1.0 / (1.0 + odds.exp())
+}
+
+fn be32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+// A PNG chunk is its length, type, data and the CRC of its type and data
+fn png_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&be32(data.len() as u32))?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    writer.write_all(&be32(crc32(kind.iter().chain(data))))
+}
+
+fn crc32<'a, I: Iterator<Item = &'a u8>>(bytes: I) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// A zlib stream of deflate blocks that are stored as they are
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        stream.push(last as u8);
+        stream.extend_from_slice(&[length as u8, (length >> 8) as u8]);
+        stream.extend_from_slice(&[!length as u8, (!length >> 8) as u8]);
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&be32(adler32(data)));
+    stream
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// Builds an occupancy grid during a run from the fixed sensors' readings, keeping
+/// track of the robot's pose from the drivetrain calls, and saves it when the run
+/// is over.
+///
+/// The pose is only dead reckoning from how long the motors ran, so the grid drifts
+/// the further the robot goes. A stepper drivetrain's exact moves are spread over
+/// the time they'd take at the settings' speeds, and always end up a whole node
+/// distance or quarter turn away.
+pub struct Mapper {
+    path: PathBuf,
+    settings: Settings,
+    strafes: bool,
+    node_distance: f32,
+    state: Mutex<Odometry>,
+}
+
+// The robot's pose, and how fast it's moving forward, sideways to the left and
+// turning to the left since the last event
+struct Odometry {
+    grid: OccupancyGrid,
+    pose: Pose,
+    forward: f32,
+    left: f32,
+    turn: f32,
+    last: time::Instant,
+    // Seconds left of an exact move, after which the robot stops
+    exact: Option<f32>,
+    // Nothing's changed since the grid was last saved
+    saved: bool,
+}
+
+impl fmt::Debug for Mapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mapper")
+            .field("path", &self.path)
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+impl Mapper {
+    /// A mapper that saves to `path` the way `OccupancyGrid::save` does. The robot
+    /// moves `node_distance` centimeters for each exact move between nodes.
+    pub fn new<P: Into<PathBuf>>(path: P,
+                                 settings: Settings,
+                                 strafes: bool,
+                                 node_distance: f32)
+                                 -> Mapper {
+        Mapper {
+            path: path.into(),
+            settings: settings,
+            strafes: strafes,
+            node_distance: node_distance,
+            state: Mutex::new(Odometry {
+                grid: OccupancyGrid::new(settings.resolution, settings.max_range),
+                pose: Pose::default(),
+                forward: 0.0,
+                left: 0.0,
+                turn: 0.0,
+                last: time::Instant::now(),
+                exact: None,
+                saved: false,
+            }),
+        }
+    }
+
+    /// Saves the grid, unless nothing's changed since it was last saved. Failures
+    /// are logged, since this is called once the run is over, however it ended.
+    pub fn save(&self) {
+        // A panic partway through an update still leaves a grid worth saving
+        let mut odometry = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        if odometry.saved {
+            return;
+        }
+        match odometry.grid.save(&self.path) {
+            Ok(()) => {
+                odometry.saved = true;
+                info!("Saved the occupancy grid to {}, ending at {:?}",
+                      self.path.display(),
+                      odometry.pose)
+            }
+            Err(err) => warn!("Couldn't save the occupancy grid: {}", err),
+        }
+    }
+
+    // Signed forward, left and turning speeds for moving or turning in `direction`
+    fn velocity(&self, direction: Direction, speed: f32, rotating: bool) -> (f32, f32, f32) {
+        let (travel, turn) = (speed * self.settings.cm_per_second,
+                              speed * self.settings.degrees_per_second);
+        match direction {
+            Direction::Forward => (travel, 0.0, 0.0),
+            Direction::Backward => (-travel, 0.0, 0.0),
+            Direction::Left if self.strafes && !rotating => (0.0, travel, 0.0),
+            Direction::Right if self.strafes && !rotating => (0.0, -travel, 0.0),
+            Direction::Left => (0.0, 0.0, turn),
+            Direction::Right => (0.0, 0.0, -turn),
+        }
+    }
+}
+
+impl Odometry {
+    // Moves the pose along at the current speeds up to now, or to the end of an exact
+    // move if that's sooner
+    fn advance(&mut self) {
+        let now = time::Instant::now();
+        let elapsed = now.duration_since(self.last);
+        let seconds = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1e9;
+        self.last = now;
+        match self.exact {
+            Some(remaining) if remaining <= seconds => {
+                self.go(remaining);
+                self.exact = None;
+                self.set_velocity((0.0, 0.0, 0.0));
+            }
+            Some(remaining) => {
+                self.go(seconds);
+                self.exact = Some(remaining - seconds);
+            }
+            None => self.go(seconds),
+        }
+    }
+
+    fn go(&mut self, seconds: f32) {
+        // Heading halfway through, for turning while moving
+        let theta = (self.pose.theta + self.turn * seconds / 2.0) * PI / 180.0;
+        self.pose.x += (self.forward * theta.cos() - self.left * theta.sin()) * seconds;
+        self.pose.y += (self.forward * theta.sin() + self.left * theta.cos()) * seconds;
+        self.pose.theta += self.turn * seconds;
+    }
+
+    fn set_velocity(&mut self, (forward, left, turn): (f32, f32, f32)) {
+        // The drivetrain only takes another call once an exact move is over, however
+        // much faster it was than expected
+        if let Some(remaining) = self.exact.take() {
+            self.go(remaining);
+        }
+        self.forward = forward;
+        self.left = left;
+        self.turn = turn;
+    }
+}
+
+impl Observer for Mapper {
+    fn observe(&self, event: &Event) {
+        let mut odometry = self.state.lock().unwrap();
+        odometry.advance();
+        odometry.saved = false;
+        match *event {
+            Event::Reading { direction, distance } => {
+                let pose = odometry.pose;
+                odometry.grid.update(pose, DistanceVector::fixed(direction, distance));
+            }
+            Event::Start { direction, speed } => {
+                odometry.set_velocity(self.velocity(direction, speed, false))
+            }
+            Event::Rotate { direction, speed } => {
+                odometry.set_velocity(self.velocity(direction, speed, true))
+            }
+            Event::Drive { left, right } => {
+                let forward = (left + right) / 2.0 * self.settings.cm_per_second;
+                let turn = (right - left) / 2.0 * self.settings.degrees_per_second;
+                odometry.set_velocity((forward, 0.0, turn));
+            }
+            Event::MoveExactly { direction, speed } => {
+                // Steppers turn in place for exact moves, even if they could strafe
+                let velocity = self.velocity(direction, speed, true);
+                let seconds = match direction {
+                    Direction::Forward | Direction::Backward => {
+                        self.node_distance / velocity.0.abs()
+                    }
+                    Direction::Left | Direction::Right => 90.0 / velocity.2.abs(),
+                };
+                odometry.set_velocity(velocity);
+                if seconds.is_finite() {
+                    odometry.exact = Some(seconds);
+                }
+            }
+            Event::Stop { .. } => odometry.set_velocity((0.0, 0.0, 0.0)),
+            Event::Command { .. } |
+            Event::SensorError { .. } |
+            Event::DriveWheel { .. } => {}
+        }
+    }
+
+    fn finish(&self) {
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use Direction;
+    use controller::DistanceVector;
+    use motor;
+    use trace::{Event, Observer};
+    use super::{HIT, MISS, STORED_BLOCK, UNKNOWN_SHADE, Mapper, OccupancyGrid, Pose, Settings,
+                adler32, crc32, zlib_stored};
+
+    // The data in each of a PNG's chunks, checking their CRCs
+    fn chunks(png: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let mut chunks = vec![];
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let length = rest[..4].iter().fold(0, |n, &byte| n << 8 | byte as usize);
+            let (kind, data) = (&rest[4..8], &rest[8..8 + length]);
+            let crc = rest[8 + length..12 + length].iter().fold(0, |n, &byte| n << 8 | byte as u32);
+            assert_eq!(crc, crc32(kind.iter().chain(data)));
+            chunks.push((String::from_utf8(kind.to_vec()).unwrap(), data.to_vec()));
+            rest = &rest[12 + length..];
+        }
+        chunks
+    }
+
+    // What a zlib stream of stored blocks holds
+    fn unstore(stream: &[u8]) -> Vec<u8> {
+        assert_eq!(&stream[..2], &[0x78, 0x01]);
+        let mut data = vec![];
+        let mut rest = &stream[2..];
+        loop {
+            let length = rest[1] as usize | (rest[2] as usize) << 8;
+            assert_eq!(length, !(rest[3] as usize | (rest[4] as usize) << 8) & 0xffff);
+            data.extend_from_slice(&rest[5..5 + length]);
+            let last = rest[0] == 1;
+            rest = &rest[5 + length..];
+            if last {
+                break;
+            }
+        }
+        let adler = rest.iter().fold(0, |n, &byte| n << 8 | byte as u32);
+        assert_eq!(adler, adler32(&data));
+        data
+    }
+
+
+    // In the middle of the cell at the origin, so rays along an axis stay in its row
+    // or column
+    fn middle() -> Pose {
+        Pose {
+            x: 1.0,
+            y: 1.0,
+            theta: 0.0,
+        }
+    }
+
+    // Open for 10cm ahead and 4cm to the left
+    fn corner() -> OccupancyGrid {
+        let mut grid = OccupancyGrid::new(2.0, 200.0);
+        grid.update(middle(), DistanceVector::fixed(Direction::Forward, 10.0));
+        grid.update(middle(), DistanceVector::fixed(Direction::Left, 4.0));
+        grid
+    }
+
+    #[test]
+    fn readings_clear_the_way_to_what_they_hit() {
+        let mut grid = OccupancyGrid::new(2.0, 200.0);
+        grid.update(middle(), DistanceVector::fixed(Direction::Forward, 10.0));
+        for x in 0..5 {
+            assert_eq!(grid.cells[&(x, 0)], MISS);
+        }
+        assert_eq!(grid.cells[&(5, 0)], HIT);
+        assert_eq!(grid.cells.len(), 6);
+    }
+
+    #[test]
+    fn readings_out_of_range_hit_nothing() {
+        let mut grid = OccupancyGrid::new(2.0, 20.0);
+        grid.update(middle(), DistanceVector::fixed(Direction::Forward, 50.0));
+        assert!(grid.cells.values().all(|&odds| odds == MISS));
+        assert_eq!(grid.cells.len(), 10);
+    }
+
+    #[test]
+    fn pgm_is_drawn_from_the_top() {
+        let mut pgm = vec![];
+        corner().write_pgm(&mut pgm).unwrap();
+        let header = b"P5\n6 3\n255\n";
+        assert_eq!(&pgm[..header.len()], &header[..]);
+        let pixels = &pgm[header.len()..];
+        assert_eq!(pixels.len(), 6 * 3);
+        // The wall to the left is in the top row, and the wall ahead in the bottom one
+        let (wall, unknown) = (pixels[0], pixels[1]);
+        assert_eq!(unknown, UNKNOWN_SHADE);
+        assert_eq!(pixels[17], wall);
+        let free = pixels[6];
+        assert!(wall < free && free < unknown);
+        // Both readings passed through the start
+        assert!(pixels[12] > free);
+    }
+
+    #[test]
+    fn png_has_the_same_pixels_as_pgm() {
+        let mut pgm = vec![];
+        corner().write_pgm(&mut pgm).unwrap();
+        let mut png = vec![];
+        corner().write_png(&mut png).unwrap();
+        let chunks = chunks(&png);
+        let kinds = chunks.iter().map(|&(ref kind, _)| kind.as_str()).collect::<Vec<_>>();
+        assert_eq!(kinds, vec!["IHDR", "IDAT", "IEND"]);
+        assert_eq!(chunks[0].1, vec![0, 0, 0, 6, 0, 0, 0, 3, 8, 0, 0, 0, 0]);
+        let rows = unstore(&chunks[1].1);
+        assert_eq!(rows.len(), 7 * 3);
+        for (row, pixels) in rows.chunks(7).zip(pgm[pgm.len() - 18..].chunks(6)) {
+            assert_eq!(row[0], 0);
+            assert_eq!(&row[1..], pixels);
+        }
+    }
+
+    #[test]
+    fn an_empty_png_is_one_unknown_pixel() {
+        let mut png = vec![];
+        OccupancyGrid::new(2.0, 200.0).write_png(&mut png).unwrap();
+        let chunks = chunks(&png);
+        assert_eq!(chunks[0].1, vec![0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]);
+        assert_eq!(unstore(&chunks[1].1), vec![0, UNKNOWN_SHADE]);
+    }
+
+    #[test]
+    fn checksums() {
+        // The check values from the CRC and zlib specifications
+        assert_eq!(crc32(b"123456789".iter()), 0xcbf4_3926);
+        assert_eq!(crc32(b"IEND".iter()), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn long_data_is_stored_in_blocks() {
+        let data = (0..STORED_BLOCK * 2 + 10).map(|i| i as u8).collect::<Vec<_>>();
+        let stream = zlib_stored(&data);
+        assert_eq!(stream.len(), 2 + 3 * 5 + data.len() + 4);
+        assert_eq!(unstore(&stream), data);
+        assert!(unstore(&zlib_stored(&[])).is_empty());
+    }
+
+    #[test]
+    fn saves_once_until_it_changes() {
+        let path = env::temp_dir().join(format!("rsbot-saved-grid-{}.png", process::id()));
+        let mapper = Mapper::new(&path, Settings::default(), false, 30.0);
+        mapper.observe(&Event::Reading {
+            direction: Direction::Forward,
+            distance: 10.0,
+        });
+        mapper.finish();
+        assert_eq!(&fs::read(&path).unwrap()[1..4], b"PNG");
+        // The run ending saves it again, but there's nothing new
+        fs::remove_file(&path).unwrap();
+        mapper.save();
+        assert!(!path.exists());
+        mapper.observe(&Event::Reading {
+            direction: Direction::Left,
+            distance: 10.0,
+        });
+        mapper.save();
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn export_is_percent_occupied_from_the_bottom() {
+        let export = corner().export();
+        assert_eq!((export.width, export.height), (6, 3));
+        assert_eq!(export.origin, (0.0, 0.0));
+        assert_eq!(&export.occupancy[..6], &[31, 40, 40, 40, 40, 70]);
+        assert_eq!(&export.occupancy[6..8], &[40, -1]);
+        assert_eq!(export.occupancy[12], 70);
+    }
+
+    #[test]
+    fn exact_moves_take_time() {
+        let path = env::temp_dir().join(format!("rsbot-grid-{}.pgm", process::id()));
+        let mapper = Mapper::new(path, Settings::default(), false, 30.0);
+        mapper.observe(&Event::MoveExactly {
+            direction: Direction::Forward,
+            speed: 1.0,
+        });
+        mapper.observe(&Event::Reading {
+            direction: Direction::Forward,
+            distance: 100.0,
+        });
+        // A second at 30cm a second, so the reading was taken near the start
+        assert!(mapper.state.lock().unwrap().pose.x < 15.0);
+        mapper.observe(&Event::MoveExactly {
+            direction: Direction::Left,
+            speed: 1.0,
+        });
+        mapper.observe(&Event::Stop { stop: motor::Stop::default() });
+        let pose = mapper.state.lock().unwrap().pose;
+        assert!((pose.x - 30.0).abs() < 1e-3);
+        assert!(pose.y.abs() < 1e-3);
+        assert!((pose.theta - 90.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,203 @@
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time;
+
+use log::{self, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+
+/// How log records are written to stderr.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    /// One line per record, for reading as it runs
+    Text,
+    /// One JSON object per line, for analyzing afterward
+    Json,
+}
+
+/// Which records get logged: a default level, and levels for particular modules.
+///
+/// Written like `info,distance=trace,motor=debug`, where modules are the top level
+/// modules such as `controller`, `distance`, `motor` and `map`, and also cover the
+/// modules inside them.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    level: LogLevelFilter,
+    modules: Vec<(String, LogLevelFilter)>,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let mut filter = Filter {
+            level: LogLevelFilter::Info,
+            modules: vec![],
+        };
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let level = |level: &str| {
+                LogLevelFilter::from_str(level)
+                    .map_err(|_| format!("`{}` isn't a log level", level))
+            };
+            match part.find('=') {
+                Some(i) => filter.modules.push((part[..i].to_string(), level(&part[i + 1..])?)),
+                None => filter.level = level(part)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl Filter {
+    fn level(&self, target: &str) -> LogLevelFilter {
+        // The most specific module wins
+        self.modules
+            .iter()
+            .filter(|&&(ref module, _)| {
+                target == module ||
+                (target.starts_with(module.as_str()) && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|&&(ref module, _)| module.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    fn max(&self) -> LogLevelFilter {
+        self.modules.iter().map(|&(_, level)| level).fold(self.level, |a, b| a.max(b))
+    }
+}
+
+// Records held back while something else has the terminal, up to `HELD_LINES`
+static HOLDING: AtomicBool = AtomicBool::new(false);
+static HELD: Mutex<Vec<String>> = Mutex::new(Vec::new());
+const HELD_LINES: usize = 1000;
+
+struct Logger {
+    filter: Filter,
+    format: Format,
+}
+
+// Records are targeted at their module path, which all start with the crate's name
+fn target(record_target: &str) -> &str {
+    record_target.trim_start_matches(concat!(env!("CARGO_PKG_NAME"), "::"))
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.filter.level(target(metadata.target()))
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let target = target(record.target());
+        let line = match self.format {
+            Format::Text => format!("{:5} {}: {}", record.level(), target, record.args()),
+            Format::Json => {
+                let now = time::SystemTime::now()
+                    .duration_since(time::UNIX_EPOCH)
+                    .unwrap_or_default();
+                json!({
+                    "time": now.as_secs() as f64 + now.subsec_nanos() as f64 / 1e9,
+                    "level": record.level().to_string(),
+                    "target": target,
+                    "message": record.args().to_string(),
+                })
+                    .to_string()
+            }
+        };
+        if HOLDING.load(Ordering::SeqCst) {
+            let mut held = HELD.lock().unwrap_or_else(|err| err.into_inner());
+            if held.len() < HELD_LINES {
+                held.push(line);
+                return;
+            }
+        }
+        // There's nowhere left to report a failed write to
+        writeln!(io::stderr(), "{}", line).ok();
+    }
+}
+
+/// Holds back log records until it's dropped, then writes them out, such as while
+/// the terminal is in raw mode and they'd be drawn over the screen. If too many
+/// pile up, the rest are written straight away.
+#[derive(Debug)]
+pub struct Hold(());
+
+impl Hold {
+    pub fn new() -> Hold {
+        HOLDING.store(true, Ordering::SeqCst);
+        Hold(())
+    }
+}
+
+impl Drop for Hold {
+    fn drop(&mut self) {
+        HOLDING.store(false, Ordering::SeqCst);
+        let mut held = HELD.lock().unwrap_or_else(|err| err.into_inner());
+        let mut stderr = io::stderr();
+        for line in held.drain(..) {
+            writeln!(stderr, "{}", line).ok();
+        }
+    }
+}
+
+/// Sends log records to stderr for the rest of the run.
+pub fn init(filter: Filter, format: Format) -> Result<(), SetLoggerError> {
+    log::set_logger(|max_level| {
+        max_level.set(filter.max());
+        Box::new(Logger {
+            filter: filter,
+            format: format,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use log::LogLevelFilter;
+
+    use super::{Filter, target};
+
+    #[test]
+    fn levels_for_modules_and_everything_else() {
+        let filter = "warn, distance=trace,controller::remote=debug".parse::<Filter>().unwrap();
+        assert_eq!(filter.level("map"), LogLevelFilter::Warn);
+        assert_eq!(filter.level("distance"), LogLevelFilter::Trace);
+        assert_eq!(filter.level("distance::sample"), LogLevelFilter::Trace);
+        assert_eq!(filter.level("controller"), LogLevelFilter::Warn);
+        assert_eq!(filter.level("controller::remote"), LogLevelFilter::Debug);
+        // Only whole module names match
+        assert_eq!(filter.level("distances"), LogLevelFilter::Warn);
+        assert_eq!(filter.max(), LogLevelFilter::Trace);
+    }
+
+    #[test]
+    fn the_most_specific_module_wins() {
+        let filter = "controller::remote=error,controller=debug".parse::<Filter>().unwrap();
+        assert_eq!(filter.level("controller::remote"), LogLevelFilter::Error);
+        assert_eq!(filter.level("controller::queue"), LogLevelFilter::Debug);
+    }
+
+    #[test]
+    fn defaults_to_info() {
+        let filter = "motor=off".parse::<Filter>().unwrap();
+        assert_eq!(filter.level("map"), LogLevelFilter::Info);
+        assert_eq!(filter.level("motor"), LogLevelFilter::Off);
+        assert_eq!("".parse::<Filter>().unwrap().level("map"), LogLevelFilter::Info);
+    }
+
+    #[test]
+    fn rejects_unknown_levels() {
+        assert_eq!("loud".parse::<Filter>().unwrap_err(),
+                   "`loud` isn't a log level");
+        assert_eq!("info,map=verbose".parse::<Filter>().unwrap_err(),
+                   "`verbose` isn't a log level");
+    }
+
+    #[test]
+    fn targets_drop_the_crate_name() {
+        assert_eq!(target("rsbot::controller::queue"), "controller::queue");
+        assert_eq!(target("rsbot"), "rsbot");
+    }
+}
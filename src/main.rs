@@ -1,5 +1,10 @@
+#[macro_use]
+extern crate clap;
 extern crate futures;
 extern crate futures_cpupool;
+extern crate i2cdev;
+#[macro_use]
+extern crate log;
 extern crate pathfinding;
 extern crate petgraph;
 extern crate retry;
@@ -8,26 +13,53 @@ extern crate serde;
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
+extern crate spidev;
 extern crate sysfs_gpio;
+extern crate termion;
+extern crate toml;
 
-use std::env;
 use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::net;
 use std::path::Path;
+use std::process;
 use std::result;
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use futures::Future;
 
 #[macro_use]
 mod gpio;
+#[macro_use]
+mod str_enum;
 
 pub mod controller;
 
+mod cli;
+mod config;
 mod distance;
+mod drivetrain;
 mod error;
+mod grid;
+mod logger;
 mod map;
 mod motor;
+mod pid;
+mod pwm;
+mod script;
+mod servo;
+mod state;
+mod stepper;
+mod telemetry;
+mod teleop;
+mod threshold;
+mod trace;
 
 type Result<T> = result::Result<T, error::Error>;
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Direction {
     Forward,
     Backward,
@@ -37,34 +69,400 @@ pub enum Direction {
 
 // Move commands work the following way:
 // Move(Forward|Backward) => move forward or backward until next node
-// Move(Left|Right) => turn 90 degrees in the given direction
-#[derive(Debug, Copy, Clone)]
+// Move(Left|Right) => turn 90 degrees in the given direction, or strafe to the next node
+//                     on a drivetrain that can slide sideways
+// MoveFor(d, t) => move in the given direction for a fixed duration
+// MoveUntilWall(d) => move until the sensor facing `d` is blocked, ignoring side openings
+//
+// SetThresholds applies its overrides on top of the robot's and map's thresholds for all
+// following moves, replacing any earlier overrides.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Command {
     Move(Direction),
+    MoveFor(Direction, time::Duration),
+    MoveUntilWall(Direction),
+    Wait(time::Duration),
+    Distance(Direction),
+    SetThresholds(threshold::Overrides),
     Stop,
 }
 
 fn main() {
-    let mapfile = env::args().nth(1).expect("Need a link to a map file");
-    let map = read_map(mapfile).unwrap();
-    println!("{:?}", map);
-    println!("{:#?}", map.path());
-    println!("{:#?}", map.path().into_commands());
-    let mut controller = controller::Builder::new()
-        .front_motor_pins(2, 3, 4, 22, 17, 27)
-        .rear_motor_pins(10, 9, 11, 19, 5, 6)
-        .front_distance_pins(14, 15)
-        .rear_distance_pins(18, 23)
-        .left_distance_pins(24, 25)
-        .right_distance_pins(8, 7)
-        .map(map)
-        .build()
-        .unwrap();
-    controller.run().unwrap();
-}
-
-fn read_map<P: AsRef<Path>>(path: P) -> serde_json::Result<map::Map> {
-    let file = fs::File::open(path)?;
-    let map = serde_json::from_reader(file)?;
-    Ok(map)
+    let matches = cli::app().get_matches();
+    let robot = global_arg(&matches, "robot");
+    let filter = global_arg(&matches, "log-level").parse().unwrap();
+    let format = match global_arg(&matches, "log-format") {
+        "json" => logger::Format::Json,
+        _ => logger::Format::Text,
+    };
+    logger::init(filter, format).unwrap();
+    let result = match matches.subcommand() {
+        ("run", Some(m)) => {
+            observed(robot, m).and_then(|(b, _grid)| run(b, m.value_of("MAP").unwrap(), m))
+        }
+        ("plan", Some(m)) => plan(m.value_of("MAP").unwrap()),
+        ("validate", Some(m)) => validate(m.value_of("MAP").unwrap()),
+        ("script", Some(m)) => {
+            observed(robot, m).and_then(|(b, _grid)| script(b, m.value_of("SCRIPT").unwrap(), m))
+        }
+        ("replay", Some(m)) => {
+            observed(robot, m).and_then(|(b, _grid)| {
+                replay(b,
+                       m.value_of("TRACE").unwrap(),
+                       m.value_of("MAP"),
+                       m.value_of("script"),
+                       m)
+            })
+        }
+        ("remote", Some(m)) => {
+            let heartbeat = value_t!(m, "heartbeat", u64).unwrap_or_else(|e| e.exit());
+            observed(robot, m).and_then(|(b, _grid)| {
+                remote(b,
+                       m.value_of("listen").unwrap(),
+                       time::Duration::from_millis(heartbeat))
+            })
+        }
+        ("teleop", Some(m)) => {
+            let step = value_t!(m, "step", u64).unwrap_or_else(|e| e.exit());
+            observed(robot, m).and_then(|(b, _grid)| teleop(b, time::Duration::from_millis(step)))
+        }
+        ("sensors", Some(m)) => {
+            let interval = value_t!(m, "interval", u64).unwrap_or_else(|e| e.exit());
+            sensors(robot, time::Duration::from_millis(interval))
+        }
+        ("motors", Some(m)) => {
+            match m.subcommand() {
+                ("test", Some(m)) => {
+                    let duration = value_t!(m, "duration", u64).unwrap_or_else(|e| e.exit());
+                    motors_test(robot, time::Duration::from_millis(duration))
+                }
+                _ => unreachable!(),
+            }
+        }
+        ("calibrate", Some(m)) => {
+            let samples = value_t!(m, "samples", usize).unwrap_or_else(|e| e.exit());
+            let turn = value_t!(m, "turn", u64).unwrap_or_else(|e| e.exit());
+            let steps = value_t!(m, "steps", u32).unwrap_or_else(|e| e.exit());
+            calibrate(robot,
+                      samples.max(1),
+                      time::Duration::from_millis(turn),
+                      steps,
+                      m.is_present("dry-run"))
+        }
+        ("scan", Some(m)) => {
+            let from = value_t!(m, "from", f32).unwrap_or_else(|e| e.exit());
+            let to = value_t!(m, "to", f32).unwrap_or_else(|e| e.exit());
+            let step = value_t!(m, "step", f32).unwrap_or_else(|e| e.exit());
+            scan(robot, from, to, step)
+        }
+        ("localize", Some(m)) => {
+            let confidence = value_t!(m, "confidence", f32).unwrap_or_else(|e| e.exit());
+            let max_moves = value_t!(m, "max-moves", usize).unwrap_or_else(|e| e.exit());
+            localize(robot,
+                     m.value_of("MAP").unwrap(),
+                     confidence,
+                     max_moves,
+                     m.value_of("state"))
+        }
+        ("explore", Some(m)) => {
+            let max_nodes = value_t!(m, "max-nodes", usize).unwrap_or_else(|e| e.exit());
+            explore(robot, m.value_of("output"), max_nodes)
+        }
+        _ => unreachable!(),
+    };
+    if let Err(err) = result {
+        writeln!(io::stderr(), "rsbot: {}", err).ok();
+        process::exit(err.exit_code());
+    }
+}
+
+// Global options like `--robot` can be given after any subcommand, so the value given
+// closest to the end of the command line wins
+fn global_arg<'a>(matches: &'a clap::ArgMatches, name: &str) -> &'a str {
+    match matches.subcommand() {
+        (_, Some(m)) => global_arg(m, name),
+        _ => matches.value_of(name).unwrap(),
+    }
+}
+
+fn builder(robot: &str) -> Result<controller::Builder> {
+    let config = config::RobotConfig::load(robot)?;
+    Ok(controller::Builder::from_config(&config))
+}
+
+fn run(builder: controller::Builder, mapfile: &str, matches: &clap::ArgMatches) -> Result<()> {
+    let map = read_map(mapfile)?;
+    let statefile = matches.value_of("state").unwrap();
+    let mut builder = builder.map(map).state_file(statefile);
+    if matches.is_present("resume") {
+        let state = state::RunState::load(statefile)?;
+        if state.finished {
+            println!("The run saved in {} already finished, so there's nothing left to do",
+                     statefile);
+            return Ok(());
+        }
+        builder = builder.resume(state);
+    }
+    let mut controller = builder.build()?;
+    run_commands(&mut controller, matches)
+}
+
+fn plan(mapfile: &str) -> Result<()> {
+    let map = read_map(mapfile)?;
+    let path = map.path()?;
+    println!("{:#?}", path);
+    println!("{:#?}", path.into_commands());
+    Ok(())
+}
+
+fn validate(mapfile: &str) -> Result<()> {
+    let map = read_map(mapfile)?;
+    map.validate()?;
+    println!("{}: ok", mapfile);
+    Ok(())
+}
+
+fn script(builder: controller::Builder,
+          scriptfile: &str,
+          matches: &clap::ArgMatches)
+          -> Result<()> {
+    let commands = load_script(scriptfile)?;
+    let mut controller = builder.commands(commands).build()?;
+    run_commands(&mut controller, matches)
+}
+
+fn replay(builder: controller::Builder,
+          tracefile: &str,
+          mapfile: Option<&str>,
+          scriptfile: Option<&str>,
+          matches: &clap::ArgMatches)
+          -> Result<()> {
+    let mut builder = builder.replay(trace::Trace::load(tracefile)?);
+    builder = match (mapfile, scriptfile) {
+        (_, Some(scriptfile)) => builder.commands(load_script(scriptfile)?),
+        (Some(mapfile), None) => builder.map(read_map(mapfile)?),
+        (None, None) => unreachable!(),
+    };
+    run_commands(&mut builder.build()?, matches)
+}
+
+// Sets up `--control`, `--keep-open` and `--step` before running the controller's
+// commands
+fn run_commands(controller: &mut controller::Controller, matches: &clap::ArgMatches) -> Result<()> {
+    if let Some(address) = matches.value_of("control") {
+        controller.serve_control(net::TcpListener::bind(address)?)?;
+    }
+    if matches.is_present("keep-open") {
+        controller.queue().keep_open();
+    }
+    if matches.is_present("step") {
+        step_through(controller.queue());
+    }
+    controller.run()
+}
+
+// Asks on the terminal before each command runs
+fn step_through(queue: controller::Queue) {
+    queue.pause();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        while let Some(next) = queue.wait_upcoming() {
+            let node = next.node().map_or(String::new(), |node| format!(" from node {}", node));
+            print!("Next is #{} {:?}{}. Enter runs it, c continues and q quits: ",
+                   next.index(),
+                   next.command(),
+                   node);
+            io::stdout().flush().ok();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                // Nobody's there to confirm the rest
+                queue.close();
+                queue.clear();
+                break;
+            }
+            match line.trim() {
+                "" => {
+                    queue.step();
+                    next.wait();
+                }
+                "c" => {
+                    queue.resume();
+                    break;
+                }
+                "q" => {
+                    queue.close();
+                    queue.clear();
+                    break;
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+fn remote(builder: controller::Builder, address: &str, heartbeat: time::Duration) -> Result<()> {
+    let listener = net::TcpListener::bind(address)?;
+    builder.build()?.remote(listener, heartbeat)
+}
+
+fn teleop(builder: controller::Builder, step: time::Duration) -> Result<()> {
+    let mut controller = builder.build()?;
+    teleop::run(&mut controller, step)
+}
+
+fn sensors(robot: &str, interval: time::Duration) -> Result<()> {
+    let controller = builder(robot)?.build()?;
+    let readings = controller.readings();
+    loop {
+        let mut line = vec![];
+        for &direction in &[Direction::Forward,
+                            Direction::Backward,
+                            Direction::Left,
+                            Direction::Right] {
+            let reading = readings.next(direction)?;
+            line.push(format!("{:?}: {:7.2}", reading.direction, reading.distance));
+        }
+        println!("{}", line.join("  "));
+        thread::sleep(interval);
+    }
+}
+
+fn scan(robot: &str, from: f32, to: f32, step: f32) -> Result<()> {
+    let mut controller = builder(robot)?.build()?;
+    for vector in controller.scan(from, to, step).wait()? {
+        println!("{:7.1}: {:7.2}", vector.angle, vector.distance);
+    }
+    Ok(())
+}
+
+fn motors_test(robot: &str, duration: time::Duration) -> Result<()> {
+    let mut controller = builder(robot)?.build()?;
+    // One wheel at a time first, so a wheel spinning backwards or on the wrong
+    // side is easy to spot
+    for wheel in controller.wheels() {
+        println!("Driving {:?} wheel forward", wheel);
+        controller.pulse_wheel(wheel, duration).wait()?;
+        thread::sleep(time::Duration::from_millis(500));
+    }
+    for &direction in &[Direction::Forward,
+                        Direction::Backward,
+                        Direction::Left,
+                        Direction::Right] {
+        println!("Driving {:?}", direction);
+        controller.travel_for(direction, duration).wait()?;
+        thread::sleep(time::Duration::from_millis(500));
+    }
+    Ok(())
+}
+
+fn calibrate(robot: &str,
+             samples: usize,
+             turn: time::Duration,
+             steps: u32,
+             dry_run: bool)
+             -> Result<()> {
+    let mut config = config::RobotConfig::load(robot)?;
+    let calibration = {
+        let mut controller = controller::Builder::from_config(&config).build()?;
+        controller.calibrate(samples, turn, steps)?
+    };
+    println!("{:#?}", calibration);
+    if !dry_run {
+        config.thresholds = calibration.thresholds;
+        config.save(robot)?;
+        println!("Saved thresholds to {}", robot);
+    }
+    Ok(())
+}
+
+fn explore(robot: &str, output: Option<&str>, max_nodes: usize) -> Result<()> {
+    let mut controller = builder(robot)?.build()?;
+    let map = controller.explore(max_nodes)?;
+    match output {
+        Some(path) => serde_json::to_writer_pretty(&mut fs::File::create(path)?, &map)?,
+        None => println!("{}", serde_json::to_string_pretty(&map)?),
+    }
+    Ok(())
+}
+
+fn localize(robot: &str,
+            mapfile: &str,
+            confidence: f32,
+            max_moves: usize,
+            statefile: Option<&str>)
+            -> Result<()> {
+    let map = read_map(mapfile)?;
+    let mut controller = builder(robot)?.build()?;
+    let candidates = controller.localize(&map, confidence, max_moves)?;
+    for candidate in candidates.iter().take_while(|c| c.probability >= 0.01) {
+        println!("node {:3} facing {:8} {:5.1}%",
+                 candidate.node,
+                 format!("{:?}", map::QUARTERS[candidate.heading as usize]),
+                 candidate.probability * 100.0);
+    }
+    if let (Some(statefile), Some(best)) = (statefile, candidates.first()) {
+        let state = state::RunState {
+            node: Some(best.node),
+            heading: best.heading,
+            index: 0,
+            finished: false,
+        };
+        state.save(statefile)?;
+        println!("Saved node {} to {}", best.node, statefile);
+    }
+    Ok(())
+}
+
+fn read_map<P: AsRef<Path>>(path: P) -> Result<map::Map> {
+    let path = path.as_ref();
+    let read = || -> Result<map::Map> { Ok(serde_json::from_reader(fs::File::open(path)?)?) };
+    read().map_err(|err| err.in_file(path))
+}
+
+// Saves the `--grid` map once the subcommand is over, even if it failed or
+// panicked, or the controller was never dropped
+struct SaveGrid(Option<Arc<grid::Mapper>>);
+
+impl Drop for SaveGrid {
+    fn drop(&mut self) {
+        if let Some(ref mapper) = self.0 {
+            mapper.save();
+        }
+    }
+}
+
+// A builder that also sends what happens to `--trace`, `--telemetry` and `--grid`
+fn observed(robot: &str,
+            matches: &clap::ArgMatches)
+            -> Result<(controller::Builder, SaveGrid)> {
+    let config = config::RobotConfig::load(robot)?;
+    let mut builder = controller::Builder::from_config(&config);
+    let mut grid = SaveGrid(None);
+    if let Some(trace) = matches.value_of("trace") {
+        builder = builder.observer(Arc::new(trace::Recorder::create(trace)?));
+    }
+    if let Some(address) = matches.value_of("telemetry") {
+        builder = builder.observer(telemetry::Telemetry::serve(address)?);
+    }
+    if let Some(path) = matches.value_of("grid") {
+        // Node spacing is in millimeters, like the rest of the stepper settings
+        let mapper = Arc::new(grid::Mapper::new(path,
+                                                config.grid,
+                                                config.drivetrain.strafes(),
+                                                config.stepper.node_distance / 10.0));
+        builder = builder.observer(mapper.clone());
+        grid.0 = Some(mapper);
+    }
+    Ok((builder, grid))
+}
+
+fn load_script(scriptfile: &str) -> Result<Vec<Command>> {
+    read_script(scriptfile).map_err(|err| err.in_file(scriptfile))
+}
+
+fn read_script<P: AsRef<Path>>(path: P) -> Result<Vec<Command>> {
+    let mut source = String::new();
+    fs::File::open(path)?.read_to_string(&mut source)?;
+    let commands = script::parse(&source)?;
+    Ok(commands)
 }
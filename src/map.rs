@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::slice;
 
@@ -10,56 +11,178 @@ use serde::de;
 use serde::ser::SerializeStruct;
 
 use {Command, Direction};
+use error::MapError;
+use threshold::Overrides;
 
 #[derive(Debug, Default)]
-pub struct Map(petgraph::Graph<bool, Direction, petgraph::Undirected>);
+pub struct Map {
+    graph: petgraph::Graph<bool, Direction, petgraph::Undirected>,
+    // Threshold overrides for the whole map, and for single edges on top of those
+    thresholds: Overrides,
+    edge_thresholds: HashMap<graph::EdgeIndex, Overrides>,
+}
 
 impl Map {
-    fn from_nodes_edges(nodes: Vec<bool>, edges: Vec<Edge<Direction>>) -> Self {
+    /// A map with a node for each goal flag, joined by `edges`, which have to be
+    /// between nodes that are on it.
+    pub fn from_nodes_edges(nodes: Vec<bool>,
+                            edges: Vec<Edge<Direction>>)
+                            -> Result<Map, MapError> {
+        // The graph would otherwise panic, or silently add nodes for these
+        let node_count = nodes.len() as u32;
+        if let Some(edge) = edges.iter()
+            .find(|e| e.nodes.0 >= node_count || e.nodes.1 >= node_count) {
+            return Err(MapError::MissingNode(edge.nodes.0, edge.nodes.1));
+        }
         let mut graph = petgraph::Graph::with_capacity(nodes.len(), edges.len());
         for n in nodes {
             graph.add_node(n);
         }
-        graph.extend_with_edges(edges);
-        Map(graph)
+        let mut edge_thresholds = HashMap::new();
+        for edge in edges {
+            let index = graph.add_edge(graph::NodeIndex::new(edge.nodes.0 as usize),
+                                       graph::NodeIndex::new(edge.nodes.1 as usize),
+                                       edge.weight);
+            if let Some(thresholds) = edge.thresholds {
+                edge_thresholds.insert(index, thresholds);
+            }
+        }
+        Ok(Map {
+            graph: graph,
+            thresholds: Overrides::default(),
+            edge_thresholds: edge_thresholds,
+        })
     }
 
     fn nodes(&self) -> NodeWeights<bool> {
-        NodeWeights { nodes: self.0.raw_nodes().iter() }
+        NodeWeights { nodes: self.graph.raw_nodes().iter() }
     }
 
     fn edges(&self) -> graph::EdgeReferences<Direction> {
-        self.0.edge_references()
+        self.graph.edge_references()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.graph.node_count() == 0
+    }
+
+    /// Threshold overrides that apply everywhere on this map.
+    pub fn thresholds(&self) -> Overrides {
+        self.thresholds
+    }
+
+    /// Checks that the map can be pathed, without computing the path.
+    pub fn validate(&self) -> Result<(), MapError> {
+        if self.is_empty() {
+            return Err(MapError::Empty);
+        }
+        if !self.nodes().any(|&goal| goal) {
+            return Err(MapError::NoGoal);
+        }
+        self.path().map(|_| ())
+    }
+
+    pub fn path(&self) -> Result<Path, MapError> {
+        if self.is_empty() {
+            return Err(MapError::Empty);
+        }
+        let edges = self.path_between(graph::NodeIndex::new(0))?;
+        Ok(Path {
+            edges: edges,
+            heading: 0,
+            facing: 0,
+        })
+    }
+
+    /// The path to a goal from somewhere along the path from the start, such as after
+    /// a restart partway through a run, for a robot facing `facing` quarter turns
+    /// clockwise from the way it started.
+    pub fn path_from(&self, node: u32, facing: u8) -> Result<Path, MapError> {
+        let path = self.path()?;
+        let heading = path.arrivals()
+            .into_iter()
+            .find(|&(n, _)| n == node)
+            .map(|(_, heading)| heading)
+            .ok_or(MapError::OffPath(node))?;
+        let edges = self.path_between(graph::NodeIndex::new(node as usize))?;
+        Ok(Path {
+            edges: edges,
+            heading: heading,
+            facing: facing % 4,
+        })
+    }
+
+    /// Where each node's edges lead, by node and then by the way out, in quarter turns
+    /// clockwise from the way the robot faces at the start node.
+    ///
+    /// Nodes that can't be reached from the start are laid out as if the robot had
+    /// started from the first of them instead.
+    pub fn exits(&self) -> Vec<[Option<u32>; 4]> {
+        let count = self.graph.node_count();
+        let mut neighbors = vec![vec![]; count];
+        for edge in self.edges() {
+            let (source, target) = (edge.source().index(), edge.target().index());
+            neighbors[source].push((target, *edge.weight(), true));
+            neighbors[target].push((source, *edge.weight(), false));
+        }
+        let mut headings = vec![None; count];
+        let mut exits = vec![[None; 4]; count];
+        for start in 0..count {
+            if headings[start].is_some() {
+                continue;
+            }
+            headings[start] = Some(0);
+            let mut stack = vec![start];
+            while let Some(node) = stack.pop() {
+                let heading = headings[node].unwrap_or(0);
+                for &(other, direction, forwards) in &neighbors[node] {
+                    // Edges are followed from source to target, so going the other way
+                    // means coming back out the way the robot went in
+                    let (way, other_heading) = if forwards {
+                        ((heading + quarter(direction)) % 4, turn(heading, direction))
+                    } else {
+                        let from = (heading + 4 - turn(0, direction)) % 4;
+                        ((from + quarter(direction) + 2) % 4, from)
+                    };
+                    exits[node][way as usize] = Some(other as u32);
+                    if headings[other].is_none() {
+                        headings[other] = Some(other_heading);
+                        stack.push(other);
+                    }
+                }
+            }
+        }
+        exits
     }
 
-    // TODO: Error handling here
-    pub fn path(&self) -> Path {
-        let mut nodes = self.0.node_indices();
-        let first = nodes.next().expect("Non-empty graph");
-        let path = astar(&first, |n| self.neighbors(n), |_| 0, |n| self.0[*n])
-            .expect("No path")
+    fn path_between(&self, first: graph::NodeIndex) -> Result<Vec<Edge<Direction>>, MapError> {
+        let path = astar(&first, |n| self.neighbors(n), |_| 0, |n| self.graph[*n])
+            .ok_or(MapError::NoPath)?
             .0;
-        Path(path.windows(2)
+        debug!("Path through nodes {:?}",
+               path.iter().map(|n| n.index()).collect::<Vec<_>>());
+        Ok(path.windows(2)
             .map(|n| {
                 // If this returns None then our pathing should have failed
-                let edge = self.0.find_edge(n[0], n[1]).expect("Invalid graph");
+                let edge = self.graph.find_edge(n[0], n[1]).expect("Invalid graph");
                 Edge {
                     nodes: (n[0].index() as u32, n[1].index() as u32),
-                    weight: self.0[edge],
+                    weight: self.graph[edge],
+                    thresholds: self.edge_thresholds.get(&edge).cloned(),
                 }
             })
-            .collect::<Vec<_>>())
+            .collect())
     }
 
     fn neighbors(&self, node: &graph::NodeIndex) -> Vec<(graph::NodeIndex, u32)> {
-        let mut neighbors = self.0.neighbors(*node).detach();
+        let mut neighbors = self.graph.neighbors(*node).detach();
         let mut nvec = vec![];
-        while let Some((edge, target)) = neighbors.next(&self.0) {
+        while let Some((edge, target)) = neighbors.next(&self.graph) {
             // We arrive at these edge costs by this calculation:
             // Moving forward or backward is a single movement action in the given direction
             // Moving left or right requires two actions: a turn in the given direction,
             // and then a forward movement
-            let cost = match self.0[edge] {
+            let cost = match self.graph[edge] {
                 Direction::Forward | Direction::Backward => 1,
                 Direction::Left | Direction::Right => 2,
             };
@@ -69,27 +192,129 @@ impl Map {
     }
 }
 
+/// Each direction by how many quarter turns clockwise it is from forward.
+pub const QUARTERS: [Direction; 4] =
+    [Direction::Forward, Direction::Right, Direction::Backward, Direction::Left];
+
+/// How many quarter turns clockwise from forward a direction is.
+pub fn quarter(direction: Direction) -> u8 {
+    match direction {
+        Direction::Forward => 0,
+        Direction::Right => 1,
+        Direction::Backward => 2,
+        Direction::Left => 3,
+    }
+}
+
+/// The heading after following an edge in `direction` from `heading`, both in quarter
+/// turns clockwise. Only left and right turn; backward reverses without turning.
+pub fn turn(heading: u8, direction: Direction) -> u8 {
+    match direction {
+        Direction::Left => (heading + 3) % 4,
+        Direction::Right => (heading + 1) % 4,
+        Direction::Forward | Direction::Backward => heading,
+    }
+}
+
+/// The edges to follow to a goal.
+///
+/// Edge directions are relative to the way the robot faces when it reaches the edge's
+/// first node, having followed the path from the start, which is its heading there.
 #[derive(Debug)]
-pub struct Path(Vec<Edge<Direction>>);
+pub struct Path {
+    edges: Vec<Edge<Direction>>,
+    // Quarter turns clockwise from the start, for the first node and the robot itself
+    heading: u8,
+    facing: u8,
+}
 
 impl Path {
     pub fn into_commands(self) -> Vec<Command> {
-        let mut command_vec = vec![];
-        for edge in self.0 {
-            match edge.weight {
+        self.into_steps().into_iter().map(|(command, _)| command).collect()
+    }
+
+    /// Each node on the path, with the heading the robot reaches it at.
+    pub fn arrivals(&self) -> Vec<(u32, u8)> {
+        let mut heading = self.heading;
+        let mut arrivals = vec![];
+        if let Some(edge) = self.edges.first() {
+            arrivals.push((edge.nodes.0, heading));
+        }
+        for edge in &self.edges {
+            heading = turn(heading, edge.weight);
+            arrivals.push((edge.nodes.1, heading));
+        }
+        arrivals
+    }
+
+    /// Like `into_commands`, along with the node the robot is at when each command
+    /// starts.
+    pub fn into_steps(self) -> Vec<(Command, Option<u32>)> {
+        let mut steps = vec![];
+        let mut thresholds = Overrides::default();
+        let mut node = None;
+        let mut heading = self.heading;
+        let mut facing = self.facing;
+        for edge in self.edges {
+            let from = Some(edge.nodes.0);
+            // Only switch thresholds when they change from one edge to the next
+            let edge_thresholds = edge.thresholds.unwrap_or_default();
+            if edge_thresholds != thresholds {
+                steps.push((Command::SetThresholds(edge_thresholds), from));
+                thresholds = edge_thresholds;
+            }
+            // A robot that has already turned for this edge only has to move
+            let way = (heading + quarter(edge.weight)) % 4;
+            match QUARTERS[((way + 4 - facing) % 4) as usize] {
                 d @ Direction::Forward |
                 d @ Direction::Backward => {
-                    command_vec.push(Command::Move(d));
+                    steps.push((Command::Move(d), from));
                 }
                 d @ Direction::Left |
                 d @ Direction::Right => {
-                    command_vec.push(Command::Move(d));
-                    command_vec.push(Command::Move(Direction::Forward));
+                    steps.push((Command::Move(d), from));
+                    steps.push((Command::Move(Direction::Forward), from));
+                    facing = way;
                 }
             }
+            heading = turn(heading, edge.weight);
+            node = Some(edge.nodes.1);
+        }
+        steps.push((Command::Stop, node));
+        steps
+    }
+
+    /// Like `into_commands`, but for a drivetrain that strafes, so the robot keeps
+    /// facing the same way and slides sideways instead of turning.
+    ///
+    /// Edge directions are relative to the way the robot would be facing if it had
+    /// turned, so they're rotated back into the robot's own frame.
+    pub fn into_strafe_commands(self) -> Vec<Command> {
+        self.into_strafe_steps().into_iter().map(|(command, _)| command).collect()
+    }
+
+    /// Like `into_strafe_commands`, along with the node the robot is at when each
+    /// command starts.
+    pub fn into_strafe_steps(self) -> Vec<(Command, Option<u32>)> {
+        let mut steps = vec![];
+        let mut thresholds = Overrides::default();
+        let mut node = None;
+        // How far the robot would have turned by now
+        let mut heading = self.heading;
+        for edge in self.edges {
+            let from = Some(edge.nodes.0);
+            let edge_thresholds = edge.thresholds.unwrap_or_default();
+            if edge_thresholds != thresholds {
+                steps.push((Command::SetThresholds(edge_thresholds), from));
+                thresholds = edge_thresholds;
+            }
+            let way = (heading + quarter(edge.weight)) % 4;
+            steps.push((Command::Move(QUARTERS[((way + 4 - self.facing) % 4) as usize]), from));
+            heading = turn(heading, edge.weight);
+            node = Some(edge.nodes.1);
         }
-        command_vec.push(Command::Stop);
-        command_vec
+        steps.push((Command::Stop, node));
+        steps
     }
 }
 
@@ -97,6 +322,18 @@ impl Path {
 pub struct Edge<E> {
     nodes: (u32, u32),
     weight: E,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thresholds: Option<Overrides>,
+}
+
+impl<E> Edge<E> {
+    pub fn new(source: u32, target: u32, weight: E) -> Self {
+        Edge {
+            nodes: (source, target),
+            weight: weight,
+            thresholds: None,
+        }
+    }
 }
 
 impl<E> petgraph::IntoWeightedEdge<E> for Edge<E> {
@@ -111,6 +348,7 @@ impl<E> From<graph::Edge<E>> for Edge<E> {
         Edge {
             nodes: (edge.source().index() as u32, edge.target().index() as u32),
             weight: edge.weight,
+            thresholds: None,
         }
     }
 }
@@ -137,12 +375,17 @@ impl serde::Serialize for Map {
                 Edge {
                     nodes: (e.source().index() as u32, e.target().index() as u32),
                     weight: e.weight(),
+                    thresholds: self.edge_thresholds.get(&e.id()).cloned(),
                 }
             })
             .collect::<Vec<_>>();
-        let mut struc = serializer.serialize_struct("Map", 2)?;
+        let len = if self.thresholds.is_empty() { 2 } else { 3 };
+        let mut struc = serializer.serialize_struct("Map", len)?;
         struc.serialize_field("nodes", &nodes)?;
         struc.serialize_field("edges", &edges)?;
+        if !self.thresholds.is_empty() {
+            struc.serialize_field("thresholds", &self.thresholds)?;
+        }
         struc.end()
     }
 }
@@ -151,11 +394,12 @@ impl serde::Deserialize for Map {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where D: serde::Deserializer
     {
-        const FIELDS: &'static [&'static str] = &["nodes", "edges"];
+        const FIELDS: &'static [&'static str] = &["nodes", "edges", "thresholds"];
 
         enum Field {
             Nodes,
             Edges,
+            Thresholds,
         }
 
         struct FieldVisitor;
@@ -164,7 +408,7 @@ impl serde::Deserialize for Map {
             type Value = Field;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("`nodes` or `edges`")
+                formatter.write_str("`nodes`, `edges` or `thresholds`")
             }
 
             fn visit_str<E>(self, value: &str) -> Result<Field, E>
@@ -173,6 +417,7 @@ impl serde::Deserialize for Map {
                 match value {
                     "nodes" => Ok(Field::Nodes),
                     "edges" => Ok(Field::Edges),
+                    "thresholds" => Ok(Field::Thresholds),
                     _ => Err(de::Error::unknown_field(value, FIELDS)),
                 }
             }
@@ -200,6 +445,7 @@ impl serde::Deserialize for Map {
             {
                 let mut nodes = None;
                 let mut edges = None;
+                let mut thresholds = None;
                 while let Some(key) = visitor.visit_key()? {
                     match key {
                         Field::Nodes => {
@@ -214,20 +460,123 @@ impl serde::Deserialize for Map {
                             }
                             edges = Some(visitor.visit_value()?);
                         }
+                        Field::Thresholds => {
+                            if thresholds.is_some() {
+                                return Err(de::Error::duplicate_field("thresholds"));
+                            }
+                            thresholds = Some(visitor.visit_value()?);
+                        }
                     }
                 }
-                let nodes = match nodes {
+                let nodes: Vec<bool> = match nodes {
                     Some(nodes) => nodes,
                     None => return Err(de::Error::missing_field("nodes")),
                 };
-                let edges = match edges {
+                let edges: Vec<Edge<Direction>> = match edges {
                     Some(edges) => edges,
                     None => return Err(de::Error::missing_field("edges")),
                 };
-                Ok(Map::from_nodes_edges(nodes, edges))
+                let mut map = Map::from_nodes_edges(nodes, edges)
+                    .map_err(|err| de::Error::custom(err.to_string()))?;
+                map.thresholds = thresholds.unwrap_or_default();
+                Ok(map)
             }
         }
 
         deserializer.deserialize_struct("Map", FIELDS, MapVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use {Command, Direction};
+    use error::MapError;
+    use super::{Edge, Map, quarter, turn};
+
+    // 0 forward to 1, left to 2, then right to the goal at 3, with a dead end to the
+    // right of 1 at 4
+    fn zigzag() -> Map {
+        let edges = vec![Edge::new(0, 1, Direction::Forward),
+                         Edge::new(1, 2, Direction::Left),
+                         Edge::new(2, 3, Direction::Right),
+                         Edge::new(1, 4, Direction::Right)];
+        Map::from_nodes_edges(vec![false, false, false, true, false], edges).unwrap()
+    }
+
+    fn moves(directions: &[Direction]) -> Vec<Command> {
+        let mut commands = directions.iter().map(|&d| Command::Move(d)).collect::<Vec<_>>();
+        commands.push(Command::Stop);
+        commands
+    }
+
+    #[test]
+    fn turns_and_quarters() {
+        assert_eq!(turn(0, Direction::Left), 3);
+        assert_eq!(turn(3, Direction::Right), 0);
+        assert_eq!(turn(2, Direction::Backward), 2);
+        assert_eq!(quarter(Direction::Right), 1);
+        assert_eq!(quarter(Direction::Left), 3);
+    }
+
+    #[test]
+    fn turns_then_moves_forward() {
+        use Direction::*;
+        let path = zigzag().path().unwrap();
+        assert_eq!(path.arrivals(), vec![(0, 0), (1, 0), (2, 3), (3, 0)]);
+        let steps = path.into_steps();
+        assert_eq!(steps.iter().map(|&(_, node)| node).collect::<Vec<_>>(),
+                   vec![Some(0), Some(1), Some(1), Some(2), Some(2), Some(3)]);
+        assert_eq!(steps.into_iter().map(|(command, _)| command).collect::<Vec<_>>(),
+                   moves(&[Forward, Left, Forward, Right, Forward]));
+    }
+
+    #[test]
+    fn strafes_without_turning() {
+        use Direction::*;
+        assert_eq!(zigzag().path().unwrap().into_strafe_commands(),
+                   moves(&[Forward, Left, Forward]));
+    }
+
+    #[test]
+    fn resumes_from_a_node_on_the_path() {
+        use Direction::*;
+        let map = zigzag();
+        // Stopped at 1 before turning, and after turning but before moving on
+        assert_eq!(map.path_from(1, 0).unwrap().into_commands(),
+                   moves(&[Left, Forward, Right, Forward]));
+        assert_eq!(map.path_from(1, 3).unwrap().into_commands(),
+                   moves(&[Forward, Right, Forward]));
+        // The same turns are slides from wherever a strafing robot faces
+        assert_eq!(map.path_from(2, 0).unwrap().into_strafe_commands(), moves(&[Forward]));
+        assert_eq!(map.path_from(2, 1).unwrap().into_strafe_commands(), moves(&[Left]));
+        assert_eq!(map.path_from(3, 0).unwrap().into_commands(), vec![Command::Stop]);
+        match map.path_from(4, 0) {
+            Err(MapError::OffPath(4)) => {}
+            other => panic!("expected node 4 to be off the path, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn exits_are_relative_to_the_start() {
+        assert_eq!(zigzag().exits(),
+                   vec![[Some(1), None, None, None],
+                        [None, Some(4), Some(0), Some(2)],
+                        [Some(3), Some(1), None, None],
+                        [None, None, Some(2), None],
+                        [None, None, None, Some(1)]]);
+    }
+
+    #[test]
+    fn edges_must_be_between_nodes_on_the_map() {
+        let edges = vec![Edge::new(0, 1, Direction::Forward), Edge::new(1, 3, Direction::Left)];
+        match Map::from_nodes_edges(vec![false, false, true], edges) {
+            Err(MapError::MissingNode(1, 3)) => {}
+            other => panic!("expected a missing node, got {:?}", other),
+        }
+        assert!(serde_json::from_str::<Map>(r#"{"nodes": [false], "edges": [
+                                                   {"nodes": [0, 1], "weight": "Forward"}]}"#)
+            .is_err());
+    }
+}
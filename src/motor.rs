@@ -1,124 +1,332 @@
+use std::thread;
+use std::time;
+
 use sysfs_gpio as gpio;
 use sysfs_gpio::Pin;
 
 use Direction;
-use error;
+use pwm;
 use super::Result;
 
+/// One H-bridge channel, driving a single motor.
 #[derive(Clone, Debug)]
-pub struct Controller {
-    enable_a: Pin,
-    in_a1: Pin,
-    in_a2: Pin,
-    enable_b: Pin,
-    in_b1: Pin,
-    in_b2: Pin,
-}
-
-pub enum Device {
-    A,
-    B,
-}
-
-impl Controller {
-    // consider our own error type here?
-    pub fn new(enable_a: Pin,
-               in_a1: Pin,
-               in_a2: Pin,
-               enable_b: Pin,
-               in_b1: Pin,
-               in_b2: Pin)
-               -> Result<Controller> {
-        let controller = Controller {
-            enable_a: enable_a,
-            in_a1: in_a1,
-            in_a2: in_a2,
-            enable_b: enable_b,
-            in_b1: in_b1,
-            in_b2: in_b2,
-        };
-        gpio_out!(controller, {enable_a, in_a1, in_a2,
-                               enable_b, in_b1, in_b2});
-        Ok(controller)
+pub struct Channel {
+    enable: Pin,
+    in1: Pin,
+    in2: Pin,
+    // PWM on the enable pin sets the channel's speed
+    speed: pwm::Pwm,
+    invert: bool,
+}
+
+str_enum! {
+    /// Which wheel a motor channel drives.
+    pub enum Role {
+        FrontLeft => "front-left",
+        FrontRight => "front-right",
+        RearLeft => "rear-left",
+        RearRight => "rear-right",
+        /// The only driven wheel on the left side, on a two wheeled robot
+        Left => "left",
+        /// The only driven wheel on the right side, on a two wheeled robot
+        Right => "right"
     }
+}
 
-    pub fn enable(&self, device: Device) -> Result<()> {
-        match device {
-            Device::A => self.enable_a.set_value(1).map_err(error::Error::from),
-            Device::B => self.enable_b.set_value(1).map_err(error::Error::from),
+/// How the two channels of a dual H-bridge motor controller are connected to the wheels.
+///
+/// By default channel B drives the left wheel and channel A the right, unless
+/// `swap_sides` is set, and setting `in_x1` high drives a wheel forward. A channel
+/// whose motor is wired the other way around can be inverted instead of swapping
+/// its wires.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+pub struct Wiring {
+    #[serde(default)]
+    pub swap_sides: bool,
+    #[serde(default)]
+    pub invert_a: bool,
+    #[serde(default)]
+    pub invert_b: bool,
+}
+
+/// Duty cycles between 0 and 1 to drive at when moving between nodes and when turning.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Speeds {
+    #[serde(default = "Speeds::full")]
+    pub travel: f32,
+    #[serde(default = "Speeds::full")]
+    pub turn: f32,
+}
+
+impl Default for Speeds {
+    fn default() -> Self {
+        Speeds {
+            travel: Speeds::full(),
+            turn: Speeds::full(),
         }
     }
+}
 
-    pub fn disable(&self, device: Device) -> Result<()> {
-        match device {
-            Device::A => self.enable_a.set_value(0).map_err(error::Error::from),
-            Device::B => self.enable_b.set_value(0).map_err(error::Error::from),
-        }
+impl Speeds {
+    fn full() -> f32 {
+        1.0
     }
 
-    pub fn set_direction(&self, direction: Direction) -> Result<()> {
+    pub fn get(&self, direction: Direction) -> f32 {
         match direction {
-            Direction::Forward => {
-                self.set_forward(Device::A)?;
-                self.set_forward(Device::B)?;
-                Ok(())
-            }
-            Direction::Backward => {
-                self.set_reverse(Device::A)?;
-                self.set_reverse(Device::B)?;
-                Ok(())
-            }
-            Direction::Left => {
-                self.set_forward(Device::A)?;
-                self.set_reverse(Device::B)?;
-                Ok(())
-            }
-            Direction::Right => {
-                self.set_reverse(Device::A)?;
-                self.set_forward(Device::B)?;
-                Ok(())
-            }
+            Direction::Forward | Direction::Backward => self.travel,
+            Direction::Left | Direction::Right => self.turn,
         }
     }
+}
 
-    pub fn unexport(&mut self) {
-        self.enable_a.set_value(0).ok();
-        self.in_a1.set_value(0).ok();
-        self.in_a2.set_value(0).ok();
-        self.enable_b.set_value(0).ok();
-        self.in_b1.set_value(0).ok();
-        self.in_b2.set_value(0).ok();
-        gpio_unexport!(self, {enable_a, in_a1, in_a2,
-                              enable_b, in_b1, in_b2})
+str_enum! {
+    /// What the motors do when a move ends.
+    ///
+    /// Coasting only drops the enable pins, so the robot rolls on for a few centimeters.
+    /// Braking shorts the motors by driving both inputs high with the channel enabled,
+    /// which stops them much sooner but keeps them shorted until the next move, so
+    /// braking then coasting releases them after the brake time.
+    pub enum StopMode {
+        Coast => "coast",
+        Brake => "brake",
+        BrakeThenCoast => "brake-then-coast"
     }
+}
 
-    fn set_forward(&self, device: Device) -> Result<()> {
-        match device {
-            Device::A => {
-                self.in_a1.set_value(1)?;
-                self.in_a2.set_value(0)?;
-                Ok(())
-            }
-            Device::B => {
-                self.in_b1.set_value(1)?;
-                self.in_b2.set_value(0)?;
-                Ok(())
-            }
+impl Default for StopMode {
+    fn default() -> Self {
+        StopMode::Coast
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Stop {
+    #[serde(default)]
+    pub mode: StopMode,
+    /// How long to brake for before coasting, with `StopMode::BrakeThenCoast`
+    #[serde(default = "Stop::default_brake_ms")]
+    pub brake_ms: u64,
+}
+
+impl Default for Stop {
+    fn default() -> Self {
+        Stop {
+            mode: StopMode::default(),
+            brake_ms: Stop::default_brake_ms(),
         }
     }
+}
+
+impl Stop {
+    fn default_brake_ms() -> u64 {
+        200
+    }
 
-    fn set_reverse(&self, device: Device) -> Result<()> {
-        match device {
-            Device::A => {
-                self.in_a1.set_value(0)?;
-                self.in_a2.set_value(1)?;
-                Ok(())
+    /// Stops all of the given channels.
+    pub fn apply<C: Brake>(&self, channels: &[&C]) -> Result<()> {
+        match self.mode {
+            StopMode::Coast => {
+                for channel in channels {
+                    channel.disable()?;
+                }
             }
-            Device::B => {
-                self.in_b1.set_value(0)?;
-                self.in_b2.set_value(1)?;
-                Ok(())
+            StopMode::Brake | StopMode::BrakeThenCoast => {
+                for channel in channels {
+                    channel.brake()?;
+                }
+                if self.mode == StopMode::BrakeThenCoast {
+                    thread::sleep(time::Duration::from_millis(self.brake_ms));
+                    for channel in channels {
+                        channel.disable()?;
+                    }
+                }
             }
         }
+        Ok(())
+    }
+}
+
+/// A motor that a `Stop` can stop.
+pub trait Brake {
+    /// Lets the motor coast.
+    fn disable(&self) -> Result<()>;
+
+    /// Stops the motor by shorting it.
+    fn brake(&self) -> Result<()>;
+}
+
+#[cfg(test)]
+impl Channel {
+    /// A channel on pins that are never exported, for tests that don't drive it.
+    pub fn unexported(enable: u64, in1: u64, in2: u64) -> Channel {
+        let enable = Pin::new(enable);
+        Channel {
+            enable: enable,
+            in1: Pin::new(in1),
+            in2: Pin::new(in2),
+            speed: pwm::Pwm::new(enable),
+            invert: false,
+        }
+    }
+}
+
+impl Brake for Channel {
+    fn disable(&self) -> Result<()> {
+        Channel::disable(self)
+    }
+
+    fn brake(&self) -> Result<()> {
+        Channel::brake(self)
+    }
+}
+
+impl Channel {
+    pub fn new(enable: Pin, in1: Pin, in2: Pin) -> Result<Channel> {
+        let channel = Channel {
+            enable: enable,
+            in1: in1,
+            in2: in2,
+            speed: pwm::Pwm::new(enable),
+            invert: false,
+        };
+        gpio_out!(channel, {enable, in1, in2});
+        Ok(channel)
+    }
+
+    /// Swaps forward and reverse, for a motor wired the other way around.
+    pub fn with_invert(mut self, invert: bool) -> Channel {
+        self.invert = invert;
+        self
+    }
+
+    pub fn enable(&self) -> Result<()> {
+        self.set_speed(1.0)
+    }
+
+    pub fn disable(&self) -> Result<()> {
+        self.set_speed(0.0)
+    }
+
+    /// Sets the duty cycle, between 0 (disabled) and 1 (fully enabled).
+    pub fn set_speed(&self, speed: f32) -> Result<()> {
+        debug!("Channel on pin {} speed {}", self.enable.get_pin(), speed);
+        self.speed.set_duty(speed)
+    }
+
+    /// Drives the motor at a signed speed between -1 (full reverse) and 1 (full forward).
+    pub fn drive(&self, speed: f32) -> Result<()> {
+        self.set_direction(speed >= 0.0)?;
+        self.set_speed(speed.abs())
+    }
+
+    /// Sets the motor to spin forward or in reverse.
+    pub fn set_direction(&self, forward: bool) -> Result<()> {
+        debug!("Channel on pin {} {}",
+               self.enable.get_pin(),
+               if forward { "forward" } else { "reverse" });
+        let (in1, in2) = levels(forward, self.invert);
+        self.in1.set_value(in1)?;
+        self.in2.set_value(in2)?;
+        Ok(())
+    }
+
+    /// Stops the motor by shorting it, with both inputs high and the channel enabled.
+    pub fn brake(&self) -> Result<()> {
+        debug!("Channel on pin {} braking", self.enable.get_pin());
+        self.in1.set_value(1)?;
+        self.in2.set_value(1)?;
+        self.speed.set_duty(1.0)
+    }
+
+    pub fn unexport(&self) {
+        self.speed.stop();
+        self.enable.set_value(0).ok();
+        self.in1.set_value(0).ok();
+        self.in2.set_value(0).ok();
+        gpio_unexport!(self, {enable, in1, in2})
+    }
+}
+
+// The levels of a channel's two inputs to spin its motor forward or in reverse
+fn levels(forward: bool, invert: bool) -> (u8, u8) {
+    if forward != invert { (1, 0) } else { (0, 1) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::time;
+
+    use Result;
+    use super::{Brake, Stop, StopMode, levels};
+
+    // Remembers what it was told to do, and when
+    #[derive(Default)]
+    struct Recorder {
+        calls: RefCell<Vec<(&'static str, time::Instant)>>,
+    }
+
+    impl Recorder {
+        fn calls(&self) -> Vec<&'static str> {
+            self.calls.borrow().iter().map(|&(call, _)| call).collect()
+        }
+    }
+
+    impl Brake for Recorder {
+        fn disable(&self) -> Result<()> {
+            self.calls.borrow_mut().push(("disable", time::Instant::now()));
+            Ok(())
+        }
+
+        fn brake(&self) -> Result<()> {
+            self.calls.borrow_mut().push(("brake", time::Instant::now()));
+            Ok(())
+        }
+    }
+
+    fn stop(mode: StopMode, brake_ms: u64) -> (Recorder, Recorder) {
+        let channels = (Recorder::default(), Recorder::default());
+        let stop = Stop {
+            mode: mode,
+            brake_ms: brake_ms,
+        };
+        stop.apply(&[&channels.0, &channels.1]).unwrap();
+        channels
+    }
+
+    #[test]
+    fn forward_sets_the_first_input() {
+        assert_eq!(levels(true, false), (1, 0));
+        assert_eq!(levels(false, false), (0, 1));
+    }
+
+    #[test]
+    fn inverted_channels_swap_the_inputs() {
+        assert_eq!(levels(true, true), (0, 1));
+        assert_eq!(levels(false, true), (1, 0));
+    }
+
+    #[test]
+    fn coasting_disables_every_channel() {
+        let (left, right) = stop(StopMode::Coast, 200);
+        assert_eq!(left.calls(), vec!["disable"]);
+        assert_eq!(right.calls(), vec!["disable"]);
+    }
+
+    #[test]
+    fn braking_holds_the_brake() {
+        let (left, right) = stop(StopMode::Brake, 200);
+        assert_eq!(left.calls(), vec!["brake"]);
+        assert_eq!(right.calls(), vec!["brake"]);
+    }
+
+    #[test]
+    fn braking_then_coasting_lets_go_after_the_brake_time() {
+        let (left, right) = stop(StopMode::BrakeThenCoast, 20);
+        assert_eq!(left.calls(), vec!["brake", "disable"]);
+        assert_eq!(right.calls(), vec!["brake", "disable"]);
+        let calls = left.calls.borrow();
+        assert!(calls[1].1.duration_since(calls[0].1) >= time::Duration::from_millis(20));
     }
 }
@@ -0,0 +1,108 @@
+/// Proportional, integral and derivative gains for a `Pid` controller.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Gains {
+    #[serde(default)]
+    pub kp: f32,
+    #[serde(default)]
+    pub ki: f32,
+    #[serde(default)]
+    pub kd: f32,
+}
+
+/// A PID controller, turning an error signal into a correction.
+#[derive(Debug, Clone)]
+pub struct Pid {
+    gains: Gains,
+    // The integral is clamped to this so it can't wind up while the output saturates
+    limit: f32,
+    integral: f32,
+    last_error: Option<f32>,
+}
+
+impl Pid {
+    /// Creates a controller whose output is clamped to `-limit..limit`.
+    pub fn new(gains: Gains, limit: f32) -> Pid {
+        Pid {
+            gains: gains,
+            limit: limit,
+            integral: 0.0,
+            last_error: None,
+        }
+    }
+
+    /// Returns the correction for `error`, `dt` seconds after the last update.
+    pub fn update(&mut self, error: f32, dt: f32) -> f32 {
+        let derivative = match self.last_error {
+            Some(last) if dt > 0.0 => (error - last) / dt,
+            _ => 0.0,
+        };
+        self.last_error = Some(error);
+        if self.gains.ki != 0.0 {
+            let max_integral = self.limit / self.gains.ki.abs();
+            self.integral = (self.integral + error * dt).max(-max_integral).min(max_integral);
+        }
+        let output = self.gains.kp * error + self.gains.ki * self.integral +
+                     self.gains.kd * derivative;
+        output.max(-self.limit).min(self.limit)
+    }
+
+    /// Forgets the accumulated error, for when the error signal is lost.
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.last_error = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gains, Pid};
+
+    fn gains(kp: f32, ki: f32, kd: f32) -> Gains {
+        Gains {
+            kp: kp,
+            ki: ki,
+            kd: kd,
+        }
+    }
+
+    #[test]
+    fn proportional() {
+        let mut pid = Pid::new(gains(0.5, 0.0, 0.0), 10.0);
+        assert_eq!(pid.update(4.0, 0.1), 2.0);
+        assert_eq!(pid.update(-2.0, 0.1), -1.0);
+    }
+
+    #[test]
+    fn output_is_clamped() {
+        let mut pid = Pid::new(gains(1.0, 0.0, 0.0), 0.5);
+        assert_eq!(pid.update(3.0, 0.1), 0.5);
+        assert_eq!(pid.update(-3.0, 0.1), -0.5);
+    }
+
+    #[test]
+    fn derivative_starts_from_the_second_update() {
+        let mut pid = Pid::new(gains(0.0, 0.0, 1.0), 10.0);
+        assert_eq!(pid.update(1.0, 0.5), 0.0);
+        assert_eq!(pid.update(2.0, 0.5), 2.0);
+        // No time passed, so there's no rate of change
+        assert_eq!(pid.update(5.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn integral_does_not_wind_up() {
+        let mut pid = Pid::new(gains(0.0, 1.0, 0.0), 1.0);
+        for _ in 0..100 {
+            pid.update(1.0, 1.0);
+        }
+        // Saturated at the limit, so one step the other way starts unwinding at once
+        assert_eq!(pid.update(-0.5, 1.0), 0.5);
+    }
+
+    #[test]
+    fn reset_forgets_the_past() {
+        let mut pid = Pid::new(gains(0.0, 1.0, 1.0), 10.0);
+        pid.update(2.0, 1.0);
+        pid.reset();
+        assert_eq!(pid.update(1.0, 1.0), 1.0);
+    }
+}
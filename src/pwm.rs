@@ -0,0 +1,155 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time;
+
+use sysfs_gpio::Pin;
+
+use gpio::LoggedWrites;
+use super::Result;
+
+// 100Hz is slow enough for sysfs to keep up with, and fast enough for the motors
+// to smooth out.
+const PERIOD_US: u64 = 10000;
+
+/// Software PWM on a GPIO output, driven by a background thread.
+///
+/// Clones share the same pin and thread. The thread only runs the pin while the
+/// duty cycle is strictly between 0 and 1, otherwise it holds the pin low or high.
+#[derive(Clone, Debug)]
+pub struct Pwm {
+    pin: Pin,
+    period_us: u64,
+    shared: Arc<(Mutex<State>, Condvar)>,
+}
+
+#[derive(Debug)]
+struct State {
+    duty: f32,
+    running: bool,
+}
+
+impl Pwm {
+    /// Starts PWM on an exported output pin, with the pin held low.
+    pub fn new(pin: Pin) -> Pwm {
+        Pwm::with_period(pin, time::Duration::from_micros(PERIOD_US))
+    }
+
+    /// Like `new`, but with a period other than the default 10ms.
+    pub fn with_period(pin: Pin, period: time::Duration) -> Pwm {
+        let pwm = Pwm {
+            pin: pin,
+            period_us: micros(period),
+            shared: Arc::new((Mutex::new(State {
+                                  duty: 0.0,
+                                  running: true,
+                              }),
+                              Condvar::new())),
+        };
+        let thread_pwm = pwm.clone();
+        thread::spawn(move || thread_pwm.run());
+        pwm
+    }
+
+    /// Sets the fraction of each period the pin is high for, between 0 and 1.
+    pub fn set_duty(&self, duty: f32) -> Result<()> {
+        let duty = clamp_duty(duty);
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        // Fully on or off takes effect right away, rather than at the end of a period
+        if duty == 0.0 {
+            self.pin.set_value(0)?;
+        } else if duty == 1.0 {
+            self.pin.set_value(1)?;
+        }
+        state.duty = duty;
+        cvar.notify_one();
+        Ok(())
+    }
+
+    /// Stops the background thread, leaving the pin low.
+    pub fn stop(&self) {
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.running = false;
+        cvar.notify_one();
+    }
+
+    // The lock is only released while waiting, so `set_duty` never races with the
+    // pin writes here.
+    fn run(&self) {
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        let mut writes = LoggedWrites::default();
+        while state.running {
+            let duty = state.duty;
+            let on = match pulse_us(self.period_us, duty) {
+                Some(on) => on,
+                None => {
+                    state = cvar.wait(state).unwrap();
+                    continue;
+                }
+            };
+            writes.set_value(self.pin, 1);
+            state = cvar.wait_timeout(state, time::Duration::from_micros(on)).unwrap().0;
+            if state.duty != duty {
+                continue;
+            }
+            writes.set_value(self.pin, 0);
+            state = cvar.wait_timeout(state, time::Duration::from_micros(self.period_us - on))
+                .unwrap()
+                .0;
+        }
+        writes.set_value(self.pin, 0);
+    }
+}
+
+fn micros(duration: time::Duration) -> u64 {
+    duration.as_secs() * 1000000 + duration.subsec_nanos() as u64 / 1000
+}
+
+fn clamp_duty(duty: f32) -> f32 {
+    duty.max(0.0).min(1.0)
+}
+
+// How long the pin is high for in each period, unless it's held low or high instead
+fn pulse_us(period_us: u64, duty: f32) -> Option<u64> {
+    if duty <= 0.0 || duty >= 1.0 {
+        None
+    } else {
+        Some((period_us as f32 * duty) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::{clamp_duty, micros, pulse_us};
+
+    #[test]
+    fn periods_are_in_microseconds() {
+        assert_eq!(micros(time::Duration::from_millis(10)), 10000);
+        assert_eq!(micros(time::Duration::new(1, 500)), 1000000);
+        assert_eq!(micros(time::Duration::new(2, 2500)), 2000002);
+    }
+
+    #[test]
+    fn duty_is_clamped() {
+        assert_eq!(clamp_duty(-0.5), 0.0);
+        assert_eq!(clamp_duty(0.3), 0.3);
+        assert_eq!(clamp_duty(1.5), 1.0);
+    }
+
+    #[test]
+    fn pulses_are_a_fraction_of_the_period() {
+        assert_eq!(pulse_us(10000, 0.25), Some(2500));
+        assert_eq!(pulse_us(10000, 0.5), Some(5000));
+        assert_eq!(pulse_us(20000, 0.75), Some(15000));
+    }
+
+    #[test]
+    fn fully_off_or_on_is_not_pulsed() {
+        assert_eq!(pulse_us(10000, 0.0), None);
+        assert_eq!(pulse_us(10000, 1.0), None);
+    }
+}
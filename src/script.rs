@@ -0,0 +1,285 @@
+use std::result;
+use std::time;
+
+use {Command, Direction};
+use error::ScriptError;
+
+// Scripts are a sequence of statements separated by newlines or `;`.
+// A `#` starts a comment that runs to the end of the line.
+//
+// forward [N]            => move forward N nodes (default 1)
+// backward [N]           => move backward N nodes (default 1)
+// forward until wall     => move forward until the front sensor is blocked
+// backward until wall    => move backward until the rear sensor is blocked
+// forward for <duration> => move forward for a fixed amount of time
+// backward for <duration>
+// left [N] / right [N]   => turn 90 degrees N times (default 1)
+// wait <duration>        => do nothing for a while, e.g. `wait 1s`, `wait 250ms`
+// distance <sensor>      => print a reading from front|rear|left|right
+// stop                   => stop all motors
+
+type Result<T> = result::Result<T, ScriptError>;
+
+// The most times one statement can repeat a move, so a typo can't expand into more
+// commands than fit in memory
+const MAX_COUNT: u32 = 1000;
+
+#[derive(Debug, Copy, Clone)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Token<'a> {
+    fn error<S: Into<String>>(&self, message: S) -> ScriptError {
+        ScriptError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+
+    // Errors about something missing after this token point just past it
+    fn error_after<S: Into<String>>(&self, message: S) -> ScriptError {
+        ScriptError {
+            line: self.line,
+            column: self.column + self.text.chars().count(),
+            message: message.into(),
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Vec<Command>> {
+    let mut commands = vec![];
+    for statement in statements(source) {
+        parse_statement(&statement, &mut commands)?;
+    }
+    Ok(commands)
+}
+
+fn statements(source: &str) -> Vec<Vec<Token<'_>>> {
+    let mut statements = vec![];
+    let mut current = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let code = match line.find('#') {
+            Some(idx) => &line[..idx],
+            None => line,
+        };
+        // (byte offset, column) of the start of the current word
+        let mut start: Option<(usize, usize)> = None;
+        let mut column = 0;
+        for (idx, c) in code.char_indices() {
+            column += 1;
+            if c.is_whitespace() || c == ';' {
+                if let Some((s, col)) = start.take() {
+                    current.push(Token {
+                        text: &code[s..idx],
+                        line: i + 1,
+                        column: col,
+                    });
+                }
+                if c == ';' && !current.is_empty() {
+                    statements.push(current);
+                    current = vec![];
+                }
+            } else if start.is_none() {
+                start = Some((idx, column));
+            }
+        }
+        if let Some((s, col)) = start {
+            current.push(Token {
+                text: &code[s..],
+                line: i + 1,
+                column: col,
+            });
+        }
+        if !current.is_empty() {
+            statements.push(current);
+            current = vec![];
+        }
+    }
+    statements
+}
+
+fn parse_statement(tokens: &[Token], commands: &mut Vec<Command>) -> Result<()> {
+    let keyword = tokens[0];
+    match keyword.text {
+        "forward" | "backward" => {
+            let direction = if keyword.text == "forward" {
+                Direction::Forward
+            } else {
+                Direction::Backward
+            };
+            match tokens.get(1) {
+                None => commands.push(Command::Move(direction)),
+                Some(t) if t.text == "until" => {
+                    match tokens.get(2) {
+                        Some(w) if w.text == "wall" => {}
+                        Some(w) => return Err(w.error(format!("expected `wall`, found `{}`", w.text))),
+                        None => return Err(t.error_after("expected `wall`")),
+                    }
+                    expect_end(tokens, 3)?;
+                    commands.push(Command::MoveUntilWall(direction));
+                }
+                Some(t) if t.text == "for" => {
+                    let duration = parse_duration(t, tokens.get(2))?;
+                    expect_end(tokens, 3)?;
+                    commands.push(Command::MoveFor(direction, duration));
+                }
+                Some(t) => {
+                    let count = parse_count(t)?;
+                    expect_end(tokens, 2)?;
+                    for _ in 0..count {
+                        commands.push(Command::Move(direction));
+                    }
+                }
+            }
+        }
+        "left" | "right" => {
+            let direction = if keyword.text == "left" {
+                Direction::Left
+            } else {
+                Direction::Right
+            };
+            let count = match tokens.get(1) {
+                Some(t) => parse_count(t)?,
+                None => 1,
+            };
+            expect_end(tokens, 2)?;
+            for _ in 0..count {
+                commands.push(Command::Move(direction));
+            }
+        }
+        "wait" => {
+            let duration = parse_duration(&keyword, tokens.get(1))?;
+            expect_end(tokens, 2)?;
+            commands.push(Command::Wait(duration));
+        }
+        "distance" => {
+            let direction = match tokens.get(1) {
+                Some(t) => parse_sensor(t)?,
+                None => return Err(keyword.error_after("expected a sensor: front, rear, left or right")),
+            };
+            expect_end(tokens, 2)?;
+            commands.push(Command::Distance(direction));
+        }
+        "stop" => {
+            expect_end(tokens, 1)?;
+            commands.push(Command::Stop);
+        }
+        other => return Err(keyword.error(format!("unknown command `{}`", other))),
+    }
+    Ok(())
+}
+
+fn expect_end(tokens: &[Token], len: usize) -> Result<()> {
+    match tokens.get(len) {
+        Some(t) => Err(t.error(format!("unexpected `{}`", t.text))),
+        None => Ok(()),
+    }
+}
+
+fn parse_count(token: &Token) -> Result<u32> {
+    match token.text.parse::<u32>() {
+        Ok(0) | Err(_) => Err(token.error(format!("expected a positive count, found `{}`", token.text))),
+        Ok(n) if n > MAX_COUNT => {
+            Err(token.error(format!("count `{}` is more than the limit of {}", n, MAX_COUNT)))
+        }
+        Ok(n) => Ok(n),
+    }
+}
+
+fn parse_sensor(token: &Token) -> Result<Direction> {
+    match token.text {
+        "front" => Ok(Direction::Forward),
+        "rear" | "back" => Ok(Direction::Backward),
+        "left" => Ok(Direction::Left),
+        "right" => Ok(Direction::Right),
+        other => Err(token.error(format!("unknown sensor `{}`, expected front, rear, left or right", other))),
+    }
+}
+
+// Durations are a number followed by a unit, e.g. `2s`, `1.5s` or `250ms`
+fn parse_duration(prev: &Token, token: Option<&Token>) -> Result<time::Duration> {
+    let token = match token {
+        Some(t) => t,
+        None => return Err(prev.error_after("expected a duration such as `1s` or `500ms`")),
+    };
+    let (number, scale) = if token.text.ends_with("ms") {
+        (&token.text[..token.text.len() - 2], 1e-3)
+    } else if token.text.ends_with('s') {
+        (&token.text[..token.text.len() - 1], 1.0)
+    } else {
+        return Err(token.error(format!("`{}` is missing a unit, expected `s` or `ms`", token.text)));
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n >= 0.0 && n.is_finite() => {
+            let secs = n * scale;
+            Ok(time::Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32))
+        }
+        _ => Err(token.error(format!("invalid duration `{}`", token.text))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use {Command, Direction};
+    use super::parse;
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let err = parse(source).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    #[test]
+    fn parses_each_statement() {
+        let source = "forward 2; left\nbackward until wall # home\n\nwait 1.5s\n\
+                      forward for 250ms; distance rear; stop";
+        assert_eq!(parse(source).unwrap(),
+                   vec![Command::Move(Direction::Forward),
+                        Command::Move(Direction::Forward),
+                        Command::Move(Direction::Left),
+                        Command::MoveUntilWall(Direction::Backward),
+                        Command::Wait(time::Duration::from_millis(1500)),
+                        Command::MoveFor(Direction::Forward, time::Duration::from_millis(250)),
+                        Command::Distance(Direction::Backward),
+                        Command::Stop]);
+    }
+
+    #[test]
+    fn empty_scripts_have_no_commands() {
+        assert_eq!(parse("").unwrap(), vec![]);
+        assert_eq!(parse("  # nothing\n;;\n").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn errors_point_at_the_token() {
+        assert_eq!(error("stop\n  jump"), (2, 3, String::from("unknown command `jump`")));
+        assert_eq!(error("left 0"),
+                   (1, 6, String::from("expected a positive count, found `0`")));
+        assert_eq!(error("forward until door"),
+                   (1, 15, String::from("expected `wall`, found `door`")));
+        assert_eq!(error("stop now"), (1, 6, String::from("unexpected `now`")));
+        assert_eq!(error("wait 3"),
+                   (1, 6, String::from("`3` is missing a unit, expected `s` or `ms`")));
+    }
+
+    #[test]
+    fn errors_about_missing_tokens_point_past_the_last() {
+        assert_eq!(error("forward until"), (1, 14, String::from("expected `wall`")));
+        assert_eq!(error("distance"),
+                   (1, 9, String::from("expected a sensor: front, rear, left or right")));
+        assert_eq!(error("wait"),
+                   (1, 5, String::from("expected a duration such as `1s` or `500ms`")));
+    }
+
+    #[test]
+    fn counts_are_limited() {
+        assert_eq!(parse("right 1000").unwrap().len(), 1000);
+        assert_eq!(error("forward 4000000000"),
+                   (1, 9, String::from("count `4000000000` is more than the limit of 1000")));
+    }
+}
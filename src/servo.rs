@@ -0,0 +1,102 @@
+use std::time;
+
+use sysfs_gpio as gpio;
+use sysfs_gpio::Pin;
+
+use pwm;
+use super::Result;
+
+// Hobby servos expect a pulse every 20ms
+const PERIOD_US: u64 = 20000;
+
+/// How a hobby servo's pulse widths map to angles.
+///
+/// Angles are in degrees, with 0 straight ahead and positive angles to the left. The
+/// middle pulse width points straight ahead, and the full range of pulse widths
+/// sweeps through `range` degrees.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(default = "Settings::default_min_pulse_us")]
+    pub min_pulse_us: u64,
+    #[serde(default = "Settings::default_max_pulse_us")]
+    pub max_pulse_us: u64,
+    #[serde(default = "Settings::default_range")]
+    pub range: f32,
+    /// Set if longer pulses turn the servo to the right
+    #[serde(default)]
+    pub invert: bool,
+    /// How long to let the servo settle after moving before taking a reading
+    #[serde(default = "Settings::default_settle_ms")]
+    pub settle_ms: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            min_pulse_us: Settings::default_min_pulse_us(),
+            max_pulse_us: Settings::default_max_pulse_us(),
+            range: Settings::default_range(),
+            invert: false,
+            settle_ms: Settings::default_settle_ms(),
+        }
+    }
+}
+
+impl Settings {
+    fn default_min_pulse_us() -> u64 {
+        1000
+    }
+
+    fn default_max_pulse_us() -> u64 {
+        2000
+    }
+
+    fn default_range() -> f32 {
+        180.0
+    }
+
+    fn default_settle_ms() -> u64 {
+        80
+    }
+}
+
+/// A hobby servo on a software PWM pin.
+#[derive(Clone, Debug)]
+pub struct Servo {
+    pin: Pin,
+    pwm: pwm::Pwm,
+    settings: Settings,
+}
+
+impl Servo {
+    pub fn new(pin: Pin, settings: Settings) -> Result<Servo> {
+        pin.set_direction(gpio::Direction::Out)?;
+        Ok(Servo {
+            pin: pin,
+            pwm: pwm::Pwm::with_period(pin, time::Duration::from_micros(PERIOD_US)),
+            settings: settings,
+        })
+    }
+
+    /// Turns to `angle` degrees, clamped to the servo's range, without waiting for it
+    /// to get there.
+    pub fn set_angle(&self, angle: f32) -> Result<()> {
+        let half = self.settings.range / 2.0;
+        let angle = angle.max(-half).min(half);
+        let angle = if self.settings.invert { -angle } else { angle };
+        let min = self.settings.min_pulse_us as f32;
+        let max = self.settings.max_pulse_us as f32;
+        let pulse = min + (max - min) * (angle + half) / self.settings.range;
+        self.pwm.set_duty(pulse / PERIOD_US as f32)
+    }
+
+    pub fn settle_time(&self) -> time::Duration {
+        time::Duration::from_millis(self.settings.settle_ms)
+    }
+
+    pub fn unexport(&self) {
+        self.pwm.stop();
+        self.pin.set_value(0).ok();
+        gpio_unexport!(self, {pin})
+    }
+}
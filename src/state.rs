@@ -0,0 +1,80 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use serde_json;
+
+use super::Result;
+
+/// Where a run through a map is up to, saved after each command so it can carry on
+/// after a restart instead of going back to the start.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct RunState {
+    /// The map node the robot is at, if it's following a map
+    pub node: Option<u32>,
+    /// Quarter turns clockwise from the way the robot faced at the start
+    pub heading: u8,
+    /// How many commands have finished
+    pub index: usize,
+    /// Whether every command has run, so there's nothing left to carry on with
+    #[serde(default)]
+    pub finished: bool,
+}
+
+impl RunState {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RunState> {
+        let path = path.as_ref();
+        let read = || -> Result<RunState> { Ok(serde_json::from_reader(fs::File::open(path)?)?) };
+        read().map_err(|err| err.in_file(path))
+    }
+
+    /// Replaces the state file, so a power cut leaves either the old state or the new
+    /// one and never half of each.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let partial = path.with_extension("partial");
+        {
+            let mut file = fs::File::create(&partial)?;
+            file.write_all(serde_json::to_string(self)?.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use serde_json;
+
+    use super::RunState;
+
+    #[test]
+    fn saves_and_loads() {
+        let path = env::temp_dir().join(format!("rsbot-state-{}.json", process::id()));
+        let state = RunState {
+            node: Some(3),
+            heading: 1,
+            index: 7,
+            finished: false,
+        };
+        state.save(&path).unwrap();
+        let loaded = RunState::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((loaded.node, loaded.heading, loaded.index, loaded.finished),
+                   (Some(3), 1, 7, false));
+        assert!(!path.with_extension("partial").exists());
+        assert!(RunState::load(&path).is_err());
+    }
+
+    #[test]
+    fn older_states_are_unfinished() {
+        let state: RunState = serde_json::from_str(r#"{"node":null,"heading":0,"index":2}"#)
+            .unwrap();
+        assert!(!state.finished);
+    }
+}
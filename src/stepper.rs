@@ -0,0 +1,501 @@
+use std::f32::consts::PI;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time;
+
+use sysfs_gpio as gpio;
+use sysfs_gpio::Pin;
+
+use Direction;
+use drivetrain::Drivetrain;
+use gpio::LoggedWrites;
+use motor::{self, Channel, Role};
+use super::Result;
+
+// Long enough for both the A4988 (1μs) and DRV8825 (1.9μs) to see a step
+const PULSE_US: u64 = 2;
+
+str_enum! {
+    /// The STEP/DIR driver chip, which decides how the microstep pins are read.
+    pub enum Driver {
+        A4988 => "a4988",
+        Drv8825 => "drv8825"
+    }
+}
+
+impl Default for Driver {
+    fn default() -> Self {
+        Driver::A4988
+    }
+}
+
+impl Driver {
+    /// Levels for the three microstep select pins (MS1-3 or M0-2), or `None` if the
+    /// driver can't do that many microsteps.
+    pub fn mode(&self, microsteps: u32) -> Option<[u8; 3]> {
+        match (*self, microsteps) {
+            (_, 1) => Some([0, 0, 0]),
+            (_, 2) => Some([1, 0, 0]),
+            (_, 4) => Some([0, 1, 0]),
+            (_, 8) => Some([1, 1, 0]),
+            (Driver::A4988, 16) => Some([1, 1, 1]),
+            (Driver::Drv8825, 16) => Some([0, 0, 1]),
+            (Driver::Drv8825, 32) => Some([1, 0, 1]),
+            _ => None,
+        }
+    }
+}
+
+/// How the stepper motors and the wheels on them are set up.
+///
+/// Speeds and accelerations are in full steps, so they don't change with `microsteps`.
+/// Lengths are in millimeters.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub driver: Driver,
+    #[serde(default = "Settings::default_steps_per_rev")]
+    pub steps_per_rev: u32,
+    #[serde(default = "Settings::default_microsteps")]
+    pub microsteps: u32,
+    /// Full steps per second at a speed of 1
+    #[serde(default = "Settings::default_max_speed")]
+    pub max_speed: f32,
+    /// Full steps per second, per second
+    #[serde(default = "Settings::default_acceleration")]
+    pub acceleration: f32,
+    #[serde(default = "Settings::default_wheel_diameter")]
+    pub wheel_diameter: f32,
+    /// Distance between the middles of the two wheels
+    #[serde(default = "Settings::default_track_width")]
+    pub track_width: f32,
+    /// Distance between neighbouring nodes in a map
+    #[serde(default = "Settings::default_node_distance")]
+    pub node_distance: f32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            driver: Driver::default(),
+            steps_per_rev: Settings::default_steps_per_rev(),
+            microsteps: Settings::default_microsteps(),
+            max_speed: Settings::default_max_speed(),
+            acceleration: Settings::default_acceleration(),
+            wheel_diameter: Settings::default_wheel_diameter(),
+            track_width: Settings::default_track_width(),
+            node_distance: Settings::default_node_distance(),
+        }
+    }
+}
+
+impl Settings {
+    fn default_steps_per_rev() -> u32 {
+        200
+    }
+
+    fn default_microsteps() -> u32 {
+        8
+    }
+
+    fn default_max_speed() -> f32 {
+        300.0
+    }
+
+    fn default_acceleration() -> f32 {
+        600.0
+    }
+
+    fn default_wheel_diameter() -> f32 {
+        65.0
+    }
+
+    fn default_track_width() -> f32 {
+        150.0
+    }
+
+    fn default_node_distance() -> f32 {
+        300.0
+    }
+
+    /// Microsteps to drive a wheel `mm` along the ground.
+    pub fn distance_steps(&self, mm: f32) -> i64 {
+        let steps_per_mm = (self.steps_per_rev * self.microsteps) as f32 /
+                           (PI * self.wheel_diameter);
+        (mm * steps_per_mm).round() as i64
+    }
+
+    /// Microsteps to drive each wheel, in opposite directions, to turn in place by
+    /// `degrees`.
+    pub fn turn_steps(&self, degrees: f32) -> i64 {
+        self.distance_steps(PI * self.track_width * degrees / 360.0)
+    }
+}
+
+/// A stepper motor on a STEP/DIR driver, stepped by a background thread.
+///
+/// Clones share the same pins, thread and step count. Speed changes ramp up and down
+/// at the configured acceleration, so the motor doesn't stall or skip steps.
+#[derive(Clone, Debug)]
+pub struct Stepper {
+    step: Pin,
+    dir: Pin,
+    // Active low on both supported drivers
+    enable: Option<Pin>,
+    invert: bool,
+    settings: Settings,
+    shared: Arc<(Mutex<State>, Condvar)>,
+}
+
+#[derive(Debug)]
+struct State {
+    // Signed microsteps per second to run at, or to move to `target` at
+    velocity: f32,
+    // Position to stop at, when making a fixed move
+    target: Option<i64>,
+    // Microsteps taken, forward less reverse
+    position: i64,
+    // Whether the motor is still turning, which it may be for a while after being
+    // told to stop
+    moving: bool,
+    running: bool,
+}
+
+impl Stepper {
+    pub fn new(step: Pin, dir: Pin, enable: Option<Pin>, settings: Settings) -> Result<Stepper> {
+        let stepper = Stepper {
+            step: step,
+            dir: dir,
+            enable: enable,
+            invert: false,
+            settings: settings,
+            shared: Arc::new((Mutex::new(State {
+                                  velocity: 0.0,
+                                  target: None,
+                                  position: 0,
+                                  moving: false,
+                                  running: true,
+                              }),
+                              Condvar::new())),
+        };
+        gpio_out!(stepper, {step, dir});
+        if let Some(enable) = stepper.enable {
+            enable.set_direction(gpio::Direction::Out)?;
+            enable.set_value(0)?;
+        }
+        let thread_stepper = stepper.clone();
+        thread::spawn(move || thread_stepper.run());
+        Ok(stepper)
+    }
+
+    /// Swaps forward and reverse, for a motor mounted the other way around.
+    pub fn with_invert(mut self, invert: bool) -> Stepper {
+        self.invert = invert;
+        self
+    }
+
+    /// Runs at a signed speed between -1 (full reverse) and 1 (full forward) until told
+    /// otherwise, cancelling any fixed move.
+    pub fn drive(&self, speed: f32) -> Result<()> {
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.target = None;
+        state.velocity = self.velocity(speed);
+        cvar.notify_all();
+        Ok(())
+    }
+
+    /// Starts moving `steps` microsteps from where the motor is now, at up to `speed`.
+    pub fn move_steps(&self, steps: i64, speed: f32) -> Result<()> {
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.target = Some(state.position + steps);
+        state.velocity = self.velocity(speed.abs()).abs() * steps.signum() as f32;
+        cvar.notify_all();
+        Ok(())
+    }
+
+    /// Blocks until the motor has finished a fixed move, or come to a standstill.
+    pub fn wait(&self) {
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        while state.running && (state.target.is_some() || state.moving) {
+            state = cvar.wait(state).unwrap();
+        }
+    }
+
+    /// Turns the driver's outputs on or off. Off lets the wheel turn freely.
+    pub fn set_enabled(&self, enabled: bool) -> Result<()> {
+        if let Some(enable) = self.enable {
+            enable.set_value(if enabled { 0 } else { 1 })?;
+        }
+        Ok(())
+    }
+
+    pub fn unexport(&self) {
+        {
+            let &(ref state, ref cvar) = &*self.shared;
+            let mut state = state.lock().unwrap();
+            state.running = false;
+            cvar.notify_all();
+        }
+        self.set_enabled(false).ok();
+        self.step.set_value(0).ok();
+        self.dir.set_value(0).ok();
+        gpio_unexport!(self, {step, dir});
+        if let Some(enable) = self.enable {
+            enable.unexport().ok();
+        }
+    }
+
+    fn velocity(&self, speed: f32) -> f32 {
+        speed.max(-1.0).min(1.0) * self.settings.max_speed * self.settings.microsteps as f32
+    }
+
+    // Each step changes the speed by the acceleration over that step, so v² goes up or
+    // down by 2a each time. Fixed moves start slowing down once the steps left are
+    // fewer than it takes to stop.
+    fn run(&self) {
+        let accel = self.settings.acceleration * self.settings.microsteps as f32;
+        let &(ref state, ref cvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        // Unsigned speed in microsteps per second, and which way it's turning
+        let mut speed = 0.0f32;
+        let mut forward = true;
+        let mut writes = LoggedWrites::default();
+        while state.running {
+            let velocity = match state.target {
+                Some(target) if target == state.position => {
+                    state.target = None;
+                    state.velocity = 0.0;
+                    state.moving = false;
+                    speed = 0.0;
+                    cvar.notify_all();
+                    continue;
+                }
+                Some(target) => {
+                    if braking(speed, accel, (target - state.position).abs()) {
+                        0.0
+                    } else {
+                        state.velocity
+                    }
+                }
+                None => state.velocity,
+            };
+
+            // Reversing means slowing to a stop first
+            let goal = if speed == 0.0 || (velocity >= 0.0) == forward {
+                velocity.abs()
+            } else {
+                0.0
+            };
+            if speed == 0.0 && goal == 0.0 {
+                state.moving = false;
+                cvar.notify_all();
+                state = cvar.wait(state).unwrap();
+                continue;
+            }
+            if speed == 0.0 {
+                forward = velocity >= 0.0;
+                writes.set_value(self.dir, if forward != self.invert { 1 } else { 0 });
+            }
+            speed = ramp(speed, goal, accel);
+            state.moving = speed > 0.0;
+            if speed == 0.0 {
+                continue;
+            }
+
+            writes.set_value(self.step, 1);
+            thread::sleep(time::Duration::from_micros(PULSE_US));
+            writes.set_value(self.step, 0);
+            state.position += if forward { 1 } else { -1 };
+
+            // Let go of the lock between steps so speed changes can get in
+            let interval = time::Duration::from_micros((1e6 / speed) as u64);
+            drop(state);
+            thread::sleep(interval);
+            state = self.shared.0.lock().unwrap();
+        }
+    }
+}
+
+// The speed for the next step, one step's acceleration closer to `goal`
+fn ramp(speed: f32, goal: f32, accel: f32) -> f32 {
+    if speed < goal {
+        (speed * speed + 2.0 * accel).sqrt().min(goal)
+    } else {
+        (speed * speed - 2.0 * accel).max(0.0).sqrt().max(goal)
+    }
+}
+
+// Whether a motor moving at `speed` has to start slowing down to stop within `left`
+// steps
+fn braking(speed: f32, accel: f32, left: i64) -> bool {
+    speed > 0.0 && speed * speed / (2.0 * accel) >= left as f32
+}
+
+/// Two wheels on stepper motors, one on each side.
+///
+/// Since the steps are counted, moves between nodes go exactly `node_distance` and
+/// turns exactly 90 degrees, without watching the sensors.
+#[derive(Debug)]
+pub struct Steppers {
+    left: Stepper,
+    right: Stepper,
+    settings: Settings,
+}
+
+impl Steppers {
+    pub fn new(left: Stepper, right: Stepper, settings: Settings) -> Steppers {
+        Steppers {
+            left: left,
+            right: right,
+            settings: settings,
+        }
+    }
+
+    fn wheel(&self, wheel: Role) -> Option<&Stepper> {
+        match wheel {
+            Role::Left => Some(&self.left),
+            Role::Right => Some(&self.right),
+            _ => None,
+        }
+    }
+}
+
+impl Drivetrain for Steppers {
+    fn drive(&self, left: f32, right: f32) -> Result<()> {
+        self.left.set_enabled(true)?;
+        self.right.set_enabled(true)?;
+        self.left.drive(left)?;
+        self.right.drive(right)
+    }
+
+    fn channels(&self) -> Vec<(Role, &Channel)> {
+        vec![]
+    }
+
+    fn wheels(&self) -> Vec<Role> {
+        vec![Role::Left, Role::Right]
+    }
+
+    fn drive_wheel(&self, wheel: Role, speed: f32) -> Result<()> {
+        if let Some(stepper) = self.wheel(wheel) {
+            stepper.set_enabled(true)?;
+            stepper.drive(speed)?;
+            if speed == 0.0 {
+                stepper.wait();
+            }
+        }
+        Ok(())
+    }
+
+    fn counts_steps(&self) -> bool {
+        true
+    }
+
+    fn move_exactly(&self, direction: Direction, speed: f32) -> Result<()> {
+        let distance = self.settings.distance_steps(self.settings.node_distance);
+        let turn = self.settings.turn_steps(90.0);
+        let (left, right) = match direction {
+            Direction::Forward => (distance, distance),
+            Direction::Backward => (-distance, -distance),
+            Direction::Left => (-turn, turn),
+            Direction::Right => (turn, -turn),
+        };
+        self.left.set_enabled(true)?;
+        self.right.set_enabled(true)?;
+        self.left.move_steps(left, speed)?;
+        self.right.move_steps(right, speed)?;
+        self.left.wait();
+        self.right.wait();
+        Ok(())
+    }
+
+    // Steppers always ramp down to a stop, and hold their position unless coasting
+    fn stop(&self, stop: motor::Stop) -> Result<()> {
+        self.left.drive(0.0)?;
+        self.right.drive(0.0)?;
+        self.left.wait();
+        self.right.wait();
+        if stop.mode == motor::StopMode::Coast {
+            self.left.set_enabled(false)?;
+            self.right.set_enabled(false)?;
+        }
+        Ok(())
+    }
+
+    fn unexport(&self) {
+        self.left.unexport();
+        self.right.unexport();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::{Driver, Settings, braking, ramp};
+
+    // A wheel that goes 100mm a turn, and turns in place on a 200mm circle
+    fn settings() -> Settings {
+        Settings {
+            steps_per_rev: 200,
+            microsteps: 4,
+            wheel_diameter: 100.0 / PI,
+            track_width: 200.0 / PI,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn distances_are_in_microsteps() {
+        assert_eq!(settings().distance_steps(100.0), 800);
+        assert_eq!(settings().distance_steps(25.0), 200);
+        assert_eq!(settings().distance_steps(-50.0), -400);
+        assert_eq!(settings().distance_steps(0.0), 0);
+    }
+
+    #[test]
+    fn turns_are_arcs_of_the_track() {
+        assert_eq!(settings().turn_steps(360.0), 1600);
+        assert_eq!(settings().turn_steps(90.0), 400);
+        assert_eq!(settings().turn_steps(-45.0), -200);
+    }
+
+    #[test]
+    fn ramps_up_and_down_by_the_acceleration() {
+        // v² goes up by 2a each step
+        assert_eq!(ramp(0.0, 100.0, 50.0), 10.0);
+        assert_eq!(ramp(10.0, 100.0, 50.0), 200.0f32.sqrt());
+        assert_eq!(ramp(10.0, 0.0, 50.0), 0.0);
+        assert_eq!(ramp(20.0, 0.0, 50.0), 300.0f32.sqrt());
+    }
+
+    #[test]
+    fn ramps_stop_at_the_goal() {
+        assert_eq!(ramp(95.0, 100.0, 5000.0), 100.0);
+        assert_eq!(ramp(105.0, 100.0, 5000.0), 100.0);
+        assert_eq!(ramp(100.0, 100.0, 50.0), 100.0);
+    }
+
+    #[test]
+    fn brakes_once_stopping_takes_the_steps_left() {
+        // Stopping from 100 steps a second at 50 steps a second² takes 100 steps
+        assert!(!braking(100.0, 50.0, 101));
+        assert!(braking(100.0, 50.0, 100));
+        assert!(braking(100.0, 50.0, 1));
+        assert!(!braking(0.0, 50.0, 0));
+    }
+
+    #[test]
+    fn microstep_pins_depend_on_the_driver() {
+        assert_eq!(Driver::A4988.mode(1), Some([0, 0, 0]));
+        assert_eq!(Driver::Drv8825.mode(8), Some([1, 1, 0]));
+        assert_eq!(Driver::A4988.mode(16), Some([1, 1, 1]));
+        assert_eq!(Driver::Drv8825.mode(16), Some([0, 0, 1]));
+        assert_eq!(Driver::Drv8825.mode(32), Some([1, 0, 1]));
+        assert_eq!(Driver::A4988.mode(32), None);
+        assert_eq!(Driver::Drv8825.mode(3), None);
+    }
+}
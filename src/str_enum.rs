@@ -0,0 +1,55 @@
+/// Defines a fieldless enum that serializes as one string per variant.
+///
+/// The serde derives expect enums to be written in their externally tagged form,
+/// which TOML can't express, so config values like `mode = "brake"` use this instead.
+macro_rules! str_enum {
+    ($(#[$attr: meta])* pub enum $name: ident {
+        $($(#[$vattr: meta])* $variant: ident => $s: tt),+
+    }) => {
+        $(#[$attr])*
+        #[derive(Debug, Copy, Clone, PartialEq)]
+        pub enum $name {
+            $($(#[$vattr])* $variant),+
+        }
+
+        impl ::serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+                where S: ::serde::Serializer
+            {
+                serializer.serialize_str(match *self {
+                    $($name::$variant => $s),+
+                })
+            }
+        }
+
+        impl ::serde::Deserialize for $name {
+            fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+                where D: ::serde::Deserializer
+            {
+                const VARIANTS: &'static [&'static str] = &[$($s),+];
+
+                struct Visitor;
+
+                impl ::serde::de::Visitor for Visitor {
+                    type Value = $name;
+
+                    fn expecting(&self, formatter: &mut ::std::fmt::Formatter)
+                                 -> ::std::fmt::Result {
+                        write!(formatter, "one of {:?}", VARIANTS)
+                    }
+
+                    fn visit_str<E>(self, value: &str) -> ::std::result::Result<$name, E>
+                        where E: ::serde::de::Error
+                    {
+                        match value {
+                            $($s => Ok($name::$variant),)+
+                            _ => Err(::serde::de::Error::unknown_variant(value, VARIANTS)),
+                        }
+                    }
+                }
+
+                deserializer.deserialize_str(Visitor)
+            }
+        }
+    };
+}
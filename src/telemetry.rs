@@ -0,0 +1,282 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time;
+
+use serde_json;
+
+use Direction;
+use controller::DistanceVector;
+use trace::{Event, Observer};
+use super::Result;
+
+// Ten messages a second is plenty to watch a run by
+const PERIOD_MS: u64 = 100;
+
+// Clients that can't keep up are dropped rather than holding up everyone else
+const WRITE_TIMEOUT_MS: u64 = 200;
+
+/// Streams what the robot is doing to any number of TCP clients, as one JSON
+/// message per line.
+///
+/// Each message has the command being run, with its index and the map node it
+/// started from, the direction the robot is moving in, the latest reading from each
+/// sensor with how many seconds old it is, and the last thing the motors were told
+/// to do.
+///
+/// Serving stops, and the address is free again, once the run is over.
+#[derive(Debug)]
+pub struct Telemetry {
+    address: SocketAddr,
+    state: Mutex<State>,
+    clients: Mutex<Vec<TcpStream>>,
+    running: AtomicBool,
+    threads: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    command: Option<Current>,
+    direction: Option<Direction>,
+    motors: Option<Event>,
+    readings: Vec<(DistanceVector, time::Instant)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Current {
+    command: String,
+    index: Option<usize>,
+    node: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct Message {
+    /// Seconds since the Unix epoch
+    time: f64,
+    command: Option<Current>,
+    direction: Option<Direction>,
+    distances: Vec<Distance>,
+    motors: Option<Event>,
+}
+
+#[derive(Debug, Serialize)]
+struct Distance {
+    direction: Direction,
+    distance: f32,
+    angle: f32,
+    /// Seconds since the reading
+    age: f64,
+}
+
+fn seconds(duration: time::Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+impl Telemetry {
+    /// Starts listening for clients on `address`, such as `0.0.0.0:7878`.
+    pub fn serve(address: &str) -> Result<Arc<Telemetry>> {
+        let listener = TcpListener::bind(address)?;
+        // Polled, so the thread accepting clients notices when it's time to stop
+        listener.set_nonblocking(true)?;
+        let telemetry = Arc::new(Telemetry {
+            address: listener.local_addr()?,
+            state: Mutex::new(State::default()),
+            clients: Mutex::new(vec![]),
+            running: AtomicBool::new(true),
+            threads: Mutex::new(vec![]),
+        });
+        info!("Serving telemetry on {}", telemetry.address);
+
+        let accepting = telemetry.clone();
+        let accept = thread::spawn(move || while accepting.running.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => accepting.connect(stream),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(time::Duration::from_millis(PERIOD_MS));
+                }
+                Err(err) => warn!("Couldn't accept a telemetry client: {}", err),
+            }
+        });
+        let publishing = telemetry.clone();
+        let publish = thread::spawn(move || while publishing.running.load(Ordering::SeqCst) {
+            thread::sleep(time::Duration::from_millis(PERIOD_MS));
+            publishing.publish();
+        });
+        telemetry.threads.lock().unwrap().extend(vec![accept, publish]);
+        Ok(telemetry)
+    }
+
+    fn connect(&self, stream: TcpStream) {
+        if let Ok(peer) = stream.peer_addr() {
+            info!("Telemetry client {} connected", peer);
+        }
+        // Some platforms hand on the listener's non-blocking mode
+        stream.set_nonblocking(false).ok();
+        stream.set_nodelay(true).ok();
+        stream.set_write_timeout(Some(time::Duration::from_millis(WRITE_TIMEOUT_MS))).ok();
+        self.clients.lock().unwrap().push(stream);
+    }
+
+    fn message(&self) -> Message {
+        let state = self.state.lock().unwrap();
+        let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        Message {
+            time: seconds(now),
+            command: state.command.clone(),
+            direction: state.direction,
+            distances: state.readings
+                .iter()
+                .map(|&(vector, at)| {
+                    Distance {
+                        direction: vector.direction,
+                        distance: vector.distance,
+                        angle: vector.angle,
+                        age: seconds(at.elapsed()),
+                    }
+                })
+                .collect(),
+            motors: state.motors.clone(),
+        }
+    }
+
+    fn publish(&self) {
+        let line = match serde_json::to_string(&self.message()) {
+            Ok(line) => line + "\n",
+            Err(err) => {
+                warn!("Couldn't serialize telemetry: {}", err);
+                return;
+            }
+        };
+        self.clients.lock().unwrap().retain(|mut client| {
+            match client.write_all(line.as_bytes()) {
+                Ok(()) => true,
+                Err(err) => {
+                    if let Ok(peer) = client.peer_addr() {
+                        info!("Telemetry client {} disconnected: {}", peer, err);
+                    }
+                    false
+                }
+            }
+        });
+    }
+}
+
+impl Observer for Telemetry {
+    fn observe(&self, event: &Event) {
+        let mut state = self.state.lock().unwrap();
+        match *event {
+            Event::Command { ref command, index, node } => {
+                state.command = Some(Current {
+                    command: command.clone(),
+                    index: index,
+                    node: node,
+                });
+            }
+            Event::Reading { direction, distance } => {
+                let vector = DistanceVector::fixed(direction, distance);
+                let now = time::Instant::now();
+                match state.readings.iter().position(|&(v, _)| v.direction == direction) {
+                    Some(i) => state.readings[i] = (vector, now),
+                    None => state.readings.push((vector, now)),
+                }
+            }
+            // The last good reading stays until there's a new one
+            Event::SensorError { .. } => {}
+            Event::Start { direction, .. } |
+            Event::MoveExactly { direction, .. } |
+            Event::Rotate { direction, .. } => {
+                state.direction = Some(direction);
+                state.motors = Some(event.clone());
+            }
+            Event::Stop { .. } => {
+                state.direction = None;
+                state.motors = Some(event.clone());
+            }
+            Event::Drive { .. } |
+            Event::DriveWheel { .. } => state.motors = Some(event.clone()),
+        }
+    }
+
+    /// Stops serving, disconnecting every client.
+    fn finish(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        for thread in self.threads.lock().unwrap().drain(..) {
+            if thread.join().is_err() {
+                warn!("A telemetry thread panicked");
+            }
+        }
+        self.clients.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time;
+
+    use serde_json::{self, Value};
+
+    use Direction;
+    use trace::{Event, Observer};
+    use super::Telemetry;
+
+    #[test]
+    fn streams_each_event() {
+        let telemetry = Telemetry::serve("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(telemetry.address).unwrap();
+        stream.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        // Every line is a whole JSON message, and the ones sent since the event
+        // show it
+        let mut after = |event: Event, shown: &dyn Fn(&Value) -> bool| {
+            telemetry.observe(&event);
+            loop {
+                let line = lines.next().unwrap().unwrap();
+                let message: Value = serde_json::from_str(&line).unwrap();
+                if shown(&message) {
+                    break;
+                }
+            }
+        };
+
+        after(Event::Command {
+                  command: String::from("Forward"),
+                  index: Some(3),
+                  node: Some(1),
+              },
+              &|message| message["command"]["index"].as_u64() == Some(3));
+        after(Event::Start {
+                  direction: Direction::Forward,
+                  speed: 0.5,
+              },
+              &|message| {
+                  message["direction"].as_str() == Some("Forward") &&
+                  message["motors"]["Start"]["speed"].as_f64() == Some(0.5)
+              });
+        after(Event::Reading {
+                  direction: Direction::Left,
+                  distance: 12.0,
+              },
+              &|message| {
+                  message["distances"][0]["distance"].as_f64() == Some(12.0) &&
+                  message["distances"][0]["angle"].as_f64() == Some(90.0)
+              });
+    }
+
+    #[test]
+    fn finishing_frees_the_address() {
+        let telemetry = Telemetry::serve("127.0.0.1:0").unwrap();
+        let address = telemetry.address;
+        let client = TcpStream::connect(address).unwrap();
+        thread::sleep(time::Duration::from_millis(250));
+        telemetry.finish();
+        let mut lines = BufReader::new(client).lines();
+        // Whatever was sent before it finished, and then nothing more
+        while let Some(Ok(_)) = lines.next() {}
+        assert!(TcpStream::connect(address).is_err());
+    }
+}
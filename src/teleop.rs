@@ -0,0 +1,221 @@
+use std::io::{self, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use futures::Future;
+use termion::{clear, color, cursor, style};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+
+use Direction;
+use controller::Controller;
+use logger;
+use threshold::Threshold;
+use super::Result;
+
+const SENSORS: [(Direction, &'static str); 4] = [(Direction::Forward, "Front"),
+                                                 (Direction::Backward, "Rear"),
+                                                 (Direction::Left, "Left"),
+                                                 (Direction::Right, "Right")];
+
+// The screen is redrawn this often, which is also how often keys are checked for
+const FRAME_MS: u64 = 50;
+
+// Bars go from nothing to this many centimeters
+const BAR_RANGE: f32 = 200.0;
+const BAR_WIDTH: usize = 50;
+
+/// Drives the robot from the keyboard, showing the sensor readings as it goes.
+///
+/// Each arrow key press moves in that direction for `step`. A press while the robot
+/// is already moving is saved up for when it finishes, so holding a key down keeps
+/// it going, and space stops it and forgets any saved up move.
+///
+/// Logging is held back until the terminal is put back, so it isn't drawn over the
+/// screen.
+pub fn run(controller: &mut Controller, step: time::Duration) -> Result<()> {
+    let hold = logger::Hold::new();
+    let mut stdout = io::stdout().into_raw_mode()?;
+    write!(stdout, "{}{}", clear::All, cursor::Hide)?;
+    let result = drive(controller, step, &mut stdout);
+    // Put the terminal back however the loop ended
+    write!(stdout,
+           "{}{}{}",
+           cursor::Goto(1, SENSORS.len() as u16 + 5),
+           style::Reset,
+           cursor::Show)?;
+    stdout.flush()?;
+    drop(stdout);
+    drop(hold);
+    // A move can still be running
+    controller.interrupt()?;
+    result
+}
+
+// What a key press asks for
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Action {
+    Move(Direction),
+    Stop,
+    Quit,
+}
+
+fn action(key: Key) -> Option<Action> {
+    match key {
+        Key::Up => Some(Action::Move(Direction::Forward)),
+        Key::Down => Some(Action::Move(Direction::Backward)),
+        Key::Left => Some(Action::Move(Direction::Left)),
+        Key::Right => Some(Action::Move(Direction::Right)),
+        Key::Char(' ') => Some(Action::Stop),
+        Key::Char('q') | Key::Esc | Key::Ctrl('c') => Some(Action::Quit),
+        _ => None,
+    }
+}
+
+fn drive<W: Write>(controller: &mut Controller, step: time::Duration, out: &mut W) -> Result<()> {
+    let readings = controller.readings();
+    let thresholds = controller.thresholds();
+    let keys = keys();
+    let mut moving: Option<mpsc::Receiver<Result<()>>> = None;
+    let mut pending = None;
+    let mut status = String::from("Ready");
+    loop {
+        while let Ok(key) = keys.try_recv() {
+            match action(key?) {
+                Some(Action::Move(direction)) => pending = Some(direction),
+                Some(Action::Stop) => {
+                    pending = None;
+                    // Ends the move that's running too, not just the motors
+                    controller.interrupt()?;
+                    status = String::from("Stopped");
+                }
+                Some(Action::Quit) => return Ok(()),
+                None => {}
+            }
+        }
+
+        // Finish off the last move before starting the next
+        let finished = match moving {
+            Some(ref done) => {
+                match done.try_recv() {
+                    Ok(result) => {
+                        result?;
+                        true
+                    }
+                    Err(mpsc::TryRecvError::Empty) => false,
+                    Err(mpsc::TryRecvError::Disconnected) => true,
+                }
+            }
+            None => true,
+        };
+        if finished {
+            moving = None;
+            if let Some(direction) = pending.take() {
+                let travel = controller.travel_for(direction, step);
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || tx.send(travel.wait()).ok());
+                moving = Some(rx);
+                status = format!("Moving {:?}", direction);
+            } else if status.starts_with("Moving") {
+                status = String::from("Ready");
+            }
+        }
+
+        write!(out, "{}rsbot teleop{}", cursor::Goto(1, 1), clear::UntilNewline)?;
+        for (i, &(direction, name)) in SENSORS.iter().enumerate() {
+            let distance = readings.latest(direction).map(|reading| reading.distance);
+            write!(out,
+                   "{}{}{}",
+                   cursor::Goto(1, i as u16 + 3),
+                   bar(name, distance, thresholds.get(direction)),
+                   clear::UntilNewline)?;
+        }
+        write!(out,
+               "{}{}{}{}Arrows move, space stops, q quits{}",
+               cursor::Goto(1, SENSORS.len() as u16 + 4),
+               status,
+               clear::UntilNewline,
+               cursor::Goto(1, SENSORS.len() as u16 + 5),
+               clear::UntilNewline)?;
+        out.flush()?;
+        thread::sleep(time::Duration::from_millis(FRAME_MS));
+    }
+}
+
+// Keys are read on their own thread so the screen keeps updating between presses
+fn keys() -> mpsc::Receiver<io::Result<Key>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || for key in io::stdin().keys() {
+        if tx.send(key).is_err() {
+            break;
+        }
+    });
+    rx
+}
+
+// Red once the sensor is blocked, yellow inside the hysteresis band and green when
+// it's clear. The threshold is marked with a `|`.
+fn bar(name: &str, distance: Option<f32>, threshold: Threshold) -> String {
+    let distance = match distance {
+        Some(distance) => distance,
+        None => return format!("{:>5}      -", name),
+    };
+    let column = |cm: f32| ((cm / BAR_RANGE).max(0.0).min(1.0) * BAR_WIDTH as f32) as usize;
+    let filled = column(distance);
+    let mark = column(threshold.distance).min(BAR_WIDTH - 1);
+    let cells = (0..BAR_WIDTH)
+        .map(|i| if i == mark {
+            '|'
+        } else if i < filled {
+            '#'
+        } else {
+            ' '
+        })
+        .collect::<String>();
+    let shade = if distance <= threshold.enter() {
+        format!("{}", color::Fg(color::Red))
+    } else if distance < threshold.leave() {
+        format!("{}", color::Fg(color::Yellow))
+    } else {
+        format!("{}", color::Fg(color::Green))
+    };
+    format!("{:>5} {:6.1} {}[{}]{}",
+            name,
+            distance,
+            shade,
+            cells,
+            color::Fg(color::Reset))
+}
+
+#[cfg(test)]
+mod tests {
+    use termion::event::Key;
+
+    use Direction;
+    use super::{Action, action};
+
+    #[test]
+    fn arrows_move() {
+        assert_eq!(action(Key::Up), Some(Action::Move(Direction::Forward)));
+        assert_eq!(action(Key::Down), Some(Action::Move(Direction::Backward)));
+        assert_eq!(action(Key::Left), Some(Action::Move(Direction::Left)));
+        assert_eq!(action(Key::Right), Some(Action::Move(Direction::Right)));
+    }
+
+    #[test]
+    fn space_stops_and_q_quits() {
+        assert_eq!(action(Key::Char(' ')), Some(Action::Stop));
+        for &key in &[Key::Char('q'), Key::Esc, Key::Ctrl('c')] {
+            assert_eq!(action(key), Some(Action::Quit));
+        }
+    }
+
+    #[test]
+    fn other_keys_do_nothing() {
+        for &key in &[Key::Char('w'), Key::Char('Q'), Key::Ctrl('d'), Key::Backspace] {
+            assert_eq!(action(key), None);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use Direction;
+
+const FB_THRESHOLD: f32 = 35.75;
+const LR_THRESHOLD: f32 = 43.0;
+
+/// The distance in centimeters at which a sensor changes between "clear" and "blocked".
+///
+/// `hysteresis` widens this into a band: a clear sensor only becomes blocked at
+/// `distance - hysteresis` or closer, and a blocked sensor only becomes clear again at
+/// `distance + hysteresis` or further, so noise near the threshold can't end a move early.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Threshold {
+    pub distance: f32,
+    #[serde(default)]
+    pub hysteresis: f32,
+}
+
+impl Threshold {
+    pub fn new(distance: f32) -> Threshold {
+        Threshold {
+            distance: distance,
+            hysteresis: 0.0,
+        }
+    }
+
+    /// The reading at or below which a clear sensor becomes blocked.
+    pub fn enter(&self) -> f32 {
+        self.distance - self.hysteresis
+    }
+
+    /// The reading at or above which a blocked sensor becomes clear.
+    pub fn leave(&self) -> f32 {
+        self.distance + self.hysteresis
+    }
+
+    fn front_back() -> Threshold {
+        Threshold::new(FB_THRESHOLD)
+    }
+
+    fn left_right() -> Threshold {
+        Threshold::new(LR_THRESHOLD)
+    }
+}
+
+/// A threshold for each of the four distance sensors.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Thresholds {
+    #[serde(default = "Threshold::front_back")]
+    pub front: Threshold,
+    #[serde(default = "Threshold::front_back")]
+    pub rear: Threshold,
+    #[serde(default = "Threshold::left_right")]
+    pub left: Threshold,
+    #[serde(default = "Threshold::left_right")]
+    pub right: Threshold,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            front: Threshold::front_back(),
+            rear: Threshold::front_back(),
+            left: Threshold::left_right(),
+            right: Threshold::left_right(),
+        }
+    }
+}
+
+impl Thresholds {
+    /// The threshold for the sensor facing `direction`.
+    pub fn get(&self, direction: Direction) -> Threshold {
+        match direction {
+            Direction::Forward => self.front,
+            Direction::Backward => self.rear,
+            Direction::Left => self.left,
+            Direction::Right => self.right,
+        }
+    }
+}
+
+/// Replacement thresholds for some of the sensors, used by maps to tune corridors
+/// that are wider or narrower than the robot's defaults.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Overrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub front: Option<Threshold>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rear: Option<Threshold>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub left: Option<Threshold>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub right: Option<Threshold>,
+}
+
+impl Overrides {
+    pub fn is_empty(&self) -> bool {
+        self.front.is_none() && self.rear.is_none() && self.left.is_none() &&
+        self.right.is_none()
+    }
+
+    /// Returns `base` with any overridden thresholds replaced.
+    pub fn apply(&self, base: Thresholds) -> Thresholds {
+        Thresholds {
+            front: self.front.unwrap_or(base.front),
+            rear: self.rear.unwrap_or(base.rear),
+            left: self.left.unwrap_or(base.left),
+            right: self.right.unwrap_or(base.right),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toml;
+
+    use Direction;
+    use super::{Overrides, Threshold, Thresholds};
+
+    #[test]
+    fn hysteresis_widens_the_threshold_into_a_band() {
+        let threshold = Threshold {
+            distance: 30.0,
+            hysteresis: 2.5,
+        };
+        assert_eq!(threshold.enter(), 27.5);
+        assert_eq!(threshold.leave(), 32.5);
+
+        let sharp = Threshold::new(30.0);
+        assert_eq!(sharp.enter(), 30.0);
+        assert_eq!(sharp.leave(), 30.0);
+    }
+
+    #[test]
+    fn hysteresis_defaults_to_none() {
+        let thresholds: Thresholds = toml::from_str("[left]\ndistance = 50.0").unwrap();
+        assert_eq!(thresholds.left, Threshold::new(50.0));
+        assert_eq!(thresholds.front, Thresholds::default().front);
+        assert_eq!(thresholds.get(Direction::Right), Thresholds::default().right);
+    }
+
+    #[test]
+    fn overrides_replace_only_their_sensors() {
+        let base = Thresholds::default();
+        let overrides = Overrides {
+            rear: Some(Threshold::new(20.0)),
+            ..Overrides::default()
+        };
+        assert!(Overrides::default().is_empty());
+        assert!(!overrides.is_empty());
+        assert_eq!(Overrides::default().apply(base), base);
+
+        let applied = overrides.apply(base);
+        assert_eq!(applied.rear, Threshold::new(20.0));
+        assert_eq!(applied.front, base.front);
+        assert_eq!(applied.left, base.left);
+        assert_eq!(applied.right, base.right);
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::result;
+use std::time;
+
+use serde_json;
+
+use Direction;
+use distance::{RangeSensor, Sensor};
+use drivetrain::{self, Drivetrain};
+use motor::{self, Channel, Role};
+use super::Result;
+
+/// Something that happened during a run.
+///
+/// Drivetrain events are the calls the controller made, not what the drivetrain
+/// did with its motors to carry them out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    /// A command starting, with how many were queued before it and the map node
+    /// the robot was at, if it came from a map
+    Command {
+        command: String,
+        index: Option<usize>,
+        node: Option<u32>,
+    },
+    Reading { direction: Direction, distance: f32 },
+    SensorError { direction: Direction, message: String },
+    Drive { left: f32, right: f32 },
+    DriveWheel { wheel: Role, speed: f32 },
+    Start { direction: Direction, speed: f32 },
+    MoveExactly { direction: Direction, speed: f32 },
+    Rotate { direction: Direction, speed: f32 },
+    Stop { stop: motor::Stop },
+}
+
+/// One line of a trace file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Seconds since recording started
+    pub time: f64,
+    pub event: Event,
+}
+
+fn seconds(duration: time::Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Something that wants to know what happens during a run.
+pub trait Observer: fmt::Debug + Send + Sync {
+    fn observe(&self, event: &Event);
+
+    /// Called once the run is over, for anything that's written out at the end.
+    fn finish(&self) {}
+}
+
+/// Tells `observer` about every reading from `sensor`.
+pub fn observe_sensor(direction: Direction,
+                      sensor: Sensor,
+                      observer: Arc<dyn Observer>)
+                      -> Sensor {
+    Arc::new(ObservedSensor {
+        direction: direction,
+        sensor: sensor,
+        observer: observer,
+    })
+}
+
+/// Tells `observer` about every call the controller makes to `drivetrain`.
+pub fn observe_drivetrain(drivetrain: Arc<dyn Drivetrain>,
+                          observer: Arc<dyn Observer>)
+                          -> Arc<dyn Drivetrain> {
+    Arc::new(ObservedDrivetrain {
+        drivetrain: drivetrain,
+        observer: observer,
+    })
+}
+
+/// Writes everything that happens during a run to a trace file, one JSON entry per
+/// line.
+pub struct Recorder {
+    start: time::Instant,
+    file: Mutex<LineWriter<fs::File>>,
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Recorder").field("start", &self.start).finish()
+    }
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Recorder> {
+        Ok(Recorder {
+            start: time::Instant::now(),
+            file: Mutex::new(LineWriter::new(fs::File::create(path)?)),
+        })
+    }
+}
+
+impl Observer for Recorder {
+    /// Adds an event to the trace. The run carries on if it can't be written.
+    fn observe(&self, event: &Event) {
+        let entry = Entry {
+            time: seconds(self.start.elapsed()),
+            event: event.clone(),
+        };
+        let result = serde_json::to_string(&entry)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            .and_then(|line| writeln!(self.file.lock().unwrap(), "{}", line));
+        if let Err(err) = result {
+            warn!("Couldn't write to the trace: {}", err);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ObservedSensor {
+    direction: Direction,
+    sensor: Sensor,
+    observer: Arc<dyn Observer>,
+}
+
+impl RangeSensor for ObservedSensor {
+    fn value(&self) -> Result<f32> {
+        let value = self.sensor.value();
+        self.observer.observe(&match value {
+            Ok(distance) => {
+                Event::Reading {
+                    direction: self.direction,
+                    distance: distance,
+                }
+            }
+            Err(ref err) => {
+                Event::SensorError {
+                    direction: self.direction,
+                    message: err.to_string(),
+                }
+            }
+        });
+        value
+    }
+
+    fn unexport(&self) {
+        self.sensor.unexport()
+    }
+
+    fn interferes(&self) -> bool {
+        self.sensor.interferes()
+    }
+}
+
+#[derive(Debug)]
+struct ObservedDrivetrain {
+    drivetrain: Arc<dyn Drivetrain>,
+    observer: Arc<dyn Observer>,
+}
+
+impl Drivetrain for ObservedDrivetrain {
+    fn drive(&self, left: f32, right: f32) -> Result<()> {
+        self.observer.observe(&Event::Drive {
+            left: left,
+            right: right,
+        });
+        self.drivetrain.drive(left, right)
+    }
+
+    fn channels(&self) -> Vec<(Role, &Channel)> {
+        self.drivetrain.channels()
+    }
+
+    fn wheels(&self) -> Vec<Role> {
+        self.drivetrain.wheels()
+    }
+
+    fn drive_wheel(&self, wheel: Role, speed: f32) -> Result<()> {
+        self.observer.observe(&Event::DriveWheel {
+            wheel: wheel,
+            speed: speed,
+        });
+        self.drivetrain.drive_wheel(wheel, speed)
+    }
+
+    fn counts_steps(&self) -> bool {
+        self.drivetrain.counts_steps()
+    }
+
+    fn move_exactly(&self, direction: Direction, speed: f32) -> Result<()> {
+        self.observer.observe(&Event::MoveExactly {
+            direction: direction,
+            speed: speed,
+        });
+        self.drivetrain.move_exactly(direction, speed)
+    }
+
+    fn strafes(&self) -> bool {
+        self.drivetrain.strafes()
+    }
+
+    fn start(&self, direction: Direction, speed: f32) -> Result<()> {
+        self.observer.observe(&Event::Start {
+            direction: direction,
+            speed: speed,
+        });
+        self.drivetrain.start(direction, speed)
+    }
+
+    fn rotate(&self, direction: Direction, speed: f32) -> Result<()> {
+        self.observer.observe(&Event::Rotate {
+            direction: direction,
+            speed: speed,
+        });
+        self.drivetrain.rotate(direction, speed)
+    }
+
+    fn stop(&self, stop: motor::Stop) -> Result<()> {
+        self.observer.observe(&Event::Stop { stop: stop });
+        self.drivetrain.stop(stop)
+    }
+
+    fn unexport(&self) {
+        self.drivetrain.unexport()
+    }
+}
+
+/// A recorded run, to be played back without any hardware.
+#[derive(Debug)]
+pub struct Trace {
+    entries: Vec<Entry>,
+}
+
+impl Trace {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Trace> {
+        let file = io::BufReader::new(fs::File::open(path)?);
+        let lines = file.lines().collect::<io::Result<Vec<_>>>()?;
+        let mut entries = vec![];
+        for (i, line) in lines.iter().enumerate().filter(|&(_, l)| !l.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                // A run that was cut short can leave half a line at the end
+                Err(err) if i + 1 == lines.len() => warn!("Skipping the trace's last line: {}", err),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(Trace { entries: entries })
+    }
+
+    /// Sensors that give back the recorded readings, each at the same time after
+    /// this is called as it was read after recording started.
+    pub fn sensors(&self) -> Vec<(Direction, Sensor)> {
+        let start = time::Instant::now();
+        [Direction::Forward, Direction::Backward, Direction::Left, Direction::Right]
+            .iter()
+            .map(|&direction| {
+                let readings = self.entries
+                    .iter()
+                    .filter_map(|entry| match entry.event {
+                        Event::Reading { direction: d, distance } if d == direction => {
+                            Some((entry.time, Ok(distance)))
+                        }
+                        Event::SensorError { direction: d, ref message } if d == direction => {
+                            Some((entry.time, Err(message.clone())))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                let sensor: Sensor = Arc::new(ReplayedSensor {
+                    direction: direction,
+                    start: start,
+                    readings: Mutex::new(readings),
+                });
+                (direction, sensor)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct ReplayedSensor {
+    direction: Direction,
+    start: time::Instant,
+    readings: Mutex<VecDeque<(f64, result::Result<f32, String>)>>,
+}
+
+impl RangeSensor for ReplayedSensor {
+    fn value(&self) -> Result<f32> {
+        let next = self.readings.lock().unwrap().pop_front();
+        match next {
+            Some((at, reading)) => {
+                let at = time::Duration::from_millis((at * 1000.0) as u64);
+                if let Some(wait) = at.checked_sub(self.start.elapsed()) {
+                    thread::sleep(wait);
+                }
+                Ok(reading.map_err(|message| io::Error::new(io::ErrorKind::Other, message))?)
+            }
+            None => {
+                let message = format!("the trace has no more {:?} readings", self.direction);
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, message).into())
+            }
+        }
+    }
+}
+
+/// Stands in for the robot's drivetrain during a replay, where nothing really moves.
+#[derive(Debug)]
+pub struct NullDrivetrain {
+    kind: drivetrain::Kind,
+}
+
+impl NullDrivetrain {
+    pub fn new(kind: drivetrain::Kind) -> NullDrivetrain {
+        NullDrivetrain { kind: kind }
+    }
+}
+
+impl Drivetrain for NullDrivetrain {
+    fn drive(&self, left: f32, right: f32) -> Result<()> {
+        debug!("Drive left {} right {}", left, right);
+        Ok(())
+    }
+
+    fn channels(&self) -> Vec<(Role, &Channel)> {
+        vec![]
+    }
+
+    fn wheels(&self) -> Vec<Role> {
+        self.kind.roles().to_vec()
+    }
+
+    fn drive_wheel(&self, wheel: Role, speed: f32) -> Result<()> {
+        debug!("Drive {:?} wheel {}", wheel, speed);
+        Ok(())
+    }
+
+    fn counts_steps(&self) -> bool {
+        self.kind == drivetrain::Kind::Stepper
+    }
+
+    fn move_exactly(&self, direction: Direction, speed: f32) -> Result<()> {
+        debug!("Move exactly {:?} at {}", direction, speed);
+        Ok(())
+    }
+
+    fn strafes(&self) -> bool {
+        self.kind.strafes()
+    }
+
+    fn stop(&self, stop: motor::Stop) -> Result<()> {
+        debug!("Stop, {:?}", stop.mode);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::process;
+    use std::sync::{Arc, Mutex};
+
+    use {Direction, Result};
+    use distance::RangeSensor;
+    use drivetrain::Kind;
+    use motor;
+    use super::{Event, NullDrivetrain, Observer, Recorder, Trace, observe_drivetrain,
+                observe_sensor};
+
+    // Gives back each of its readings once, then fails
+    #[derive(Debug)]
+    struct Readings(Mutex<Vec<f32>>);
+
+    impl RangeSensor for Readings {
+        fn value(&self) -> Result<f32> {
+            match self.0.lock().unwrap().pop() {
+                Some(distance) => Ok(distance),
+                None => Err(io::Error::new(io::ErrorKind::TimedOut, "no echo").into()),
+            }
+        }
+    }
+
+    fn values(sensor: &Arc<dyn RangeSensor>) -> Vec<::std::result::Result<f32, String>> {
+        (0..4).map(|_| sensor.value().map_err(|err| err.to_string())).collect()
+    }
+
+    #[test]
+    fn replays_recorded_readings_in_order() {
+        let path = env::temp_dir().join(format!("rsbot-trace-{}.jsonl", process::id()));
+        {
+            let recorder = Arc::new(Recorder::create(&path).unwrap());
+            let front = observe_sensor(Direction::Forward,
+                                       Arc::new(Readings(Mutex::new(vec![30.0, 20.0, 10.0]))),
+                                       recorder.clone());
+            let left = observe_sensor(Direction::Left,
+                                      Arc::new(Readings(Mutex::new(vec![5.0]))),
+                                      recorder.clone());
+            let drivetrain = observe_drivetrain(Arc::new(NullDrivetrain::new(Kind::Skid)),
+                                                recorder.clone());
+            recorder.observe(&Event::Command {
+                command: String::from("Forward"),
+                index: Some(0),
+                node: None,
+            });
+            drivetrain.start(Direction::Forward, 1.0).unwrap();
+            front.value().unwrap();
+            left.value().unwrap();
+            front.value().unwrap();
+            assert!(left.value().is_err());
+            drivetrain.stop(motor::Stop::default()).unwrap();
+            front.value().unwrap();
+        }
+
+        let trace = Trace::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(trace.entries.len(), 8);
+        assert!(trace.entries.windows(2).all(|pair| pair[0].time <= pair[1].time));
+        let sensors = trace.sensors();
+        let sensor = |direction: Direction| {
+            sensors.iter().find(|&&(d, _)| d == direction).unwrap().1.clone()
+        };
+        let eof = |direction: &str| Err(format!("the trace has no more {} readings", direction));
+        assert_eq!(values(&sensor(Direction::Forward)),
+                   vec![Ok(10.0), Ok(20.0), Ok(30.0), eof("Forward")]);
+        assert_eq!(values(&sensor(Direction::Left)),
+                   vec![Ok(5.0), Err(String::from("no echo")), eof("Left"), eof("Left")]);
+        assert_eq!(values(&sensor(Direction::Backward)),
+                   vec![eof("Backward"), eof("Backward"), eof("Backward"), eof("Backward")]);
+    }
+
+    #[test]
+    fn skips_half_a_last_line() {
+        let path = env::temp_dir().join(format!("rsbot-cut-trace-{}.jsonl", process::id()));
+        {
+            let mut file = fs::File::create(&path).unwrap();
+            writeln!(file,
+                     r#"{{"time":0.5,"event":{{"Reading":{{"direction":"Right","distance":7.0}}}}}}"#)
+                .unwrap();
+            write!(file, r#"{{"time":0.6,"event":{{"Rea"#).unwrap();
+        }
+        let trace = Trace::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(trace.entries.len(), 1);
+    }
+}